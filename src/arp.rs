@@ -0,0 +1,71 @@
+use super::error::{check_len, ParseError};
+use core::net::Ipv4Addr;
+
+const HARDWARE_TYPE_ETHERNET: u16 = 1;
+const PROTOCOL_TYPE_IPV4: u16 = 0x0800;
+
+#[derive(Debug, PartialEq)]
+pub enum Operation {
+    Request,
+    Reply,
+    Unknown(u16),
+}
+
+// Address Resolution Protocol for Ethernet and IPv4 (RFC 826)
+#[derive(Debug)]
+pub struct Arp {
+    pub operation: Operation,
+    pub sender_mac: [u8; 6],
+    pub sender_address: Ipv4Addr,
+    pub target_mac: [u8; 6],
+    pub target_address: Ipv4Addr,
+}
+
+impl Arp {
+    pub fn parse(buffer: &[u8]) -> Result<Arp, ParseError> {
+        check_len(buffer, 28)?;
+        let hardware_type = u16::from_be_bytes([buffer[0], buffer[1]]);
+        let protocol_type = u16::from_be_bytes([buffer[2], buffer[3]]);
+        if hardware_type != HARDWARE_TYPE_ETHERNET || protocol_type != PROTOCOL_TYPE_IPV4 {
+            return Err(ParseError::Unsupported);
+        }
+        if buffer[4] != 6 || buffer[5] != 4 {
+            return Err(ParseError::BadLength);
+        }
+        let operation = match u16::from_be_bytes([buffer[6], buffer[7]]) {
+            1 => Operation::Request,
+            2 => Operation::Reply,
+            v => Operation::Unknown(v),
+        };
+        let sender_mac = (&buffer[8..14]).try_into().unwrap();
+        let sender_address: [u8; 4] = (&buffer[14..18]).try_into().unwrap();
+        let target_mac = (&buffer[18..24]).try_into().unwrap();
+        let target_address: [u8; 4] = (&buffer[24..28]).try_into().unwrap();
+        Ok(Arp {
+            operation,
+            sender_mac,
+            sender_address: Ipv4Addr::from(sender_address),
+            target_mac,
+            target_address: Ipv4Addr::from(target_address),
+        })
+    }
+
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut packet = vec![];
+        packet.extend_from_slice(&HARDWARE_TYPE_ETHERNET.to_be_bytes());
+        packet.extend_from_slice(&PROTOCOL_TYPE_IPV4.to_be_bytes());
+        packet.extend_from_slice(&[6, 4]);
+        let operation: u16 = match self.operation {
+            Operation::Request => 1,
+            Operation::Reply => 2,
+            Operation::Unknown(v) => v,
+        };
+        packet.extend_from_slice(&operation.to_be_bytes());
+        packet.extend_from_slice(&self.sender_mac);
+        packet.extend_from_slice(&self.sender_address.octets());
+        packet.extend_from_slice(&self.target_mac);
+        packet.extend_from_slice(&self.target_address.octets());
+        packet
+    }
+}
@@ -0,0 +1,98 @@
+/// Packet built in place in a caller provided buffer.
+///
+/// The payload is written first, after enough headroom for every header, then each layer
+/// prepends its header from the innermost to the outermost so that checksums are computed
+/// over bytes that are already in place. Running out of room is a bug in the caller, which
+/// sizes its buffer from the header length constants, so it panics.
+pub struct PacketBuffer<'a> {
+    buffer: &'a mut [u8],
+    start: usize,
+    end: usize,
+}
+
+impl<'a> PacketBuffer<'a> {
+    pub fn new(buffer: &'a mut [u8], headroom: usize) -> PacketBuffer<'a> {
+        assert!(headroom <= buffer.len());
+        PacketBuffer {
+            buffer,
+            start: headroom,
+            end: headroom,
+        }
+    }
+
+    /// Bytes written so far.
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer[self.start..self.end]
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Room left in front of the data.
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    /// Room left after the data.
+    pub fn tailroom(&self) -> usize {
+        self.buffer.len() - self.end
+    }
+
+    pub fn append(&mut self, data: &[u8]) {
+        self.append_zeroed(data.len()).copy_from_slice(data);
+    }
+
+    /// Extend the data with `len` zeroed bytes and return them.
+    pub fn append_zeroed(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.tailroom(), "no room for {} bytes", len);
+        let start = self.end;
+        self.end += len;
+        let appended = &mut self.buffer[start..self.end];
+        appended.fill(0);
+        appended
+    }
+
+    /// Make room for a `len` bytes header in front of the data and return the header.
+    pub fn prepend(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.headroom(), "no headroom for {} bytes", len);
+        self.start -= len;
+        let header = &mut self.buffer[self.start..self.start + len];
+        header.fill(0);
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepend_and_append() {
+        let mut storage = [0xff; 16];
+        let mut buffer = PacketBuffer::new(&mut storage, 8);
+        assert!(buffer.is_empty());
+        buffer.append(&[1, 2]);
+        buffer.prepend(2).copy_from_slice(&[3, 4]);
+        buffer.append_zeroed(1);
+        assert_eq!(buffer.data(), &[3, 4, 1, 2, 0]);
+        assert_eq!(buffer.headroom(), 6);
+        assert_eq!(buffer.tailroom(), 5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_no_headroom() {
+        let mut storage = [0; 16];
+        let mut buffer = PacketBuffer::new(&mut storage, 4);
+        buffer.prepend(8);
+    }
+}
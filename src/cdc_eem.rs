@@ -1,3 +1,4 @@
+use super::buffer::PacketBuffer;
 use usb_device::class_prelude::*;
 use usb_device::Result;
 
@@ -8,8 +9,10 @@ const CDC_PROTOCOL_EEM: u8 = 0x07;
 const EEM_PACKET_TYPE_DATA: u8 = 0;
 const EEM_PACKET_TYPE_COMMAND: u8 = 1;
 
+pub const HEADER_LENGTH: usize = 2;
+
 const MAX_PACKET_SIZE: u16 = 64;
-const MAX_TRANSFER_SIZE: usize = 1024;
+const MAX_TRANSFER_SIZE: usize = 2048;
 
 pub struct CdcEemClass<'a, B: UsbBus> {
     intf: InterfaceNumber,
@@ -47,12 +50,12 @@ impl<B: UsbBus> CdcEemClass<'_, B> {
             })
         }
     }
-    pub fn write(&mut self, packet: &[u8]) {
-        let mut buffer = vec![];
-        buffer.extend_from_slice(&(packet.len() as u16 & 0x3FFF).to_le_bytes());
-        buffer.extend_from_slice(packet);
+    /// Send the frame in `buffer`, prepending the EEM header in place.
+    pub fn write(&mut self, buffer: &mut PacketBuffer) {
+        let header = (buffer.len() as u16 & 0x3FFF).to_le_bytes();
+        buffer.prepend(HEADER_LENGTH).copy_from_slice(&header);
 
-        self.in_ep.write(&buffer).unwrap();
+        self.in_ep.write(buffer.data()).unwrap();
     }
 }
 
@@ -77,25 +80,34 @@ impl<'a> Iterator for CdcEemReadIterator<'a> {
     type Item = CdcEemPacket<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.0.is_empty() {
-            return None;
-        }
-
-        let header = u16::from_le_bytes([self.0[0], self.0[1]]);
-        let bm_type = header >> 15;
-
-        match bm_type as u8 {
-            EEM_PACKET_TYPE_DATA => {
-                let crc = (header >> 14 & 0b1) == 1;
-                let frame_length: usize = (header & 0x3FFF).into();
-                let frame = &self.0[2..frame_length + 2];
-                self.0 = &self.0[frame_length + 2..];
-                Some(CdcEemPacket::Data { crc, frame })
+        loop {
+            // A truncated transfer ends the iteration
+            if self.0.len() < 2 {
+                return None;
             }
-            EEM_PACKET_TYPE_COMMAND => {
-                todo!();
+
+            let header = u16::from_le_bytes([self.0[0], self.0[1]]);
+            let bm_type = header >> 15;
+
+            match bm_type as u8 {
+                EEM_PACKET_TYPE_DATA => {
+                    let crc = (header >> 14 & 0b1) == 1;
+                    let frame_length: usize = (header & 0x3FFF).into();
+                    let frame = self.0.get(2..frame_length + 2)?;
+                    self.0 = &self.0[frame_length + 2..];
+                    return Some(CdcEemPacket::Data { crc, frame });
+                }
+                // Commands (echo, suspend hint...) are not supported and skipped
+                EEM_PACKET_TYPE_COMMAND => {
+                    let command_length: usize = match (header >> 11) & 0b111 {
+                        // Echo and Echo Response carry data
+                        0 | 1 => 2 + (header & 0x7FF) as usize,
+                        _ => 2,
+                    };
+                    self.0 = self.0.get(command_length..)?;
+                }
+                _ => unreachable!(),
             }
-            _ => unreachable!(),
         }
     }
 }
@@ -123,24 +135,24 @@ impl<B: UsbBus> UsbClass<B> for CdcEemClass<'_, B> {
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
 
-        if !(req.request_type == control::RequestType::Class
+        // Class requests are not supported yet, they are only logged
+        if req.request_type == control::RequestType::Class
             && req.recipient == control::Recipient::Interface
-            && req.index == u8::from(self.intf) as u16)
+            && req.index == u8::from(self.intf) as u16
         {
-            return;
+            log!(debug, "Control in");
         }
-        println!("Control in");
     }
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
         let req = xfer.request();
 
-        if !(req.request_type == control::RequestType::Class
+        // Class requests are not supported yet, they are only logged
+        if req.request_type == control::RequestType::Class
             && req.recipient == control::Recipient::Interface
-            && req.index == u8::from(self.intf) as u16)
+            && req.index == u8::from(self.intf) as u16
         {
-            return;
+            log!(debug, "Control out");
         }
-        println!("Control out");
     }
 }
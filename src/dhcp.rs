@@ -0,0 +1,653 @@
+use super::error::{check_len, ParseError};
+use super::ethernet::BROADCAST_MAC;
+use super::interface::{Datagram, UdpReply, UdpService};
+use super::time::{Duration, Instant};
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+const OP_BOOTREQUEST: u8 = 1;
+const OP_BOOTREPLY: u8 = 2;
+const HARDWARE_TYPE_ETHERNET: u8 = 1;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DOMAIN_NAME_SERVER: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_IDENTIFIER: u8 = 54;
+const OPTION_END: u8 = 255;
+
+// Time a client has to request an offered address before it goes back to the pool
+const OFFER_TIMEOUT: Duration = Duration::from_secs(60);
+// Declined addresses are in use by another host, and kept out of the pool for a while
+// (RFC 2131 section 4.3.3)
+const DECLINE_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+    Unknown(u8),
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            8 => MessageType::Inform,
+            v => MessageType::Unknown(v),
+        }
+    }
+
+    fn value(&self) -> u8 {
+        match self {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Decline => 4,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+            MessageType::Release => 7,
+            MessageType::Inform => 8,
+            MessageType::Unknown(v) => *v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DhcpOption<'a> {
+    SubnetMask(Ipv4Addr),
+    Router(Ipv4Addr),
+    DomainNameServer(Ipv4Addr),
+    RequestedAddress(Ipv4Addr),
+    LeaseTime(u32),
+    MessageType(MessageType),
+    ServerIdentifier(Ipv4Addr),
+    Unknown(u8, &'a [u8]),
+}
+
+impl<'a> DhcpOption<'a> {
+    fn parse(code: u8, data: &'a [u8]) -> Self {
+        let address = || Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+        match code {
+            OPTION_SUBNET_MASK if data.len() == 4 => DhcpOption::SubnetMask(address()),
+            OPTION_ROUTER if data.len() >= 4 => DhcpOption::Router(address()),
+            OPTION_DOMAIN_NAME_SERVER if data.len() >= 4 => DhcpOption::DomainNameServer(address()),
+            OPTION_REQUESTED_ADDRESS if data.len() == 4 => DhcpOption::RequestedAddress(address()),
+            OPTION_LEASE_TIME if data.len() == 4 => {
+                DhcpOption::LeaseTime(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+            }
+            OPTION_MESSAGE_TYPE if data.len() == 1 => {
+                DhcpOption::MessageType(MessageType::from_u8(data[0]))
+            }
+            OPTION_SERVER_IDENTIFIER if data.len() == 4 => DhcpOption::ServerIdentifier(address()),
+            code => DhcpOption::Unknown(code, data),
+        }
+    }
+
+    fn write(&self, packet: &mut Vec<u8>) {
+        let (code, data): (u8, &[u8]) = match self {
+            DhcpOption::SubnetMask(a) => (OPTION_SUBNET_MASK, &a.octets()),
+            DhcpOption::Router(a) => (OPTION_ROUTER, &a.octets()),
+            DhcpOption::DomainNameServer(a) => (OPTION_DOMAIN_NAME_SERVER, &a.octets()),
+            DhcpOption::RequestedAddress(a) => (OPTION_REQUESTED_ADDRESS, &a.octets()),
+            DhcpOption::LeaseTime(t) => (OPTION_LEASE_TIME, &t.to_be_bytes()),
+            DhcpOption::MessageType(t) => (OPTION_MESSAGE_TYPE, &[t.value()]),
+            DhcpOption::ServerIdentifier(a) => (OPTION_SERVER_IDENTIFIER, &a.octets()),
+            DhcpOption::Unknown(code, data) => (*code, data),
+        };
+        packet.push(code);
+        packet.push(data.len() as u8);
+        packet.extend_from_slice(data);
+    }
+}
+
+// Code, data and the rest of the buffer
+type RawOption<'a> = (u8, &'a [u8], &'a [u8]);
+
+/// Split the next option off `buffer`, skipping padding.
+fn split_option(mut buffer: &[u8]) -> Result<Option<RawOption<'_>>, ParseError> {
+    loop {
+        match buffer.first() {
+            None | Some(&OPTION_END) => return Ok(None),
+            Some(&OPTION_PAD) => buffer = &buffer[1..],
+            Some(&code) => {
+                check_len(buffer, 2)?;
+                let len = 2 + buffer[1] as usize;
+                check_len(buffer, len)?;
+                return Ok(Some((code, &buffer[2..len], &buffer[len..])));
+            }
+        }
+    }
+}
+
+pub struct Options<'a>(&'a [u8]);
+
+impl<'a> Options<'a> {
+    fn check(&self) -> Result<(), ParseError> {
+        let mut buffer = self.0;
+        while let Some((_, _, rest)) = split_option(buffer)? {
+            buffer = rest;
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = DhcpOption<'a>> {
+        let mut buffer = self.0;
+        iter::from_fn(move || {
+            let (code, data, rest) = split_option(buffer).ok()??;
+            buffer = rest;
+            Some(DhcpOption::parse(code, data))
+        })
+    }
+
+    pub fn message_type(&self) -> Option<MessageType> {
+        self.iter().find_map(|option| match option {
+            DhcpOption::MessageType(t) => Some(t),
+            _ => None,
+        })
+    }
+
+    pub fn requested_address(&self) -> Option<Ipv4Addr> {
+        self.iter().find_map(|option| match option {
+            DhcpOption::RequestedAddress(a) => Some(a),
+            _ => None,
+        })
+    }
+
+    pub fn server_identifier(&self) -> Option<Ipv4Addr> {
+        self.iter().find_map(|option| match option {
+            DhcpOption::ServerIdentifier(a) => Some(a),
+            _ => None,
+        })
+    }
+}
+
+impl std::fmt::Debug for Options<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub reply: bool,
+    pub xid: u32,
+    pub secs: u16,
+    pub broadcast: bool,
+    pub client_address: Ipv4Addr,
+    pub your_address: Ipv4Addr,
+    pub server_address: Ipv4Addr,
+    pub relay_address: Ipv4Addr,
+    pub client_mac: [u8; 6],
+}
+
+#[derive(Debug)]
+pub struct ParsedDhcp<'a> {
+    pub message: Message,
+    pub options: Options<'a>,
+}
+
+pub fn parse(buffer: &[u8]) -> Result<ParsedDhcp<'_>, ParseError> {
+    check_len(buffer, 240)?;
+    let address = |i: usize| Ipv4Addr::new(buffer[i], buffer[i + 1], buffer[i + 2], buffer[i + 3]);
+    if buffer[1] != HARDWARE_TYPE_ETHERNET || buffer[236..240] != MAGIC_COOKIE {
+        return Err(ParseError::Unsupported);
+    }
+    if buffer[2] != 6 {
+        return Err(ParseError::BadLength);
+    }
+    let reply = buffer[0] == OP_BOOTREPLY;
+    let xid = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+    let secs = u16::from_be_bytes([buffer[8], buffer[9]]);
+    let flags = u16::from_be_bytes([buffer[10], buffer[11]]);
+
+    let options = Options(&buffer[240..]);
+    options.check()?;
+
+    Ok(ParsedDhcp {
+        message: Message {
+            reply,
+            xid,
+            secs,
+            broadcast: flags & FLAG_BROADCAST != 0,
+            client_address: address(12),
+            your_address: address(16),
+            server_address: address(20),
+            relay_address: address(24),
+            client_mac: buffer[28..34].try_into().unwrap(),
+        },
+        options,
+    })
+}
+
+pub fn to_bytes(message: &Message, options: &[DhcpOption]) -> Vec<u8> {
+    let mut packet = vec![];
+    packet.push(if message.reply {
+        OP_BOOTREPLY
+    } else {
+        OP_BOOTREQUEST
+    });
+    packet.extend_from_slice(&[HARDWARE_TYPE_ETHERNET, 6, 0]);
+    packet.extend_from_slice(&message.xid.to_be_bytes());
+    packet.extend_from_slice(&message.secs.to_be_bytes());
+    let flags = if message.broadcast { FLAG_BROADCAST } else { 0 };
+    packet.extend_from_slice(&flags.to_be_bytes());
+    packet.extend_from_slice(&message.client_address.octets());
+    packet.extend_from_slice(&message.your_address.octets());
+    packet.extend_from_slice(&message.server_address.octets());
+    packet.extend_from_slice(&message.relay_address.octets());
+    packet.extend_from_slice(&message.client_mac);
+    // Rest of chaddr, sname and file
+    packet.resize(236, 0);
+    packet.extend_from_slice(&MAGIC_COOKIE);
+    for option in options {
+        option.write(&mut packet);
+    }
+    packet.push(OPTION_END);
+    packet
+}
+
+pub struct Config {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub pool_start: Ipv4Addr,
+    pub pool_size: u8,
+    pub lease_time: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LeaseState {
+    Offered,
+    Bound,
+    /// Held by no client, the address is in use by another host.
+    Declined,
+}
+
+struct Lease {
+    mac: [u8; 6],
+    address: Ipv4Addr,
+    state: LeaseState,
+    expires: Instant,
+}
+
+pub struct Reply {
+    pub destination_address: Ipv4Addr,
+    /// None for relay agents, which are reached at the MAC address the request came from.
+    pub destination_mac: Option<[u8; 6]>,
+    pub destination_port: u16,
+    pub payload: Vec<u8>,
+}
+
+pub struct Server {
+    config: Config,
+    leases: Vec<Lease>,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        Server {
+            config,
+            leases: vec![],
+        }
+    }
+
+    /// Lease of the client with `mac`, a new offer when it has none.
+    fn lease_for(&mut self, mac: [u8; 6], now: Instant) -> Option<&mut Lease> {
+        self.leases.retain(|lease| lease.expires > now);
+        if let Some(index) = self
+            .leases
+            .iter()
+            .position(|lease| lease.mac == mac && lease.state != LeaseState::Declined)
+        {
+            return Some(&mut self.leases[index]);
+        }
+
+        let start = u32::from(self.config.pool_start);
+        let address = (start..start + self.config.pool_size as u32)
+            .map(Ipv4Addr::from)
+            .find(|&address| self.leases.iter().all(|lease| lease.address != address))?;
+        self.leases.push(Lease {
+            mac,
+            address,
+            state: LeaseState::Offered,
+            expires: now + OFFER_TIMEOUT,
+        });
+        self.leases.last_mut()
+    }
+
+    fn release(&mut self, mac: [u8; 6]) {
+        self.leases
+            .retain(|lease| lease.mac != mac || lease.state == LeaseState::Declined);
+    }
+
+    fn decline(&mut self, mac: [u8; 6], now: Instant) {
+        for lease in self.leases.iter_mut() {
+            if lease.mac == mac && lease.state != LeaseState::Declined {
+                lease.state = LeaseState::Declined;
+                lease.expires = now + DECLINE_TIMEOUT;
+            }
+        }
+    }
+
+    pub fn handle(&mut self, dhcp: &ParsedDhcp, now: Instant) -> Option<Reply> {
+        let request = &dhcp.message;
+        if request.reply {
+            return None;
+        }
+
+        let server_identifier = dhcp.options.server_identifier();
+        let (message_type, your_address) = match dhcp.options.message_type()? {
+            MessageType::Discover => {
+                let lease = self.lease_for(request.client_mac, now)?;
+                if lease.state == LeaseState::Offered {
+                    lease.expires = now + OFFER_TIMEOUT;
+                }
+                (MessageType::Offer, lease.address)
+            }
+            MessageType::Request => {
+                if server_identifier.is_some_and(|id| id != self.config.address) {
+                    // The client picked an offer from another server
+                    self.release(request.client_mac);
+                    return None;
+                }
+                let requested = dhcp
+                    .options
+                    .requested_address()
+                    .unwrap_or(request.client_address);
+                let lease_time = Duration::from_secs(self.config.lease_time.into());
+                match self.lease_for(request.client_mac, now) {
+                    Some(lease) if lease.address == requested => {
+                        lease.state = LeaseState::Bound;
+                        lease.expires = now + lease_time;
+                        (MessageType::Ack, requested)
+                    }
+                    _ => (MessageType::Nak, Ipv4Addr::UNSPECIFIED),
+                }
+            }
+            MessageType::Inform => (MessageType::Ack, Ipv4Addr::UNSPECIFIED),
+            MessageType::Release => {
+                self.release(request.client_mac);
+                return None;
+            }
+            MessageType::Decline => {
+                self.decline(request.client_mac, now);
+                return None;
+            }
+            _ => return None,
+        };
+
+        let mut options = vec![
+            DhcpOption::MessageType(message_type),
+            DhcpOption::ServerIdentifier(self.config.address),
+        ];
+        if message_type != MessageType::Nak {
+            if your_address != Ipv4Addr::UNSPECIFIED {
+                options.push(DhcpOption::LeaseTime(self.config.lease_time));
+            }
+            options.push(DhcpOption::SubnetMask(self.config.netmask));
+            options.push(DhcpOption::Router(self.config.address));
+            options.push(DhcpOption::DomainNameServer(self.config.address));
+        }
+
+        // Relay agents get every reply on the server port (RFC 2131 section 4.1)
+        let relayed = request.relay_address != Ipv4Addr::UNSPECIFIED;
+        let (destination_address, destination_mac) = if relayed {
+            (request.relay_address, None)
+        } else if message_type == MessageType::Nak {
+            (Ipv4Addr::BROADCAST, Some(BROADCAST_MAC))
+        } else if request.client_address != Ipv4Addr::UNSPECIFIED {
+            (request.client_address, Some(request.client_mac))
+        } else if request.broadcast {
+            (Ipv4Addr::BROADCAST, Some(BROADCAST_MAC))
+        } else {
+            (your_address, Some(request.client_mac))
+        };
+
+        let payload = to_bytes(
+            &Message {
+                reply: true,
+                xid: request.xid,
+                secs: 0,
+                // Relayed NAKs are broadcast by the relay agent
+                broadcast: request.broadcast || (relayed && message_type == MessageType::Nak),
+                client_address: request.client_address,
+                your_address,
+                server_address: Ipv4Addr::UNSPECIFIED,
+                relay_address: request.relay_address,
+                client_mac: request.client_mac,
+            },
+            &options,
+        );
+
+        Some(Reply {
+            destination_address,
+            destination_mac,
+            destination_port: if relayed { SERVER_PORT } else { CLIENT_PORT },
+            payload,
+        })
+    }
+}
+
+impl UdpService for Server {
+    fn port(&self) -> u16 {
+        SERVER_PORT
+    }
+
+    fn handle(&mut self, datagram: &Datagram, now: Instant) -> Result<Vec<UdpReply>, ParseError> {
+        if !datagram.source_address.is_ipv4() {
+            return Ok(vec![]);
+        }
+        let dhcp = parse(datagram.payload)?;
+        log!(debug, "dhcp {:?}", dhcp);
+
+        Ok(Server::handle(self, &dhcp, now)
+            .map(|reply| UdpReply {
+                destination_mac: reply.destination_mac.unwrap_or(datagram.source_mac),
+                source_address: datagram.local_address,
+                destination_address: IpAddr::V4(reply.destination_address),
+                source_port: SERVER_PORT,
+                destination_port: reply.destination_port,
+                payload: reply.payload,
+            })
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+
+    fn server() -> Server {
+        Server::new(Config {
+            address: Ipv4Addr::new(192, 168, 42, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 252),
+            pool_start: Ipv4Addr::new(192, 168, 42, 2),
+            pool_size: 1,
+            lease_time: 3600,
+        })
+    }
+
+    fn request(mac: [u8; 6], options: &[DhcpOption]) -> Vec<u8> {
+        to_bytes(
+            &Message {
+                reply: false,
+                xid: 0x1234,
+                secs: 0,
+                broadcast: true,
+                client_address: Ipv4Addr::UNSPECIFIED,
+                your_address: Ipv4Addr::UNSPECIFIED,
+                server_address: Ipv4Addr::UNSPECIFIED,
+                relay_address: Ipv4Addr::UNSPECIFIED,
+                client_mac: mac,
+            },
+            options,
+        )
+    }
+
+    #[test]
+    fn test_discover_request_release() {
+        let mut server = server();
+        let now = Instant::now();
+
+        let discover = request(
+            CLIENT_MAC,
+            &[DhcpOption::MessageType(MessageType::Discover)],
+        );
+        let offer = server.handle(&parse(&discover).unwrap(), now).unwrap();
+        assert_eq!(offer.destination_address, Ipv4Addr::BROADCAST);
+        let offer = parse(&offer.payload).unwrap();
+        assert_eq!(offer.options.message_type(), Some(MessageType::Offer));
+        assert_eq!(offer.message.xid, 0x1234);
+        assert_eq!(offer.message.your_address, Ipv4Addr::new(192, 168, 42, 2));
+        assert!(offer
+            .options
+            .iter()
+            .any(|o| o == DhcpOption::Router(Ipv4Addr::new(192, 168, 42, 1))));
+
+        let req = request(
+            CLIENT_MAC,
+            &[
+                DhcpOption::MessageType(MessageType::Request),
+                DhcpOption::RequestedAddress(offer.message.your_address),
+                DhcpOption::ServerIdentifier(Ipv4Addr::new(192, 168, 42, 1)),
+            ],
+        );
+        let ack = server.handle(&parse(&req).unwrap(), now).unwrap();
+        let ack = parse(&ack.payload).unwrap();
+        assert_eq!(ack.options.message_type(), Some(MessageType::Ack));
+        assert_eq!(ack.message.your_address, Ipv4Addr::new(192, 168, 42, 2));
+
+        // The pool only holds one address
+        let other = request(
+            [2, 0, 0, 0, 0, 2],
+            &[DhcpOption::MessageType(MessageType::Discover)],
+        );
+        assert!(server.handle(&parse(&other).unwrap(), now).is_none());
+
+        let release = request(CLIENT_MAC, &[DhcpOption::MessageType(MessageType::Release)]);
+        assert!(server.handle(&parse(&release).unwrap(), now).is_none());
+        assert!(server.handle(&parse(&other).unwrap(), now).is_some());
+    }
+
+    #[test]
+    fn test_request_wrong_address() {
+        let mut server = server();
+        let now = Instant::now();
+        let req = request(
+            CLIENT_MAC,
+            &[
+                DhcpOption::MessageType(MessageType::Request),
+                DhcpOption::RequestedAddress(Ipv4Addr::new(10, 0, 0, 5)),
+            ],
+        );
+        let nak = server.handle(&parse(&req).unwrap(), now).unwrap();
+        assert_eq!(
+            parse(&nak.payload).unwrap().options.message_type(),
+            Some(MessageType::Nak)
+        );
+    }
+
+    #[test]
+    fn test_relayed_replies() {
+        let mut server = server();
+        let now = Instant::now();
+        let relay_address = Ipv4Addr::new(192, 168, 42, 3);
+        let relayed = |options: &[DhcpOption]| {
+            to_bytes(
+                &Message {
+                    reply: false,
+                    xid: 0x1234,
+                    secs: 0,
+                    broadcast: false,
+                    client_address: Ipv4Addr::UNSPECIFIED,
+                    your_address: Ipv4Addr::UNSPECIFIED,
+                    server_address: Ipv4Addr::UNSPECIFIED,
+                    relay_address,
+                    client_mac: CLIENT_MAC,
+                },
+                options,
+            )
+        };
+
+        let discover = relayed(&[DhcpOption::MessageType(MessageType::Discover)]);
+        let offer = server.handle(&parse(&discover).unwrap(), now).unwrap();
+        assert_eq!(offer.destination_address, relay_address);
+        assert_eq!(offer.destination_mac, None);
+        assert_eq!(offer.destination_port, SERVER_PORT);
+        assert_eq!(
+            parse(&offer.payload).unwrap().message.relay_address,
+            relay_address
+        );
+
+        let req = relayed(&[
+            DhcpOption::MessageType(MessageType::Request),
+            DhcpOption::RequestedAddress(Ipv4Addr::new(10, 0, 0, 5)),
+        ]);
+        let nak = server.handle(&parse(&req).unwrap(), now).unwrap();
+        assert_eq!(nak.destination_address, relay_address);
+        assert!(parse(&nak.payload).unwrap().message.broadcast);
+    }
+
+    #[test]
+    fn test_expiry_and_decline() {
+        let mut server = server();
+        let now = Instant::now();
+        let discover = request(
+            CLIENT_MAC,
+            &[DhcpOption::MessageType(MessageType::Discover)],
+        );
+        let other = request(
+            [2, 0, 0, 0, 0, 2],
+            &[DhcpOption::MessageType(MessageType::Discover)],
+        );
+        assert!(server.handle(&parse(&discover).unwrap(), now).is_some());
+
+        // An abandoned offer goes back to the pool
+        assert!(server.handle(&parse(&other).unwrap(), now).is_none());
+        let now = now + OFFER_TIMEOUT;
+        assert!(server.handle(&parse(&other).unwrap(), now).is_some());
+
+        // So does a lease that wasn't renewed
+        let req = request(
+            [2, 0, 0, 0, 0, 2],
+            &[
+                DhcpOption::MessageType(MessageType::Request),
+                DhcpOption::RequestedAddress(Ipv4Addr::new(192, 168, 42, 2)),
+            ],
+        );
+        assert!(server.handle(&parse(&req).unwrap(), now).is_some());
+        assert!(server.handle(&parse(&discover).unwrap(), now).is_none());
+        let now = now + Duration::from_secs(3600);
+        assert!(server.handle(&parse(&discover).unwrap(), now).is_some());
+
+        // A declined address isn't offered again until the quarantine ends
+        let decline = request(CLIENT_MAC, &[DhcpOption::MessageType(MessageType::Decline)]);
+        assert!(server.handle(&parse(&decline).unwrap(), now).is_none());
+        assert!(server.handle(&parse(&discover).unwrap(), now).is_none());
+        let now = now + DECLINE_TIMEOUT;
+        assert!(server.handle(&parse(&discover).unwrap(), now).is_some());
+    }
+}
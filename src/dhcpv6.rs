@@ -0,0 +1,756 @@
+use super::dns;
+use super::error::{check_len, ParseError};
+use super::interface::{Datagram, UdpReply, UdpService};
+use super::time::{Duration, Instant};
+use std::iter;
+use std::net::{IpAddr, Ipv6Addr};
+
+pub const SERVER_PORT: u16 = 547;
+pub const CLIENT_PORT: u16 = 546;
+
+pub const ALL_DHCP_RELAY_AGENTS_AND_SERVERS: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 1, 2);
+
+const OPTION_CLIENTID: u16 = 1;
+const OPTION_SERVERID: u16 = 2;
+const OPTION_IA_NA: u16 = 3;
+const OPTION_IAADDR: u16 = 5;
+const OPTION_STATUS_CODE: u16 = 13;
+const OPTION_RAPID_COMMIT: u16 = 14;
+const OPTION_DNS_SERVERS: u16 = 23;
+const OPTION_DOMAIN_LIST: u16 = 24;
+
+// Time a client has to request an advertised address before it goes back to the pool
+const ADVERTISE_TIMEOUT: Duration = Duration::from_secs(60);
+// Declined addresses are in use by another host, and kept out of the pool for a while
+// (RFC 8415 section 18.4.7)
+const DECLINE_TIMEOUT: Duration = Duration::from_secs(600);
+
+const DUID_TYPE_LL: u16 = 3;
+const HARDWARE_TYPE_ETHERNET: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    Solicit,
+    Advertise,
+    Request,
+    Confirm,
+    Renew,
+    Rebind,
+    Reply,
+    Release,
+    Decline,
+    InformationRequest,
+    Unknown(u8),
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => MessageType::Solicit,
+            2 => MessageType::Advertise,
+            3 => MessageType::Request,
+            4 => MessageType::Confirm,
+            5 => MessageType::Renew,
+            6 => MessageType::Rebind,
+            7 => MessageType::Reply,
+            8 => MessageType::Release,
+            9 => MessageType::Decline,
+            11 => MessageType::InformationRequest,
+            v => MessageType::Unknown(v),
+        }
+    }
+
+    fn value(&self) -> u8 {
+        match self {
+            MessageType::Solicit => 1,
+            MessageType::Advertise => 2,
+            MessageType::Request => 3,
+            MessageType::Confirm => 4,
+            MessageType::Renew => 5,
+            MessageType::Rebind => 6,
+            MessageType::Reply => 7,
+            MessageType::Release => 8,
+            MessageType::Decline => 9,
+            MessageType::InformationRequest => 11,
+            MessageType::Unknown(v) => *v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusCode {
+    Success,
+    NoAddrsAvail,
+    NoBinding,
+    NotOnLink,
+    Unknown(u16),
+}
+
+impl StatusCode {
+    fn from_u16(value: u16) -> Self {
+        match value {
+            0 => StatusCode::Success,
+            2 => StatusCode::NoAddrsAvail,
+            3 => StatusCode::NoBinding,
+            4 => StatusCode::NotOnLink,
+            v => StatusCode::Unknown(v),
+        }
+    }
+
+    fn value(&self) -> u16 {
+        match self {
+            StatusCode::Success => 0,
+            StatusCode::NoAddrsAvail => 2,
+            StatusCode::NoBinding => 3,
+            StatusCode::NotOnLink => 4,
+            StatusCode::Unknown(v) => *v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Dhcpv6Option<'a> {
+    ClientId(&'a [u8]),
+    ServerId(&'a [u8]),
+    IaNa {
+        iaid: u32,
+        t1: u32,
+        t2: u32,
+        options: Vec<Dhcpv6Option<'a>>,
+    },
+    IaAddress {
+        address: Ipv6Addr,
+        preferred_lifetime: u32,
+        valid_lifetime: u32,
+    },
+    StatusCode(StatusCode),
+    RapidCommit,
+    DnsServers(Vec<Ipv6Addr>),
+    DomainList(Vec<String>),
+    Unknown(u16, &'a [u8]),
+}
+
+impl<'a> Dhcpv6Option<'a> {
+    fn parse(code: u16, data: &'a [u8]) -> Self {
+        let u32_at =
+            |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let address_at = |i: usize| {
+            let address: [u8; 16] = data[i..i + 16].try_into().unwrap();
+            Ipv6Addr::from(address)
+        };
+        match code {
+            OPTION_CLIENTID => Dhcpv6Option::ClientId(data),
+            OPTION_SERVERID => Dhcpv6Option::ServerId(data),
+            OPTION_IA_NA if data.len() >= 12 => Dhcpv6Option::IaNa {
+                iaid: u32_at(0),
+                t1: u32_at(4),
+                t2: u32_at(8),
+                options: Options(&data[12..]).iter().collect(),
+            },
+            OPTION_IAADDR if data.len() >= 24 => Dhcpv6Option::IaAddress {
+                address: address_at(0),
+                preferred_lifetime: u32_at(16),
+                valid_lifetime: u32_at(20),
+            },
+            OPTION_STATUS_CODE if data.len() >= 2 => {
+                Dhcpv6Option::StatusCode(StatusCode::from_u16(u16::from_be_bytes([
+                    data[0], data[1],
+                ])))
+            }
+            OPTION_RAPID_COMMIT => Dhcpv6Option::RapidCommit,
+            OPTION_DNS_SERVERS if data.len().is_multiple_of(16) => {
+                Dhcpv6Option::DnsServers((0..data.len()).step_by(16).map(address_at).collect())
+            }
+            OPTION_DOMAIN_LIST => Dhcpv6Option::DomainList(
+                dns::read_names(data)
+                    .map(|name| name.as_str().to_owned())
+                    .collect(),
+            ),
+            code => Dhcpv6Option::Unknown(code, data),
+        }
+    }
+
+    fn write(&self, packet: &mut Vec<u8>) {
+        let start = packet.len();
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        let code = match self {
+            Dhcpv6Option::ClientId(duid) => {
+                packet.extend_from_slice(duid);
+                OPTION_CLIENTID
+            }
+            Dhcpv6Option::ServerId(duid) => {
+                packet.extend_from_slice(duid);
+                OPTION_SERVERID
+            }
+            Dhcpv6Option::IaNa {
+                iaid,
+                t1,
+                t2,
+                options,
+            } => {
+                packet.extend_from_slice(&iaid.to_be_bytes());
+                packet.extend_from_slice(&t1.to_be_bytes());
+                packet.extend_from_slice(&t2.to_be_bytes());
+                for option in options {
+                    option.write(packet);
+                }
+                OPTION_IA_NA
+            }
+            Dhcpv6Option::IaAddress {
+                address,
+                preferred_lifetime,
+                valid_lifetime,
+            } => {
+                packet.extend_from_slice(&address.octets());
+                packet.extend_from_slice(&preferred_lifetime.to_be_bytes());
+                packet.extend_from_slice(&valid_lifetime.to_be_bytes());
+                OPTION_IAADDR
+            }
+            Dhcpv6Option::StatusCode(status) => {
+                packet.extend_from_slice(&status.value().to_be_bytes());
+                OPTION_STATUS_CODE
+            }
+            Dhcpv6Option::RapidCommit => OPTION_RAPID_COMMIT,
+            Dhcpv6Option::DnsServers(servers) => {
+                for server in servers {
+                    packet.extend_from_slice(&server.octets());
+                }
+                OPTION_DNS_SERVERS
+            }
+            Dhcpv6Option::DomainList(domains) => {
+                for domain in domains {
+                    dns::write_name(domain, |bytes| packet.extend_from_slice(bytes));
+                }
+                OPTION_DOMAIN_LIST
+            }
+            Dhcpv6Option::Unknown(code, data) => {
+                packet.extend_from_slice(data);
+                *code
+            }
+        };
+        let len = (packet.len() - start - 4) as u16;
+        packet[start..start + 2].copy_from_slice(&code.to_be_bytes());
+        packet[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+// Code, data and the rest of the buffer
+type RawOption<'a> = (u16, &'a [u8], &'a [u8]);
+
+/// Split the next option off `buffer`.
+fn split_option(buffer: &[u8]) -> Result<Option<RawOption<'_>>, ParseError> {
+    if buffer.is_empty() {
+        return Ok(None);
+    }
+    check_len(buffer, 4)?;
+    let code = u16::from_be_bytes([buffer[0], buffer[1]]);
+    let len = 4 + u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+    check_len(buffer, len)?;
+    Ok(Some((code, &buffer[4..len], &buffer[len..])))
+}
+
+pub struct Options<'a>(&'a [u8]);
+
+impl<'a> Options<'a> {
+    /// Options encapsulated in an IA_NA are checked too.
+    fn check(&self) -> Result<(), ParseError> {
+        let mut buffer = self.0;
+        while let Some((code, data, rest)) = split_option(buffer)? {
+            if code == OPTION_IA_NA && data.len() >= 12 {
+                Options(&data[12..]).check()?;
+            }
+            buffer = rest;
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Dhcpv6Option<'a>> {
+        let mut buffer = self.0;
+        iter::from_fn(move || {
+            let (code, data, rest) = split_option(buffer).ok()??;
+            buffer = rest;
+            Some(Dhcpv6Option::parse(code, data))
+        })
+    }
+
+    pub fn client_id(&self) -> Option<&'a [u8]> {
+        self.iter().find_map(|option| match option {
+            Dhcpv6Option::ClientId(duid) => Some(duid),
+            _ => None,
+        })
+    }
+
+    pub fn server_id(&self) -> Option<&'a [u8]> {
+        self.iter().find_map(|option| match option {
+            Dhcpv6Option::ServerId(duid) => Some(duid),
+            _ => None,
+        })
+    }
+}
+
+impl std::fmt::Debug for Options<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub message_type: MessageType,
+    pub transaction_id: u32,
+}
+
+#[derive(Debug)]
+pub struct ParsedDhcpv6<'a> {
+    pub message: Message,
+    pub options: Options<'a>,
+}
+
+pub fn parse(buffer: &[u8]) -> Result<ParsedDhcpv6<'_>, ParseError> {
+    check_len(buffer, 4)?;
+    let options = Options(&buffer[4..]);
+    options.check()?;
+
+    Ok(ParsedDhcpv6 {
+        message: Message {
+            message_type: MessageType::from_u8(buffer[0]),
+            transaction_id: u32::from_be_bytes([0, buffer[1], buffer[2], buffer[3]]),
+        },
+        options,
+    })
+}
+
+pub fn to_bytes(message: &Message, options: &[Dhcpv6Option]) -> Vec<u8> {
+    let mut packet = vec![message.message_type.value()];
+    packet.extend_from_slice(&message.transaction_id.to_be_bytes()[1..]);
+    for option in options {
+        option.write(&mut packet);
+    }
+    packet
+}
+
+pub struct Config {
+    pub mac_address: [u8; 6],
+    pub pool_start: Ipv6Addr,
+    pub pool_size: u16,
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+    pub dns_servers: Vec<Ipv6Addr>,
+    pub search_domains: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LeaseState {
+    Advertised,
+    Bound,
+    /// Held by no client, the address is in use by another host.
+    Declined,
+}
+
+struct Lease {
+    client_id: Vec<u8>,
+    iaid: u32,
+    address: Ipv6Addr,
+    state: LeaseState,
+    expires: Instant,
+}
+
+pub struct Server {
+    config: Config,
+    duid: Vec<u8>,
+    leases: Vec<Lease>,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        let mut duid = vec![];
+        duid.extend_from_slice(&DUID_TYPE_LL.to_be_bytes());
+        duid.extend_from_slice(&HARDWARE_TYPE_ETHERNET.to_be_bytes());
+        duid.extend_from_slice(&config.mac_address);
+        Server {
+            config,
+            duid,
+            leases: vec![],
+        }
+    }
+
+    /// Lease of the client's identity association, a new advertised one when it has none
+    /// and `allocate` is set.
+    fn lease_for(
+        &mut self,
+        client_id: &[u8],
+        iaid: u32,
+        allocate: bool,
+        now: Instant,
+    ) -> Option<&mut Lease> {
+        self.leases.retain(|lease| lease.expires > now);
+        if let Some(index) = self.leases.iter().position(|lease| {
+            lease.client_id == client_id
+                && lease.iaid == iaid
+                && lease.state != LeaseState::Declined
+        }) {
+            return Some(&mut self.leases[index]);
+        }
+        if !allocate {
+            return None;
+        }
+
+        let start = u128::from(self.config.pool_start);
+        let address = (start..start + self.config.pool_size as u128)
+            .map(Ipv6Addr::from)
+            .find(|&address| self.leases.iter().all(|lease| lease.address != address))?;
+        self.leases.push(Lease {
+            client_id: client_id.to_vec(),
+            iaid,
+            address,
+            state: LeaseState::Advertised,
+            expires: now + ADVERTISE_TIMEOUT,
+        });
+        self.leases.last_mut()
+    }
+
+    /// IA_NA option for the client, binding the address when `bind` is set.
+    fn identity_association<'a>(
+        &mut self,
+        client_id: &[u8],
+        iaid: u32,
+        allocate: bool,
+        bind: bool,
+        now: Instant,
+    ) -> Dhcpv6Option<'a> {
+        let valid_lifetime = Duration::from_secs(self.config.valid_lifetime.into());
+        let address = self.lease_for(client_id, iaid, allocate, now).map(|lease| {
+            if bind {
+                lease.state = LeaseState::Bound;
+                lease.expires = now + valid_lifetime;
+            } else if lease.state == LeaseState::Advertised {
+                lease.expires = now + ADVERTISE_TIMEOUT;
+            }
+            lease.address
+        });
+        let options = match address {
+            Some(address) => vec![Dhcpv6Option::IaAddress {
+                address,
+                preferred_lifetime: self.config.preferred_lifetime,
+                valid_lifetime: self.config.valid_lifetime,
+            }],
+            None if allocate => vec![Dhcpv6Option::StatusCode(StatusCode::NoAddrsAvail)],
+            None => vec![Dhcpv6Option::StatusCode(StatusCode::NoBinding)],
+        };
+        Dhcpv6Option::IaNa {
+            iaid,
+            t1: self.config.preferred_lifetime / 2,
+            t2: self.config.preferred_lifetime / 5 * 4,
+            options,
+        }
+    }
+
+    pub fn handle(&mut self, dhcpv6: &ParsedDhcpv6, now: Instant) -> Option<Vec<u8>> {
+        let request = &dhcpv6.message;
+        let client_id = dhcpv6.options.client_id();
+        let server_id = dhcpv6.options.server_id();
+        let iaids: Vec<u32> = dhcpv6
+            .options
+            .iter()
+            .filter_map(|option| match option {
+                Dhcpv6Option::IaNa { iaid, .. } => Some(iaid),
+                _ => None,
+            })
+            .collect();
+        let rapid_commit = dhcpv6
+            .options
+            .iter()
+            .any(|option| option == Dhcpv6Option::RapidCommit);
+
+        // Messages targeted at another server
+        if server_id.is_some_and(|id| id != self.duid) {
+            return None;
+        }
+        // Messages about a binding must name its server (RFC 8415 section 16)
+        if server_id.is_none()
+            && matches!(
+                request.message_type,
+                MessageType::Request
+                    | MessageType::Renew
+                    | MessageType::Release
+                    | MessageType::Decline
+            )
+        {
+            return None;
+        }
+
+        let mut options = vec![];
+        let message_type = match request.message_type {
+            MessageType::Solicit => {
+                let client_id = client_id?;
+                for &iaid in &iaids {
+                    options.push(self.identity_association(
+                        client_id,
+                        iaid,
+                        true,
+                        rapid_commit,
+                        now,
+                    ));
+                }
+                if rapid_commit {
+                    options.push(Dhcpv6Option::RapidCommit);
+                    MessageType::Reply
+                } else {
+                    MessageType::Advertise
+                }
+            }
+            MessageType::Request | MessageType::Renew | MessageType::Rebind => {
+                let client_id = client_id?;
+                let allocate = request.message_type == MessageType::Request;
+                for &iaid in &iaids {
+                    options.push(self.identity_association(client_id, iaid, allocate, true, now));
+                }
+                MessageType::Reply
+            }
+            MessageType::Release | MessageType::Decline => {
+                let client_id = client_id?;
+                let declined = request.message_type == MessageType::Decline;
+                self.leases.retain_mut(|lease| {
+                    if lease.client_id != client_id
+                        || !iaids.contains(&lease.iaid)
+                        || lease.state == LeaseState::Declined
+                    {
+                        return true;
+                    }
+                    if declined {
+                        lease.state = LeaseState::Declined;
+                        lease.expires = now + DECLINE_TIMEOUT;
+                    }
+                    declined
+                });
+                options.push(Dhcpv6Option::StatusCode(StatusCode::Success));
+                MessageType::Reply
+            }
+            MessageType::InformationRequest => MessageType::Reply,
+            _ => return None,
+        };
+
+        options.push(Dhcpv6Option::ServerId(&self.duid));
+        if let Some(client_id) = client_id {
+            options.push(Dhcpv6Option::ClientId(client_id));
+        }
+        if !matches!(
+            request.message_type,
+            MessageType::Release | MessageType::Decline
+        ) {
+            if !self.config.dns_servers.is_empty() {
+                options.push(Dhcpv6Option::DnsServers(self.config.dns_servers.clone()));
+            }
+            if !self.config.search_domains.is_empty() {
+                options.push(Dhcpv6Option::DomainList(self.config.search_domains.clone()));
+            }
+        }
+
+        Some(to_bytes(
+            &Message {
+                message_type,
+                transaction_id: request.transaction_id,
+            },
+            &options,
+        ))
+    }
+}
+
+impl UdpService for Server {
+    fn port(&self) -> u16 {
+        SERVER_PORT
+    }
+
+    fn handle(&mut self, datagram: &Datagram, now: Instant) -> Result<Vec<UdpReply>, ParseError> {
+        if !datagram.source_address.is_ipv6() {
+            return Ok(vec![]);
+        }
+        let dhcpv6 = parse(datagram.payload)?;
+        log!(debug, "dhcpv6 {:?}", dhcpv6);
+
+        Ok(Server::handle(self, &dhcpv6, now)
+            .map(|payload| UdpReply {
+                destination_mac: datagram.source_mac,
+                source_address: datagram.local_address,
+                destination_address: datagram.source_address,
+                source_port: SERVER_PORT,
+                destination_port: CLIENT_PORT,
+                payload,
+            })
+            .into_iter()
+            .collect())
+    }
+
+    fn address_changed(&mut self, old: IpAddr, new: IpAddr, _now: Instant) {
+        let (IpAddr::V6(old), IpAddr::V6(new)) = (old, new) else {
+            return;
+        };
+        for server in &mut self.config.dns_servers {
+            if *server == old {
+                *server = new;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_ID: [u8; 10] = [0, 3, 0, 1, 2, 0, 0, 0, 0, 1];
+
+    fn server() -> Server {
+        Server::new(Config {
+            mac_address: [42; 6],
+            pool_start: "fd42:4242:4242::100".parse().unwrap(),
+            pool_size: 1,
+            preferred_lifetime: 3600,
+            valid_lifetime: 7200,
+            dns_servers: vec!["fd42:4242:4242::4242".parse().unwrap()],
+            search_domains: vec!["home.arpa".to_owned()],
+        })
+    }
+
+    fn message(message_type: MessageType, options: &[Dhcpv6Option]) -> Vec<u8> {
+        to_bytes(
+            &Message {
+                message_type,
+                transaction_id: 0x123456,
+            },
+            options,
+        )
+    }
+
+    fn ia_address(options: &Options) -> Option<Ipv6Addr> {
+        options.iter().find_map(|option| match option {
+            Dhcpv6Option::IaNa { options, .. } => options.iter().find_map(|option| match option {
+                Dhcpv6Option::IaAddress { address, .. } => Some(*address),
+                _ => None,
+            }),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_solicit_request() {
+        let mut server = server();
+        let now = Instant::now();
+        let ia_na = Dhcpv6Option::IaNa {
+            iaid: 1,
+            t1: 0,
+            t2: 0,
+            options: vec![],
+        };
+
+        let solicit = message(
+            MessageType::Solicit,
+            &[Dhcpv6Option::ClientId(&CLIENT_ID), ia_na.clone()],
+        );
+        let advertise = server.handle(&parse(&solicit).unwrap(), now).unwrap();
+        let advertise = parse(&advertise).unwrap();
+        assert_eq!(advertise.message.message_type, MessageType::Advertise);
+        assert_eq!(advertise.message.transaction_id, 0x123456);
+        assert_eq!(advertise.options.client_id(), Some(&CLIENT_ID[..]));
+        let address = ia_address(&advertise.options).unwrap();
+        assert_eq!(address, "fd42:4242:4242::100".parse::<Ipv6Addr>().unwrap());
+
+        let server_id = advertise.options.server_id().unwrap();
+        let request = message(
+            MessageType::Request,
+            &[
+                Dhcpv6Option::ClientId(&CLIENT_ID),
+                Dhcpv6Option::ServerId(server_id),
+                ia_na,
+            ],
+        );
+        let reply = server.handle(&parse(&request).unwrap(), now).unwrap();
+        let reply = parse(&reply).unwrap();
+        assert_eq!(reply.message.message_type, MessageType::Reply);
+        assert_eq!(ia_address(&reply.options), Some(address));
+        assert!(reply.options.iter().any(|option| option
+            == Dhcpv6Option::DnsServers(vec!["fd42:4242:4242::4242".parse().unwrap()])));
+    }
+
+    #[test]
+    fn test_information_request() {
+        let mut server = server();
+        let now = Instant::now();
+        let request = message(
+            MessageType::InformationRequest,
+            &[Dhcpv6Option::ClientId(&CLIENT_ID)],
+        );
+        let reply = server.handle(&parse(&request).unwrap(), now).unwrap();
+        let reply = parse(&reply).unwrap();
+        assert_eq!(reply.message.message_type, MessageType::Reply);
+        assert_eq!(ia_address(&reply.options), None);
+        assert!(reply
+            .options
+            .iter()
+            .any(|option| option == Dhcpv6Option::DomainList(vec!["home.arpa".to_owned()])));
+    }
+
+    #[test]
+    fn test_expiry_and_decline() {
+        let mut server = server();
+        let now = Instant::now();
+        let ia_na = Dhcpv6Option::IaNa {
+            iaid: 1,
+            t1: 0,
+            t2: 0,
+            options: vec![],
+        };
+        let other_id = [0, 3, 0, 1, 2, 0, 0, 0, 0, 2];
+        let solicit = |client_id| {
+            message(
+                MessageType::Solicit,
+                &[Dhcpv6Option::ClientId(client_id), ia_na.clone()],
+            )
+        };
+        let advertised = |server: &mut Server, client_id, now| {
+            let advertise = server.handle(&parse(&solicit(client_id)).unwrap(), now);
+            ia_address(&parse(&advertise.unwrap()).unwrap().options)
+        };
+        assert!(advertised(&mut server, &CLIENT_ID, now).is_some());
+
+        // An abandoned advertisement goes back to the pool
+        assert_eq!(advertised(&mut server, &other_id, now), None);
+        let now = now + ADVERTISE_TIMEOUT;
+        let address = advertised(&mut server, &other_id, now).unwrap();
+
+        // Requests without the Server ID are dropped
+        let request = message(
+            MessageType::Request,
+            &[Dhcpv6Option::ClientId(&other_id), ia_na.clone()],
+        );
+        assert!(server.handle(&parse(&request).unwrap(), now).is_none());
+        let duid = server.duid.clone();
+        let request = message(
+            MessageType::Request,
+            &[
+                Dhcpv6Option::ClientId(&other_id),
+                Dhcpv6Option::ServerId(&duid),
+                ia_na.clone(),
+            ],
+        );
+        assert!(server.handle(&parse(&request).unwrap(), now).is_some());
+
+        // A binding expires at the end of its valid lifetime
+        assert_eq!(advertised(&mut server, &CLIENT_ID, now), None);
+        let now = now + Duration::from_secs(7200);
+        assert_eq!(advertised(&mut server, &CLIENT_ID, now), Some(address));
+
+        // A declined address isn't advertised again until the quarantine ends
+        let decline = message(
+            MessageType::Decline,
+            &[
+                Dhcpv6Option::ClientId(&CLIENT_ID),
+                Dhcpv6Option::ServerId(&duid),
+                ia_na.clone(),
+            ],
+        );
+        assert!(server.handle(&parse(&decline).unwrap(), now).is_some());
+        assert_eq!(advertised(&mut server, &CLIENT_ID, now), None);
+        let now = now + DECLINE_TIMEOUT;
+        assert_eq!(advertised(&mut server, &CLIENT_ID, now), Some(address));
+    }
+}
@@ -1,31 +1,191 @@
-use std::fmt;
-use std::iter;
+use super::buffer::PacketBuffer;
+use super::error::{check_len, ParseError};
+use core::fmt;
+use core::iter;
+use core::net::{Ipv4Addr, Ipv6Addr};
 
+// Longest name in text form, without the trailing dot (RFC 1035 section 3.1)
+pub const MAX_NAME_LENGTH: usize = 253;
+
+/// Name read from the wire into a fixed-capacity string.
+pub type Name = heapless::String<MAX_NAME_LENGTH>;
+
+// Names are at most 255 bytes on the wire (RFC 1035 section 3.1)
+const MAX_ENCODED_NAME_LENGTH: usize = 255;
+const HEADER_LENGTH: usize = 12;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_NSEC: u16 = 47;
+/// Question type matching every record type.
+pub const TYPE_ANY: u16 = 255;
+
+/// Longest label (RFC 1035 section 2.3.4).
+pub const MAX_LABEL_LENGTH: usize = 63;
+
+/// Name in a DNS message, its labels may continue elsewhere in the message through
+/// compression pointers. Names to write can also be built from text.
 #[derive(Clone, Copy)]
-pub struct DomainName<'a>(&'a [u8]);
+pub struct DomainName<'a>(Labels<'a>);
 
-impl DomainName<'_> {
-    pub fn parts(&self) -> impl Iterator<Item = &'_ str> {
-        let mut buffer = self.0;
-        iter::from_fn(move || {
-            if buffer.is_empty() {
-                None
-            } else {
-                let len = 1 + buffer[0] as usize;
-                let slice = &buffer[1..len];
-                buffer = &buffer[len..];
-                Some(std::str::from_utf8(slice).unwrap())
+#[derive(Clone, Copy)]
+enum Labels<'a> {
+    Wire {
+        message: &'a [u8],
+        offset: usize,
+    },
+    /// Dot-separated labels, checked by `from_text`.
+    Text(&'a str),
+}
+
+impl<'a> DomainName<'a> {
+    /// Name in text form such as `licorne.local`, the trailing dot is optional.
+    pub fn from_text(name: &'a str) -> Result<DomainName<'a>, ParseError> {
+        let mut encoded_len = 1;
+        for label in name.split('.').filter(|label| !label.is_empty()) {
+            if label.len() > MAX_LABEL_LENGTH {
+                return Err(ParseError::BadLength);
+            }
+            encoded_len += 1 + label.len();
+        }
+        if encoded_len > MAX_ENCODED_NAME_LENGTH {
+            return Err(ParseError::BadLength);
+        }
+        Ok(DomainName(Labels::Text(name)))
+    }
+
+    /// Name of uncompressed labels, e.g. written by `write_name`.
+    pub fn new(encoded: &'a [u8]) -> Result<DomainName<'a>, ParseError> {
+        let (name, len) = DomainName::parse(encoded, 0)?;
+        if len != encoded.len() {
+            return Err(ParseError::BadLength);
+        }
+        Ok(name)
+    }
+
+    /// Name at `offset` in `message` and the length it takes there. Labels are checked for
+    /// UTF-8 so that `parts` can't fail, and pointers must go backwards so that they can't
+    /// loop.
+    fn parse(message: &'a [u8], offset: usize) -> Result<(DomainName<'a>, usize), ParseError> {
+        let mut position = offset;
+        let mut len = None;
+        let mut encoded_len = 0;
+        loop {
+            let label_len = *message.get(position).ok_or(ParseError::Truncated)? as usize;
+            match label_len {
+                0 => {
+                    let len = len.unwrap_or_else(|| position + 1 - offset);
+                    return Ok((DomainName(Labels::Wire { message, offset }), len));
+                }
+                0xC0.. => {
+                    let pointer = *message.get(position + 1).ok_or(ParseError::Truncated)?;
+                    let target = (label_len & 0x3F) << 8 | pointer as usize;
+                    if target >= position {
+                        return Err(ParseError::Unsupported);
+                    }
+                    len.get_or_insert_with(|| position + 2 - offset);
+                    position = target;
+                }
+                // Extended label types
+                0x40.. => return Err(ParseError::Unsupported),
+                _ => {
+                    let label = message
+                        .get(position + 1..position + 1 + label_len)
+                        .ok_or(ParseError::Truncated)?;
+                    if core::str::from_utf8(label).is_err() {
+                        return Err(ParseError::Unsupported);
+                    }
+                    encoded_len += 1 + label_len;
+                    if encoded_len >= MAX_ENCODED_NAME_LENGTH {
+                        return Err(ParseError::BadLength);
+                    }
+                    position += 1 + label_len;
+                }
+            }
+        }
+    }
+
+    pub fn parts(&self) -> impl Iterator<Item = &'a str> + Clone {
+        let (mut wire, mut text) = match self.0 {
+            Labels::Wire { message, offset } => (Some((message, offset)), None),
+            Labels::Text(name) => (
+                None,
+                Some(name.split('.').filter(|label| !label.is_empty())),
+            ),
+        };
+        iter::from_fn(move || loop {
+            if let Some(labels) = &mut text {
+                return labels.next();
+            }
+            let (message, position) = wire.as_mut()?;
+            let message = *message;
+            let label_len = message[*position] as usize;
+            match label_len {
+                0 => return None,
+                0xC0.. => {
+                    *position = (label_len & 0x3F) << 8 | message[*position + 1] as usize;
+                }
+                _ => {
+                    let label = &message[*position + 1..*position + 1 + label_len];
+                    *position += 1 + label_len;
+                    return core::str::from_utf8(label).ok();
+                }
             }
         })
     }
 
-    fn len(buffer: &[u8]) -> usize {
-        println!("{:?}", buffer);
-        let mut len = 0;
-        while buffer[len] != 0 {
-            len += 1 + buffer[len] as usize
+    /// Length of the name written without compression.
+    pub fn encoded_len(&self) -> usize {
+        self.parts().map(|part| 1 + part.len()).sum::<usize>() + 1
+    }
+
+    /// Whether this is `name` in text form, ignoring ASCII case as DNS does.
+    pub fn eq_ignore_ascii_case(&self, name: &str) -> bool {
+        let labels = name.split('.').filter(|label| !label.is_empty());
+        same_labels(labels, self.parts())
+    }
+
+    pub fn to_name(&self) -> Name {
+        let mut name = Name::new();
+        for part in self.parts() {
+            if !name.is_empty() {
+                let _ = name.push('.');
+            }
+            // Names checked by `parse` fit
+            let _ = name.push_str(part);
+        }
+        name
+    }
+
+    fn write(&self, buffer: &mut PacketBuffer) {
+        for part in self.parts() {
+            buffer.append(&[part.len() as u8]);
+            buffer.append(part.as_bytes());
         }
-        len + 1
+        buffer.append(&[0]);
+    }
+}
+
+// Whether `other` has the labels of `labels`, ignoring ASCII case
+fn same_labels<'a, 'b>(
+    mut labels: impl Iterator<Item = &'a str>,
+    mut other: impl Iterator<Item = &'b str>,
+) -> bool {
+    other.all(|part| {
+        labels
+            .next()
+            .is_some_and(|label| label.eq_ignore_ascii_case(part))
+    }) && labels.next().is_none()
+}
+
+/// Names compare like DNS does, ignoring ASCII case and compression.
+impl PartialEq for DomainName<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        same_labels(other.parts(), self.parts())
     }
 }
 
@@ -39,6 +199,39 @@ impl fmt::Debug for DomainName<'_> {
     }
 }
 
+/// Write `name` as uncompressed labels, passing the encoded bytes to `write`.
+pub fn write_name(name: &str, mut write: impl FnMut(&[u8])) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        write(&[label.len() as u8]);
+        write(label.as_bytes());
+    }
+    write(&[0]);
+}
+
+/// Read a list of uncompressed names, as carried by the DNSSL and DHCPv6 domain list options.
+pub fn read_names(mut buffer: &[u8]) -> impl Iterator<Item = Name> + '_ {
+    iter::from_fn(move || {
+        let mut name = Name::new();
+        while let Some((&len, rest)) = buffer.split_first() {
+            if len == 0 {
+                if name.is_empty() {
+                    // Trailing padding
+                    return None;
+                }
+                buffer = rest;
+                return Some(name);
+            }
+            if !name.is_empty() {
+                name.push('.').ok()?;
+            }
+            let label = rest.get(..len as usize)?;
+            name.push_str(core::str::from_utf8(label).ok()?).ok()?;
+            buffer = &rest[len as usize..];
+        }
+        None
+    })
+}
+
 #[derive(Debug)]
 pub struct Question<'a> {
     pub name: DomainName<'a>,
@@ -47,34 +240,262 @@ pub struct Question<'a> {
 }
 
 impl Question<'_> {
-    fn parse(buffer: &[u8]) -> (Question<'_>, usize) {
-        let name_end = DomainName::len(buffer);
-        let qtype = u16::from_be_bytes([buffer[name_end], buffer[name_end + 1]]);
-        let class = u16::from_be_bytes([buffer[name_end + 2], buffer[name_end + 3]]);
-        (
-            Question {
-                name: DomainName(&buffer[0..name_end]),
-                qtype,
-                class,
-            },
-            name_end + 4,
-        )
+    fn parse(message: &[u8], offset: usize) -> Result<(Question<'_>, usize), ParseError> {
+        let (name, name_len) = DomainName::parse(message, offset)?;
+        let fields = offset + name_len;
+        check_len(message, fields + 4)?;
+        let qtype = u16::from_be_bytes([message[fields], message[fields + 1]]);
+        let class = u16::from_be_bytes([message[fields + 2], message[fields + 3]]);
+        Ok((Question { name, qtype, class }, name_len + 4))
+    }
+}
+
+/// Strings of a TXT record, each preceded by its length (RFC 1035 section 3.3.14).
+#[derive(Clone, Copy, PartialEq)]
+pub struct CharacterStrings<'a>(&'a [u8]);
+
+impl<'a> CharacterStrings<'a> {
+    fn parse(data: &'a [u8]) -> Result<CharacterStrings<'a>, ParseError> {
+        let mut rest = data;
+        while let Some((&len, strings)) = rest.split_first() {
+            rest = strings.get(len as usize..).ok_or(ParseError::Truncated)?;
+        }
+        Ok(CharacterStrings(data))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a [u8]> {
+        let mut rest = self.0;
+        iter::from_fn(move || {
+            let (&len, strings) = rest.split_first()?;
+            let (string, next) = strings.split_at(len as usize);
+            rest = next;
+            Some(string)
+        })
+    }
+}
+
+impl fmt::Debug for CharacterStrings<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.iter()
+                    .map(|string| core::str::from_utf8(string).unwrap_or("?")),
+            )
+            .finish()
+    }
+}
+
+/// Types an NSEC record says exist, as windows of bitmaps (RFC 4034 section 4.1.2).
+#[derive(Clone, Copy, PartialEq)]
+pub struct TypeBitmaps<'a>(&'a [u8]);
+
+impl<'a> TypeBitmaps<'a> {
+    fn parse(data: &'a [u8]) -> Result<TypeBitmaps<'a>, ParseError> {
+        let mut rest = data;
+        while !rest.is_empty() {
+            check_len(rest, 2)?;
+            let len = rest[1] as usize;
+            if !(1..=32).contains(&len) {
+                return Err(ParseError::BadLength);
+            }
+            rest = rest.get(2 + len..).ok_or(ParseError::Truncated)?;
+        }
+        Ok(TypeBitmaps(data))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + 'a {
+        let mut rest = self.0;
+        iter::from_fn(move || {
+            let (&window, windows) = rest.split_first()?;
+            let (&len, windows) = windows.split_first()?;
+            let (bitmap, next) = windows.split_at(len as usize);
+            rest = next;
+            Some((window as u16, bitmap))
+        })
+        .flat_map(|(window, bitmap)| {
+            bitmap.iter().enumerate().flat_map(move |(index, byte)| {
+                (0..8)
+                    .filter(move |bit| byte & (0x80 >> bit) != 0)
+                    .map(move |bit| window << 8 | (index * 8 + bit) as u16)
+            })
+        })
+    }
+
+    pub fn contains(&self, rtype: u16) -> bool {
+        self.iter().any(|listed| listed == rtype)
+    }
+}
+
+impl fmt::Debug for TypeBitmaps<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Data of a record, typed for the record types the services use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RData<'a> {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(DomainName<'a>),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DomainName<'a>,
+    },
+    Txt(CharacterStrings<'a>),
+    Nsec {
+        next_domain_name: DomainName<'a>,
+        types: TypeBitmaps<'a>,
+    },
+    Other(&'a [u8]),
+}
+
+impl<'a> RData<'a> {
+    /// Data of type `rtype` without compressed names, e.g. written by `write_name`.
+    pub fn new(rtype: u16, data: &'a [u8]) -> Result<RData<'a>, ParseError> {
+        RData::parse(rtype, data, 0, data.len())
     }
 
-    fn len(buffer: &[u8]) -> usize {
-        DomainName::len(buffer) + 4
+    // Names in the data may point to the rest of the message
+    fn parse(
+        rtype: u16,
+        message: &'a [u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<RData<'a>, ParseError> {
+        let data = message
+            .get(offset..offset + len)
+            .ok_or(ParseError::Truncated)?;
+        // Name taking the rest of the data
+        let name_at = |start: usize| {
+            let (name, name_len) = DomainName::parse(message, offset + start)?;
+            if start + name_len != len {
+                return Err(ParseError::BadLength);
+            }
+            Ok(name)
+        };
+        let rdata = match rtype {
+            TYPE_A => RData::A(Ipv4Addr::from(
+                <[u8; 4]>::try_from(data).map_err(|_| ParseError::BadLength)?,
+            )),
+            TYPE_AAAA => RData::Aaaa(Ipv6Addr::from(
+                <[u8; 16]>::try_from(data).map_err(|_| ParseError::BadLength)?,
+            )),
+            TYPE_PTR => RData::Ptr(name_at(0)?),
+            TYPE_SRV => {
+                check_len(data, 6)?;
+                RData::Srv {
+                    priority: u16::from_be_bytes([data[0], data[1]]),
+                    weight: u16::from_be_bytes([data[2], data[3]]),
+                    port: u16::from_be_bytes([data[4], data[5]]),
+                    target: name_at(6)?,
+                }
+            }
+            TYPE_TXT => RData::Txt(CharacterStrings::parse(data)?),
+            TYPE_NSEC => {
+                let (next_domain_name, name_len) = DomainName::parse(message, offset)?;
+                let types = data.get(name_len..).ok_or(ParseError::BadLength)?;
+                RData::Nsec {
+                    next_domain_name,
+                    types: TypeBitmaps::parse(types)?,
+                }
+            }
+            _ => RData::Other(data),
+        };
+        Ok(rdata)
+    }
+
+    /// Length of the data written without compression.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            RData::A(_) => 4,
+            RData::Aaaa(_) => 16,
+            RData::Ptr(name) => name.encoded_len(),
+            RData::Srv { target, .. } => 6 + target.encoded_len(),
+            RData::Txt(strings) => strings.0.len(),
+            RData::Nsec {
+                next_domain_name,
+                types,
+            } => next_domain_name.encoded_len() + types.0.len(),
+            RData::Other(data) => data.len(),
+        }
+    }
+
+    fn write(&self, buffer: &mut PacketBuffer) {
+        match self {
+            RData::A(address) => buffer.append(&address.octets()),
+            RData::Aaaa(address) => buffer.append(&address.octets()),
+            RData::Ptr(name) => name.write(buffer),
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                buffer.append(&priority.to_be_bytes());
+                buffer.append(&weight.to_be_bytes());
+                buffer.append(&port.to_be_bytes());
+                target.write(buffer);
+            }
+            RData::Txt(strings) => buffer.append(strings.0),
+            RData::Nsec {
+                next_domain_name,
+                types,
+            } => {
+                next_domain_name.write(buffer);
+                buffer.append(types.0);
+            }
+            RData::Other(data) => buffer.append(data),
+        }
+    }
+
+    /// Data without compression, e.g. to compare it byte by byte.
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0; self.encoded_len()];
+        self.write(&mut PacketBuffer::new(&mut data, 0));
+        data
     }
 }
 
+#[derive(Debug)]
 pub struct Resource<'a> {
     pub name: DomainName<'a>,
     pub rtype: u16,
     pub class: u16,
     pub ttl: u32,
-    pub data: &'a [u8],
+    pub data: RData<'a>,
 }
 
-#[derive(Debug)]
+impl Resource<'_> {
+    fn parse(message: &[u8], offset: usize) -> Result<(Resource<'_>, usize), ParseError> {
+        let (name, name_len) = DomainName::parse(message, offset)?;
+        let fields = offset + name_len;
+        check_len(message, fields + 10)?;
+        let field = |index: usize| {
+            u16::from_be_bytes([message[fields + index], message[fields + index + 1]])
+        };
+        let rtype = field(0);
+        let class = field(2);
+        let ttl = (field(4) as u32) << 16 | field(6) as u32;
+        let data_len = field(8) as usize;
+        let data = RData::parse(rtype, message, fields + 10, data_len)?;
+        Ok((
+            Resource {
+                name,
+                rtype,
+                class,
+                ttl,
+                data,
+            },
+            name_len + 10 + data_len,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct Header {
     pub id: u16,
     pub query: bool,
@@ -86,32 +507,66 @@ pub struct Header {
     pub rcode: u8,
 }
 
-#[derive(Debug)]
-pub struct Resources<'a>(&'a [u8]);
+// Reads an entry at an offset of the message, returning it with its length
+type ParseEntry<'a, T> = fn(&'a [u8], usize) -> Result<(T, usize), ParseError>;
 
-pub struct Questions<'a>(&'a [u8]);
+/// Section of a message, the entries it counts were checked by `parse`.
+pub struct Section<'a, T> {
+    message: &'a [u8],
+    offset: usize,
+    count: u16,
+    parse: ParseEntry<'a, T>,
+}
 
-impl Questions<'_> {
-    pub fn iter(&self) -> impl Iterator<Item = Question<'_>> {
-        let mut buffer = self.0;
-        iter::from_fn(move || {
-            if buffer.is_empty() {
-                None
-            } else {
-                let (question, len) = Question::parse(buffer);
-                buffer = &buffer[len..];
-                Some(question)
-            }
+impl<'a, T: 'a> Section<'a, T> {
+    // Check the entries and return the section with the offset following it
+    fn parse(
+        message: &'a [u8],
+        offset: usize,
+        count: u16,
+        parse: ParseEntry<'a, T>,
+    ) -> Result<(Section<'a, T>, usize), ParseError> {
+        let mut end = offset;
+        for _ in 0..count {
+            end += parse(message, end)?.1;
+        }
+        let section = Section {
+            message,
+            offset,
+            count,
+            parse,
+        };
+        Ok((section, end))
+    }
+
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> + 'a {
+        let (message, parse) = (self.message, self.parse);
+        let mut offset = self.offset;
+        (0..self.count).map_while(move |_| {
+            let (entry, len) = parse(message, offset).ok()?;
+            offset += len;
+            Some(entry)
         })
     }
 }
 
-impl fmt::Debug for Questions<'_> {
+impl<'a, T: fmt::Debug + 'a> fmt::Debug for Section<'a, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_list().entries(self.iter()).finish()
     }
 }
 
+pub type Questions<'a> = Section<'a, Question<'a>>;
+pub type Resources<'a> = Section<'a, Resource<'a>>;
+
 #[derive(Debug)]
 pub struct ParsedDns<'a> {
     pub header: Header,
@@ -121,83 +576,384 @@ pub struct ParsedDns<'a> {
     pub additional: Resources<'a>,
 }
 
-pub fn parse(buffer: &[u8]) -> ParsedDns<'_> {
-    println!("{:?}", buffer);
+pub fn parse(buffer: &[u8]) -> Result<ParsedDns<'_>, ParseError> {
+    check_len(buffer, HEADER_LENGTH)?;
     let id = u16::from_be_bytes([buffer[0], buffer[1]]);
     let flags = u16::from_be_bytes([buffer[2], buffer[3]]);
-    let query = (flags >> 15) == 0;
-    let opcode = ((flags >> 11) & 0xF) as u8;
-    let authoritative_answer = (flags >> 10) == 1;
-    let truncation = (flags >> 9) == 1;
-    let recursion_desired = (flags >> 8) == 1;
-    let recursion_available = (flags >> 7) == 1;
-    let rcode = (flags & 0xF) as u8;
-    let qd_count = u16::from_be_bytes([buffer[4], buffer[5]]);
-    let an_count = u16::from_be_bytes([buffer[6], buffer[7]]);
-    let ns_count = u16::from_be_bytes([buffer[8], buffer[9]]);
-    let ar_count = u16::from_be_bytes([buffer[10], buffer[11]]);
-
-    let questions_start = &buffer[12..];
-    let mut questions_len = 0;
-    for _ in 0..qd_count {
-        questions_len = Question::len(&questions_start[questions_len..]);
-        break;
-    }
-    let questions = Questions(&questions_start[0..questions_len]);
-
-    ParsedDns {
+    let flag = |bit: u16| (flags >> bit) & 1 == 1;
+    let count = |index: usize| u16::from_be_bytes([buffer[index], buffer[index + 1]]);
+
+    let (questions, offset) = Section::parse(buffer, HEADER_LENGTH, count(4), Question::parse)?;
+    let (answers, offset) = Section::parse(buffer, offset, count(6), Resource::parse)?;
+    let (authority, offset) = Section::parse(buffer, offset, count(8), Resource::parse)?;
+    let (additional, _) = Section::parse(buffer, offset, count(10), Resource::parse)?;
+
+    Ok(ParsedDns {
         header: Header {
             id,
-            query,
-            opcode,
-            authoritative_answer,
-            truncation,
-            recursion_desired,
-            recursion_available,
-            rcode,
+            query: !flag(15),
+            opcode: ((flags >> 11) & 0xF) as u8,
+            authoritative_answer: flag(10),
+            truncation: flag(9),
+            recursion_desired: flag(8),
+            recursion_available: flag(7),
+            rcode: (flags & 0xF) as u8,
         },
         questions,
-        answers: Resources(&[]),
-        authority: Resources(&[]),
-        additional: Resources(&[]),
+        answers,
+        authority,
+        additional,
+    })
+}
+
+// Label offsets remembered for compression, later names are written in full
+const MAX_COMPRESSION_TARGETS: usize = 64;
+// Pointers hold 14 bits of offset
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// Writes names of a message, replacing suffixes written before with pointers to them
+/// (RFC 1035 section 4.1.4).
+struct NameCompressor {
+    /// Offset of the message in the buffer.
+    start: usize,
+    /// Offsets in the message of the labels written so far.
+    labels: heapless::Vec<u16, MAX_COMPRESSION_TARGETS>,
+}
+
+impl NameCompressor {
+    fn write(&mut self, buffer: &mut PacketBuffer, name: &DomainName) {
+        let mut parts = name.parts();
+        loop {
+            let suffix = parts.clone();
+            let message = &buffer.data()[self.start..];
+            let written = self.labels.iter().copied().find(|offset| {
+                DomainName::parse(message, *offset as usize)
+                    .is_ok_and(|(written, _)| same_labels(written.parts(), suffix.clone()))
+            });
+            if let Some(offset) = written {
+                buffer.append(&(0xC000 | offset).to_be_bytes());
+                return;
+            }
+            let Some(part) = parts.next() else {
+                buffer.append(&[0]);
+                return;
+            };
+            let offset = buffer.len() - self.start;
+            if offset <= MAX_POINTER_OFFSET {
+                // Past the capacity, names just aren't compressed
+                let _ = self.labels.push(offset as u16);
+            }
+            buffer.append(&[part.len() as u8]);
+            buffer.append(part.as_bytes());
+        }
     }
 }
 
-pub fn to_bytes(header: Header, answers: &[Resource]) -> Vec<u8> {
-    let mut packet = vec![];
-    packet.extend_from_slice(&header.id.to_be_bytes());
-    let mut flags: u16 = header.rcode as u16;
-    if header.query == false {
-        flags |= (1 << 15);
+/// Message to write, the counterpart of `ParsedDns`.
+pub struct Message<'a> {
+    pub header: Header,
+    pub questions: &'a [Question<'a>],
+    pub answers: &'a [Resource<'a>],
+    pub authority: &'a [Resource<'a>],
+    pub additional: &'a [Resource<'a>],
+}
+
+impl Message<'_> {
+    fn resources(&self) -> impl Iterator<Item = &Resource<'_>> {
+        self.answers
+            .iter()
+            .chain(self.authority)
+            .chain(self.additional)
     }
-    if header.authoritative_answer {
-        flags |= (1 << 10);
+
+    /// Append the message to `buffer`, compressing the names of the entries and of PTR data.
+    pub fn emit(&self, buffer: &mut PacketBuffer) {
+        let mut names = NameCompressor {
+            start: buffer.len(),
+            labels: heapless::Vec::new(),
+        };
+        let header = &self.header;
+        buffer.append(&header.id.to_be_bytes());
+        let mut flags = ((header.opcode as u16 & 0xF) << 11) | (header.rcode as u16 & 0xF);
+        for (bit, set) in [
+            (15, !header.query),
+            (10, header.authoritative_answer),
+            (9, header.truncation),
+            (8, header.recursion_desired),
+            (7, header.recursion_available),
+        ] {
+            if set {
+                flags |= 1 << bit;
+            }
+        }
+        buffer.append(&flags.to_be_bytes());
+        for count in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authority.len(),
+            self.additional.len(),
+        ] {
+            buffer.append(&(count as u16).to_be_bytes());
+        }
+        for question in self.questions {
+            names.write(buffer, &question.name);
+            buffer.append(&question.qtype.to_be_bytes());
+            buffer.append(&question.class.to_be_bytes());
+        }
+        for resource in self.resources() {
+            names.write(buffer, &resource.name);
+            buffer.append(&resource.rtype.to_be_bytes());
+            buffer.append(&resource.class.to_be_bytes());
+            buffer.append(&resource.ttl.to_be_bytes());
+            let length_offset = buffer.len();
+            buffer.append(&[0, 0]);
+            match &resource.data {
+                // Other types keep their names whole for resolvers that don't know them
+                // (RFC 3597 section 4)
+                RData::Ptr(name) => names.write(buffer, name),
+                data => data.write(buffer),
+            }
+            let data_len = (buffer.len() - length_offset - 2) as u16;
+            buffer.data_mut()[length_offset..length_offset + 2]
+                .copy_from_slice(&data_len.to_be_bytes());
+        }
     }
-    packet.extend_from_slice(&flags.to_be_bytes());
-    packet.extend_from_slice(&[0, 0]);
-    packet.extend_from_slice(&(answers.len() as u16).to_be_bytes());
-    packet.extend_from_slice(&[0, 0]);
-    packet.extend_from_slice(&[0, 0]);
-    for answer in answers {
-        packet.extend_from_slice(answer.name.0);
-        packet.extend_from_slice(&answer.rtype.to_be_bytes());
-        packet.extend_from_slice(&answer.class.to_be_bytes());
-        packet.extend_from_slice(&answer.ttl.to_be_bytes());
-        packet.extend_from_slice(&(answer.data.len() as u16).to_be_bytes());
-        packet.extend_from_slice(answer.data);
+
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = HEADER_LENGTH
+            + self
+                .questions
+                .iter()
+                .map(|question| question.name.encoded_len() + 4)
+                .sum::<usize>()
+            // Without compression, at most that long
+            + self
+                .resources()
+                .map(|resource| resource.name.encoded_len() + 10 + resource.data.encoded_len())
+                .sum::<usize>();
+        let mut packet = vec![0; len];
+        let mut buffer = PacketBuffer::new(&mut packet, 0);
+        self.emit(&mut buffer);
+        let len = buffer.len();
+        packet.truncate(len);
+        packet
     }
-    packet
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_parse() {
         let buffer = [
             0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 7, 108, 105, 99, 111, 114, 110, 101, 5, 108, 111,
             99, 97, 108, 0, 0, 1, 0, 1, 192, 12, 0, 28, 0, 1,
         ];
-        let result = super::parse(&buffer);
-        println!("{:?}", result);
+        let result = parse(&buffer).unwrap();
+        let questions: Vec<Question> = result.questions.iter().collect();
+        assert_eq!(questions.len(), 2);
+        assert_eq!(questions[0].qtype, 1);
+        assert!(questions[0].name.eq_ignore_ascii_case("licorne.local"));
+        // The second name is a pointer to the first
+        assert_eq!(questions[1].qtype, 28);
+        assert!(questions[1].name.eq_ignore_ascii_case("LICORNE.local."));
+        assert!(!questions[1].name.eq_ignore_ascii_case("licorne"));
+        assert_eq!(questions[1].name.to_name(), "licorne.local");
+    }
+
+    #[test]
+    fn test_compression_loop() {
+        // The question name points to itself
+        let buffer = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 192, 12, 0, 1, 0, 1];
+        assert_eq!(parse(&buffer).unwrap_err(), ParseError::Unsupported);
+    }
+
+    #[test]
+    fn test_pointer_chain() {
+        // a., then b.a. and a pointer to it
+        let mut buffer = vec![0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0];
+        buffer.extend_from_slice(&[1, b'a', 0, 0, 1, 0, 1]);
+        buffer.extend_from_slice(&[1, b'b', 192, 12, 0, 1, 0, 1]);
+        buffer.extend_from_slice(&[192, 19, 0, 1, 0, 1]);
+        let result = parse(&buffer).unwrap();
+        let question = result.questions.iter().nth(2).unwrap();
+        assert!(question.name.eq_ignore_ascii_case("b.a"));
+    }
+
+    #[test]
+    fn test_parse_rdata() {
+        let mut message = vec![0, 0, 0x84, 0, 0, 1, 0, 4, 0, 0, 0, 0];
+        write_name("_http._tcp.local", |bytes| message.extend_from_slice(bytes));
+        message.extend_from_slice(&[0, 12, 0, 1]);
+        let records: [(u16, &[u8]); 4] = [
+            (TYPE_PTR, b"\x07Licorne\xC0\x0C"),
+            // Target pointing to the `local` label of the question
+            (TYPE_SRV, b"\0\0\0\0\0\x50\x07licorne\xC0\x17"),
+            (TYPE_TXT, b"\x06path=/\x00"),
+            (TYPE_NSEC, b"\xC0\x0C\x00\x05\x40\x00\x00\x08\x40"),
+        ];
+        for (rtype, data) in records {
+            message.extend_from_slice(&[0xC0, 12]);
+            message.extend_from_slice(&rtype.to_be_bytes());
+            message.extend_from_slice(&[0, 1, 0, 0, 0x11, 0x94]);
+            message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            message.extend_from_slice(data);
+        }
+
+        let result = parse(&message).unwrap();
+        let answers: Vec<Resource> = result.answers.iter().collect();
+        let RData::Ptr(instance) = answers[0].data else {
+            panic!("not a PTR");
+        };
+        assert_eq!(instance.to_name(), "Licorne._http._tcp.local");
+        let RData::Srv { port, target, .. } = answers[1].data else {
+            panic!("not an SRV");
+        };
+        assert_eq!(port, 80);
+        assert!(target.eq_ignore_ascii_case("licorne.local"));
+        let RData::Txt(strings) = answers[2].data else {
+            panic!("not a TXT");
+        };
+        assert_eq!(strings.iter().collect::<Vec<_>>(), [&b"path=/"[..], b""]);
+        let RData::Nsec {
+            next_domain_name,
+            types,
+        } = answers[3].data
+        else {
+            panic!("not an NSEC");
+        };
+        assert!(next_domain_name.eq_ignore_ascii_case("_http._tcp.local"));
+        assert_eq!(
+            types.iter().collect::<Vec<_>>(),
+            [TYPE_A, TYPE_AAAA, TYPE_SRV]
+        );
+
+        // Names compare uncompressed
+        let mut srv = vec![0, 0, 0, 0, 0, 0x50];
+        write_name("LICORNE.local", |bytes| srv.extend_from_slice(bytes));
+        assert_eq!(answers[1].data, RData::new(TYPE_SRV, &srv).unwrap());
+        assert_eq!(
+            answers[1].data.to_bytes()[6..],
+            srv[6..].to_ascii_lowercase()
+        );
+    }
+
+    #[test]
+    fn test_bad_rdata() {
+        assert_eq!(
+            RData::new(TYPE_A, &[192, 168, 42]),
+            Err(ParseError::BadLength)
+        );
+        assert_eq!(
+            RData::new(TYPE_TXT, b"\x07path=/"),
+            Err(ParseError::Truncated)
+        );
+        // Data continuing past the name
+        assert_eq!(
+            RData::new(TYPE_PTR, b"\x05local\x00\x00"),
+            Err(ParseError::BadLength)
+        );
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        let name = |text| DomainName::from_text(text).unwrap();
+        let questions = [Question {
+            name: name("_http._tcp.local"),
+            qtype: TYPE_PTR,
+            class: 1,
+        }];
+        let resource = |name, data| Resource {
+            name,
+            rtype: 0,
+            class: 1,
+            ttl: 120,
+            data,
+        };
+        let answers = [Resource {
+            rtype: TYPE_PTR,
+            ..resource(
+                name("_HTTP._tcp.local."),
+                RData::Ptr(name("Licorne._http._tcp.local")),
+            )
+        }];
+        let additional = [
+            Resource {
+                rtype: TYPE_SRV,
+                ..resource(
+                    name("Licorne._http._tcp.local"),
+                    RData::Srv {
+                        priority: 0,
+                        weight: 0,
+                        port: 80,
+                        target: name("licorne.local"),
+                    },
+                )
+            },
+            Resource {
+                rtype: TYPE_A,
+                ..resource(
+                    name("licorne.local"),
+                    RData::A(Ipv4Addr::new(192, 168, 42, 1)),
+                )
+            },
+        ];
+        let message = Message {
+            header: Header {
+                id: 0x1234,
+                query: false,
+                opcode: 2,
+                authoritative_answer: true,
+                truncation: true,
+                recursion_desired: true,
+                recursion_available: true,
+                rcode: 3,
+            },
+            questions: &questions,
+            answers: &answers,
+            authority: &[],
+            additional: &additional,
+        };
+        let bytes = message.to_bytes();
+        // The answer name is a pointer to the question one, the PTR data adds one label
+        // to it
+        assert_eq!(bytes[34..36], [0xC0, 12]);
+        assert_eq!(bytes[46..56], *b"\x07Licorne\xC0\x0C");
+
+        let parsed = parse(&bytes).unwrap();
+        let header = parsed.header;
+        assert_eq!((header.id, header.query, header.opcode), (0x1234, false, 2));
+        assert!(header.authoritative_answer && header.truncation);
+        assert!(header.recursion_desired && header.recursion_available);
+        assert_eq!(header.rcode, 3);
+        assert_eq!(parsed.questions.len(), 1);
+        let answer = parsed.answers.iter().next().unwrap();
+        assert_eq!(answer.data, answers[0].data);
+        let written: Vec<Resource> = parsed.additional.iter().collect();
+        assert_eq!(written[0].data, additional[0].data);
+        assert!(written[1].name.eq_ignore_ascii_case("licorne.local"));
+        assert_eq!(written[1].data, additional[1].data);
+    }
+
+    #[test]
+    fn test_from_text() {
+        assert_eq!(
+            DomainName::from_text("licorne.local.")
+                .unwrap()
+                .encoded_len(),
+            15
+        );
+        let label = "a".repeat(64);
+        assert_eq!(
+            DomainName::from_text(&label).unwrap_err(),
+            ParseError::BadLength
+        );
+        let name = ["a"; 128].join(".");
+        assert_eq!(
+            DomainName::from_text(&name).unwrap_err(),
+            ParseError::BadLength
+        );
     }
 }
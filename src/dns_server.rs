@@ -0,0 +1,571 @@
+use super::dns::{self, TYPE_A, TYPE_AAAA, TYPE_ANY, TYPE_PTR, TYPE_SOA, TYPE_SRV, TYPE_TXT};
+use super::error::ParseError;
+use super::interface::{Datagram, UdpReply, UdpService};
+use super::mdns::HostName;
+use super::time::Instant;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const PORT: u16 = 53;
+
+// Longest response to a client without EDNS (RFC 1035 section 4.2.1)
+const MAX_UDP_MESSAGE_LENGTH: usize = 512;
+
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+// Negative answers are cached for this long, the TTL and minimum of the SOA records
+// (RFC 2308 section 5)
+const NEGATIVE_TTL: u32 = 60;
+// Timers of the SOA records, only secondary servers use them and there are none
+const REFRESH: u32 = 3600;
+const RETRY: u32 = 600;
+const EXPIRE: u32 = 86400;
+
+// Response codes (RFC 1035 section 4.1.1)
+const RCODE_FORMAT_ERROR: u8 = 1;
+const RCODE_NAME_ERROR: u8 = 3;
+const RCODE_NOT_IMPLEMENTED: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+
+pub enum Data {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Txt(Vec<String>),
+}
+
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: Data,
+}
+
+/// The device, named after the host name shared with the other name services so that it
+/// follows renames.
+pub struct Host {
+    pub name: HostName,
+    pub addresses: Vec<IpAddr>,
+    pub ttl: u32,
+}
+
+pub struct Config {
+    /// Domains the server is authoritative for, e.g. `home.arpa` and the reverse zone of the
+    /// link's addresses. Each gets an SOA record.
+    pub zones: Vec<String>,
+    /// Address records of the device in the first zone, with PTR records in the reverse
+    /// zones holding its addresses.
+    pub host: Option<Host>,
+    /// Records of the zones, the others are ignored.
+    pub records: Vec<Record>,
+}
+
+// Record with its data written out, it is parsed again to be written in a response
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    rtype: u16,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+impl Entry {
+    /// Panics if a name of `record` is too long.
+    fn new(record: &Record) -> Self {
+        let (rtype, data) = match &record.data {
+            Data::A(address) => (TYPE_A, dns::RData::A(*address).to_bytes()),
+            Data::Aaaa(address) => (TYPE_AAAA, dns::RData::Aaaa(*address).to_bytes()),
+            Data::Ptr(target) => (TYPE_PTR, dns::RData::Ptr(domain_name(target)).to_bytes()),
+            Data::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                let data = dns::RData::Srv {
+                    priority: *priority,
+                    weight: *weight,
+                    port: *port,
+                    target: domain_name(target),
+                };
+                (TYPE_SRV, data.to_bytes())
+            }
+            Data::Txt(strings) => {
+                let mut data = vec![];
+                for string in strings {
+                    let string = &string.as_bytes()[..string.len().min(u8::MAX as usize)];
+                    data.push(string.len() as u8);
+                    data.extend_from_slice(string);
+                }
+                (TYPE_TXT, data)
+            }
+        };
+        Entry {
+            name: record.name.clone(),
+            rtype,
+            ttl: record.ttl,
+            data,
+        }
+    }
+
+    fn resource(&self) -> dns::Resource<'_> {
+        dns::Resource {
+            // Checked by `Server::new`
+            name: dns::DomainName::from_text(&self.name).unwrap(),
+            rtype: self.rtype,
+            class: CLASS_IN,
+            ttl: self.ttl,
+            data: dns::RData::new(self.rtype, &self.data).unwrap(),
+        }
+    }
+}
+
+/// Authoritative DNS server for the zones of its configuration, answering over UDP.
+pub struct Server {
+    zones: Vec<String>,
+    host: Option<Host>,
+    /// Entries of the configured records and the SOA records.
+    entries: Vec<Entry>,
+}
+
+// Name of the configuration, panics when it can't be written
+fn domain_name(name: &str) -> dns::DomainName<'_> {
+    dns::DomainName::from_text(name).unwrap_or_else(|_| panic!("bad name {:?}", name))
+}
+
+impl Server {
+    /// Panics if a name of `config` is too long.
+    pub fn new(config: Config) -> Self {
+        let mut server = Server {
+            zones: config.zones,
+            host: config.host,
+            entries: vec![],
+        };
+        for zone in &server.zones {
+            server.entries.push(Entry {
+                name: zone.clone(),
+                rtype: TYPE_SOA,
+                ttl: NEGATIVE_TTL,
+                data: soa_data(zone),
+            });
+        }
+        for record in config.records {
+            if server.is_in_zones(&domain_name(&record.name)) {
+                server.entries.push(Entry::new(&record));
+            }
+        }
+        assert!(
+            server.host.is_none() || server.host_name().is_some(),
+            "bad host name"
+        );
+        server
+    }
+
+    fn is_in_zones(&self, name: &dns::DomainName) -> bool {
+        self.zones.iter().any(|zone| is_subdomain(name, zone))
+    }
+
+    // Name of the host in the first zone, nothing when a rename made it too long
+    fn host_name(&self) -> Option<String> {
+        let (Some(host), Some(zone)) = (&self.host, self.zones.first()) else {
+            return None;
+        };
+        let name = format!("{}.{}", host.name.get(), zone);
+        dns::DomainName::from_text(&name).is_ok().then_some(name)
+    }
+
+    // Entries of the host under its current name
+    fn host_entries(&self) -> Vec<Entry> {
+        let (Some(host), Some(name)) = (&self.host, self.host_name()) else {
+            return vec![];
+        };
+        let mut entries = vec![];
+        for address in &host.addresses {
+            let data = match address {
+                IpAddr::V4(address) => Data::A(*address),
+                IpAddr::V6(address) => Data::Aaaa(*address),
+            };
+            entries.push(Entry::new(&Record {
+                name: name.clone(),
+                ttl: host.ttl,
+                data,
+            }));
+            let pointer = Record {
+                name: reverse_name(address),
+                ttl: host.ttl,
+                data: Data::Ptr(name.clone()),
+            };
+            if self.is_in_zones(&domain_name(&pointer.name)) {
+                entries.push(Entry::new(&pointer));
+            }
+        }
+        entries
+    }
+
+    /// Response to `query`, or nothing when it isn't one.
+    fn respond(&self, query: &dns::ParsedDns) -> Option<Vec<u8>> {
+        if !query.header.query {
+            return None;
+        }
+        let questions: Vec<dns::Question> = query.questions.iter().collect();
+        let mut header = dns::Header {
+            id: query.header.id,
+            query: false,
+            opcode: query.header.opcode,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: query.header.recursion_desired,
+            recursion_available: false,
+            rcode: 0,
+        };
+        let error = |mut header: dns::Header, rcode| {
+            header.rcode = rcode;
+            dns::Message {
+                header,
+                questions: &questions,
+                answers: &[],
+                authority: &[],
+                additional: &[],
+            }
+            .to_bytes()
+        };
+
+        if query.header.opcode != 0 {
+            return Some(error(header, RCODE_NOT_IMPLEMENTED));
+        }
+        let [question] = &questions[..] else {
+            return Some(error(header, RCODE_FORMAT_ERROR));
+        };
+        let class = question.class;
+        if (class != CLASS_IN && class != CLASS_ANY) || !self.is_in_zones(&question.name) {
+            return Some(error(header, RCODE_REFUSED));
+        }
+        header.authoritative_answer = true;
+        // Negative answers carry the SOA of the zone, it tells how long to cache them
+        // (RFC 2308 section 3)
+        let mut entries = self.host_entries();
+        entries.extend_from_slice(&self.entries);
+        // The zone holding the name is the innermost one
+        let soa: Vec<dns::Resource> = entries
+            .iter()
+            .filter(|entry| entry.rtype == TYPE_SOA && is_subdomain(&question.name, &entry.name))
+            .max_by_key(|entry| entry.name.len())
+            .map(Entry::resource)
+            .into_iter()
+            .collect();
+        // A name exists when it or a name below it has records, zones have their SOA
+        let exists = entries
+            .iter()
+            .any(|entry| is_subdomain(&domain_name(&entry.name), question.name.to_name().as_str()));
+        if !exists {
+            header.rcode = RCODE_NAME_ERROR;
+            let response = dns::Message {
+                header,
+                questions: &questions,
+                answers: &[],
+                authority: &soa,
+                additional: &[],
+            };
+            return Some(response.to_bytes());
+        }
+
+        let answers: Vec<dns::Resource> = entries
+            .iter()
+            .filter(|entry| question.qtype == entry.rtype || question.qtype == TYPE_ANY)
+            .map(Entry::resource)
+            .filter(|resource| resource.name == question.name)
+            .collect();
+        // Addresses of the targets save the client a query (RFC 2782)
+        let additional: Vec<dns::Resource> = answers
+            .iter()
+            .filter_map(|answer| match answer.data {
+                dns::RData::Srv { target, .. } => Some(target),
+                _ => None,
+            })
+            .flat_map(|target| {
+                entries
+                    .iter()
+                    .filter(|entry| entry.rtype == TYPE_A || entry.rtype == TYPE_AAAA)
+                    .map(Entry::resource)
+                    .filter(move |resource| resource.name == target)
+            })
+            .collect();
+
+        let response = dns::Message {
+            header,
+            questions: &questions,
+            answers: &answers,
+            authority: if answers.is_empty() { &soa } else { &[] },
+            additional: &additional,
+        }
+        .to_bytes();
+        if response.len() <= MAX_UDP_MESSAGE_LENGTH {
+            return Some(response);
+        }
+        // The client may retry over TCP, which isn't served
+        let header = dns::Header {
+            truncation: true,
+            ..header
+        };
+        Some(error(header, 0))
+    }
+}
+
+// SOA data of `zone` with made-up names, the server isn't named and has no contact
+fn soa_data(zone: &str) -> Vec<u8> {
+    let mut data = vec![];
+    for name in [zone.to_owned(), format!("hostmaster.{}", zone)] {
+        // Checked like the other names of the configuration
+        domain_name(&name);
+        dns::write_name(&name, |bytes| data.extend_from_slice(bytes));
+    }
+    for value in [1, REFRESH, RETRY, EXPIRE, NEGATIVE_TTL] {
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+    data
+}
+
+// Name of the PTR record of `address` (RFC 1035 section 3.5, RFC 3596 section 2.5)
+fn reverse_name(address: &IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => {
+            let [a, b, c, d] = address.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(address) => {
+            let mut name = String::new();
+            for byte in address.octets().iter().rev() {
+                name += &format!("{:x}.{:x}.", byte & 0xF, byte >> 4);
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+// Whether `name` is `domain` or below it, ignoring ASCII case
+fn is_subdomain(name: &dns::DomainName, domain: &str) -> bool {
+    let labels: Vec<&str> = name.parts().collect();
+    let domain: Vec<&str> = domain
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .collect();
+    labels.len() >= domain.len()
+        && labels[labels.len() - domain.len()..]
+            .iter()
+            .zip(domain)
+            .all(|(label, domain_label)| label.eq_ignore_ascii_case(domain_label))
+}
+
+impl UdpService for Server {
+    fn port(&self) -> u16 {
+        PORT
+    }
+
+    fn handle(&mut self, datagram: &Datagram, _now: Instant) -> Result<Vec<UdpReply>, ParseError> {
+        if datagram.destination_address.is_multicast() {
+            return Ok(vec![]);
+        }
+        let query = dns::parse(datagram.payload)?;
+        log!(debug, "dns {:?}", query);
+
+        Ok(self
+            .respond(&query)
+            .map(|payload| UdpReply {
+                destination_mac: datagram.source_mac,
+                source_address: datagram.local_address,
+                destination_address: datagram.source_address,
+                source_port: PORT,
+                destination_port: datagram.source_port,
+                payload,
+            })
+            .into_iter()
+            .collect())
+    }
+
+    fn address_changed(&mut self, old: IpAddr, new: IpAddr, _now: Instant) {
+        let Some(host) = &mut self.host else {
+            return;
+        };
+        for address in &mut host.addresses {
+            if *address == old {
+                *address = new;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(hostname: &HostName) -> Server {
+        Server::new(Config {
+            zones: vec!["home.arpa".to_owned(), "42.168.192.in-addr.arpa".to_owned()],
+            host: Some(Host {
+                name: hostname.clone(),
+                addresses: vec!["192.168.42.1".parse().unwrap(), "fd42::1".parse().unwrap()],
+                ttl: 120,
+            }),
+            records: vec![
+                Record {
+                    name: "_http._tcp.licorne.home.arpa".to_owned(),
+                    ttl: 120,
+                    data: Data::Srv {
+                        priority: 0,
+                        weight: 0,
+                        port: 80,
+                        target: "licorne.home.arpa".to_owned(),
+                    },
+                },
+                Record {
+                    name: "_http._tcp.licorne.home.arpa".to_owned(),
+                    ttl: 120,
+                    data: Data::Txt(vec!["path=/".to_owned()]),
+                },
+            ],
+        })
+    }
+
+    fn respond(server: &Server, name: &str, qtype: u16) -> Vec<u8> {
+        let questions = [dns::Question {
+            name: dns::DomainName::from_text(name).unwrap(),
+            qtype,
+            class: CLASS_IN,
+        }];
+        let query = dns::Message {
+            header: dns::Header {
+                id: 0x4242,
+                query: true,
+                opcode: 0,
+                authoritative_answer: false,
+                truncation: false,
+                recursion_desired: true,
+                recursion_available: false,
+                rcode: 0,
+            },
+            questions: &questions,
+            answers: &[],
+            authority: &[],
+            additional: &[],
+        }
+        .to_bytes();
+        server.respond(&dns::parse(&query).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_answers() {
+        let hostname = HostName::new("licorne");
+        let server = server(&hostname);
+        let response = respond(&server, "LICORNE.home.arpa", TYPE_A);
+        let response = dns::parse(&response).unwrap();
+        assert_eq!(response.header.id, 0x4242);
+        assert!(response.header.authoritative_answer && response.header.recursion_desired);
+        assert_eq!(response.header.rcode, 0);
+        let answer = response.answers.iter().next().unwrap();
+        assert_eq!(answer.data, dns::RData::A(Ipv4Addr::new(192, 168, 42, 1)));
+
+        let response = respond(&server, "1.42.168.192.in-addr.arpa", TYPE_PTR);
+        let response = dns::parse(&response).unwrap();
+        let answer = response.answers.iter().next().unwrap();
+        let dns::RData::Ptr(target) = answer.data else {
+            panic!("not a PTR");
+        };
+        assert!(target.eq_ignore_ascii_case("licorne.home.arpa"));
+
+        // The target address comes along with the SRV record
+        let response = respond(&server, "_http._tcp.licorne.home.arpa", TYPE_SRV);
+        let response = dns::parse(&response).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.additional.iter().next().unwrap().rtype, TYPE_A);
+
+        let response = respond(&server, "_http._tcp.licorne.home.arpa", TYPE_ANY);
+        assert_eq!(dns::parse(&response).unwrap().answers.len(), 2);
+
+        // The host records follow renames by the mDNS responder
+        hostname.set("licorne-2".to_owned());
+        let response = respond(&server, "licorne-2.home.arpa", TYPE_AAAA);
+        let response = dns::parse(&response).unwrap();
+        let answer = response.answers.iter().next().unwrap();
+        assert_eq!(answer.data, dns::RData::Aaaa("fd42::1".parse().unwrap()));
+        let response = respond(&server, "licorne.home.arpa", TYPE_A);
+        assert!(dns::parse(&response).unwrap().answers.is_empty());
+        let response = respond(&server, "1.42.168.192.in-addr.arpa", TYPE_PTR);
+        let response = dns::parse(&response).unwrap();
+        let answer = response.answers.iter().next().unwrap();
+        assert_eq!(
+            answer.data,
+            dns::RData::Ptr(domain_name("licorne-2.home.arpa"))
+        );
+    }
+
+    #[test]
+    fn test_long_host_name() {
+        let hostname = HostName::new("licorne");
+        let zone = [
+            "b".repeat(62),
+            "c".repeat(62),
+            "d".repeat(62),
+            "arpa".to_owned(),
+        ]
+        .join(".");
+        let server = Server::new(Config {
+            zones: vec![zone.clone()],
+            host: Some(Host {
+                name: hostname.clone(),
+                addresses: vec!["192.168.42.1".parse().unwrap()],
+                ttl: 120,
+            }),
+            records: vec![],
+        });
+        let response = respond(&server, &format!("licorne.{}", zone), TYPE_A);
+        assert_eq!(dns::parse(&response).unwrap().answers.len(), 1);
+
+        // Renamed past the longest name, the host has no records anymore
+        hostname.set("a".repeat(dns::MAX_LABEL_LENGTH));
+        let response = respond(&server, &format!("licorne.{}", zone), TYPE_A);
+        assert!(dns::parse(&response).unwrap().answers.is_empty());
+    }
+
+    #[test]
+    fn test_errors() {
+        let server = server(&HostName::new("licorne"));
+        let rcode = |name: &str, qtype: u16| {
+            let response = respond(&server, name, qtype);
+            let response = dns::parse(&response).unwrap();
+            (response.header.rcode, response.answers.len())
+        };
+        assert_eq!(rcode("other.home.arpa", TYPE_A), (RCODE_NAME_ERROR, 0));
+        assert_eq!(rcode("example.com", TYPE_A), (RCODE_REFUSED, 0));
+        // Existing names without records of the type, including ones with records below
+        assert_eq!(rcode("licorne.home.arpa", TYPE_TXT), (0, 0));
+        assert_eq!(rcode("_tcp.licorne.home.arpa", TYPE_A), (0, 0));
+        assert_eq!(rcode("home.arpa", TYPE_A), (0, 0));
+        assert_eq!(rcode("home.arpa", TYPE_SOA), (0, 1));
+
+        // Negative answers have the SOA of their zone
+        for (name, zone) in [
+            ("other.home.arpa", "home.arpa"),
+            ("licorne.home.arpa", "home.arpa"),
+            ("2.42.168.192.in-addr.arpa", "42.168.192.in-addr.arpa"),
+        ] {
+            let response = respond(&server, name, TYPE_TXT);
+            let response = dns::parse(&response).unwrap();
+            let authority: Vec<dns::Resource> = response.authority.iter().collect();
+            assert_eq!(authority.len(), 1);
+            assert_eq!(
+                (authority[0].rtype, authority[0].ttl),
+                (TYPE_SOA, NEGATIVE_TTL)
+            );
+            assert!(authority[0].name.eq_ignore_ascii_case(zone));
+            let dns::RData::Other(data) = authority[0].data else {
+                panic!("not an SOA");
+            };
+            assert_eq!(data[data.len() - 4..], NEGATIVE_TTL.to_be_bytes());
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use core::fmt;
+
+/// Reason a received packet was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The buffer ends before the fields it must contain.
+    Truncated,
+    /// A length field disagrees with the buffer or the format.
+    BadLength,
+    BadChecksum,
+    /// Valid but not handled, e.g. an unknown hardware type or message type.
+    Unsupported,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParseError::Truncated => "truncated",
+            ParseError::BadLength => "bad length",
+            ParseError::BadChecksum => "bad checksum",
+            ParseError::Unsupported => "unsupported",
+        })
+    }
+}
+
+/// Fail with `Truncated` unless `buffer` holds at least `len` bytes.
+pub fn check_len(buffer: &[u8], len: usize) -> Result<(), ParseError> {
+    if buffer.len() < len {
+        return Err(ParseError::Truncated);
+    }
+    Ok(())
+}
@@ -1,10 +1,30 @@
+use super::buffer::PacketBuffer;
+use super::error::{check_len, ParseError};
+
 #[derive(Debug, PartialEq)]
 pub enum EtherType {
     Ipv4,
+    Arp,
     Ipv6,
     Unknown(u16),
 }
 
+impl EtherType {
+    fn value(&self) -> u16 {
+        match self {
+            EtherType::Ipv4 => 0x0800,
+            EtherType::Arp => 0x0806,
+            EtherType::Ipv6 => 0x86DD,
+            EtherType::Unknown(v) => *v,
+        }
+    }
+}
+
+pub const HEADER_LENGTH: usize = 14;
+pub const CRC_LENGTH: usize = 4;
+// Largest payload of a frame without jumbo frames
+pub const MAX_PAYLOAD_LENGTH: usize = 1500;
+
 // Layer 2 Ethernet Frame
 #[derive(Debug)]
 pub struct EthernetFrame<'a> {
@@ -16,35 +36,62 @@ pub struct EthernetFrame<'a> {
 }
 
 impl EthernetFrame<'_> {
-    pub fn parse(buffer: &[u8]) -> EthernetFrame<'_> {
+    pub fn parse(buffer: &[u8]) -> Result<EthernetFrame<'_>, ParseError> {
+        check_len(buffer, HEADER_LENGTH + CRC_LENGTH)?;
         let destination_mac = (&buffer[0..6]).try_into().unwrap();
         let source_mac = (&buffer[6..12]).try_into().unwrap();
         let ether_type = u16::from_be_bytes([buffer[12], buffer[13]]);
         let ether_type = match ether_type {
             0x0800 => EtherType::Ipv4,
+            0x0806 => EtherType::Arp,
             0x86DD => EtherType::Ipv6,
             v => EtherType::Unknown(v),
         };
         let payload = &buffer[14..(buffer.len() - 4)];
         let crc = &buffer[(buffer.len() - 4)..];
         let crc = u32::from_be_bytes(crc.try_into().unwrap());
-        EthernetFrame {
+        Ok(EthernetFrame {
             destination_mac,
             source_mac,
             ether_type,
             payload,
             crc,
-        }
+        })
     }
 
+    /// Wrap the data of `buffer`, which is the payload in place of `self.payload`, with the
+    /// header and CRC.
+    pub fn emit(&self, buffer: &mut PacketBuffer) {
+        let header = buffer.prepend(HEADER_LENGTH);
+        header[0..6].copy_from_slice(&self.destination_mac);
+        header[6..12].copy_from_slice(&self.source_mac);
+        header[12..14].copy_from_slice(&self.ether_type.value().to_be_bytes());
+        buffer.append(&self.crc.to_be_bytes());
+    }
+
+    #[cfg(feature = "std")]
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut packet = vec![];
-        packet.extend_from_slice(&self.destination_mac);
-        packet.extend_from_slice(&self.source_mac);
-        assert!(self.ether_type == EtherType::Ipv6);
-        packet.extend_from_slice(&(0x86DD as u16).to_be_bytes());
-        packet.extend_from_slice(self.payload);
-        packet.extend_from_slice(&self.crc.to_be_bytes());
+        let mut packet = vec![0; HEADER_LENGTH + self.payload.len() + CRC_LENGTH];
+        let mut buffer = PacketBuffer::new(&mut packet, HEADER_LENGTH);
+        buffer.append(self.payload);
+        self.emit(&mut buffer);
         packet
     }
 }
+
+pub const BROADCAST_MAC: [u8; 6] = [0xFF; 6];
+
+// IPv6 minimum link MTU, the largest payload we send in a single frame
+pub const MTU: usize = 1280;
+
+/// Multicast MAC address an IPv6 multicast group is mapped to (RFC 2464 section 7).
+pub fn ipv6_multicast_mac(address: &core::net::Ipv6Addr) -> [u8; 6] {
+    let octets = address.octets();
+    [0x33, 0x33, octets[12], octets[13], octets[14], octets[15]]
+}
+
+/// Multicast MAC address an IPv4 multicast group is mapped to (RFC 1112 section 6.4).
+pub fn ipv4_multicast_mac(address: &core::net::Ipv4Addr) -> [u8; 6] {
+    let octets = address.octets();
+    [0x01, 0x00, 0x5E, octets[1] & 0x7F, octets[2], octets[3]]
+}
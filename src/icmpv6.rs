@@ -1,43 +1,470 @@
+use super::buffer::PacketBuffer;
+use super::dns;
+use super::error::{check_len, ParseError};
 use super::ipv6::checksum;
-use std::net::Ipv6Addr;
+use super::time::{Duration, Instant};
+use core::net::Ipv6Addr;
 
+pub const DESTINATION_UNREACHABLE_NO_ROUTE: u8 = 0;
+pub const DESTINATION_UNREACHABLE_ADDRESS: u8 = 3;
+pub const DESTINATION_UNREACHABLE_PORT: u8 = 4;
+
+pub const TIME_EXCEEDED_FRAGMENT_REASSEMBLY: u8 = 1;
+
+pub const PARAMETER_PROBLEM_ERRONEOUS_HEADER: u8 = 0;
+pub const PARAMETER_PROBLEM_UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+pub const PARAMETER_PROBLEM_UNRECOGNIZED_OPTION: u8 = 2;
+
+// Multicast Address Record types (RFC 3810 section 5.2.12)
+pub const MODE_IS_INCLUDE: u8 = 1;
+pub const MODE_IS_EXCLUDE: u8 = 2;
+pub const CHANGE_TO_INCLUDE_MODE: u8 = 3;
+pub const CHANGE_TO_EXCLUDE_MODE: u8 = 4;
+
+// Error messages must fit in the minimum MTU with their IPv6 and ICMPv6 headers
+const MAX_INVOKING_PACKET_LEN: usize = 1280 - 40 - 8;
+
+// Capacities of the lists in parsed messages, further entries are ignored
+pub const MAX_OPTIONS: usize = 8;
+pub const MAX_DNS_SERVERS: usize = 4;
+pub const MAX_SEARCH_DOMAINS: usize = 4;
+pub const MAX_SOURCES: usize = 8;
+pub const MAX_RECORDS: usize = 16;
+
+const OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+const OPTION_TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+const OPTION_MTU: u8 = 5;
+const OPTION_RECURSIVE_DNS_SERVER: u8 = 25;
+const OPTION_DNS_SEARCH_LIST: u8 = 31;
+
+// Neighbor Discovery options (RFC 4861 section 4.6, RFC 8106)
+// Without a heap the lists are stored inline, making the list variants the largest
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum NdpOption {
+    SourceLinkLayerAddress([u8; 6]),
+    TargetLinkLayerAddress([u8; 6]),
+    PrefixInformation {
+        prefix_length: u8,
+        on_link: bool,
+        autonomous: bool,
+        valid_lifetime: u32,
+        preferred_lifetime: u32,
+        prefix: Ipv6Addr,
+    },
+    Mtu(u32),
+    RecursiveDnsServer {
+        lifetime: u32,
+        servers: heapless::Vec<Ipv6Addr, MAX_DNS_SERVERS>,
+    },
+    DnsSearchList {
+        lifetime: u32,
+        domains: heapless::Vec<dns::Name, MAX_SEARCH_DOMAINS>,
+    },
+    Unknown(u8),
+}
+
+impl NdpOption {
+    fn parse_all(mut buffer: &[u8]) -> Result<heapless::Vec<NdpOption, MAX_OPTIONS>, ParseError> {
+        let mut options = heapless::Vec::new();
+        while buffer.len() >= 2 {
+            let len = buffer[1] as usize * 8;
+            // A zero length option would loop forever, the packet must be discarded
+            if len == 0 {
+                return Err(ParseError::BadLength);
+            }
+            check_len(buffer, len)?;
+            let option = &buffer[..len];
+            let min_len = match option[0] {
+                OPTION_PREFIX_INFORMATION => 32,
+                _ => 8,
+            };
+            if len < min_len
+                || (option[0] == OPTION_RECURSIVE_DNS_SERVER && !(len - 8).is_multiple_of(16))
+            {
+                return Err(ParseError::BadLength);
+            }
+            let u32_at = |i: usize| {
+                u32::from_be_bytes([option[i], option[i + 1], option[i + 2], option[i + 3]])
+            };
+            let address_at = |i: usize| {
+                let address: [u8; 16] = option[i..i + 16].try_into().unwrap();
+                Ipv6Addr::from(address)
+            };
+            let _ = options.push(match option[0] {
+                OPTION_SOURCE_LINK_LAYER_ADDRESS => {
+                    NdpOption::SourceLinkLayerAddress(option[2..8].try_into().unwrap())
+                }
+                OPTION_TARGET_LINK_LAYER_ADDRESS => {
+                    NdpOption::TargetLinkLayerAddress(option[2..8].try_into().unwrap())
+                }
+                OPTION_PREFIX_INFORMATION => NdpOption::PrefixInformation {
+                    prefix_length: option[2],
+                    on_link: option[3] & 0x80 != 0,
+                    autonomous: option[3] & 0x40 != 0,
+                    valid_lifetime: u32_at(4),
+                    preferred_lifetime: u32_at(8),
+                    prefix: address_at(16),
+                },
+                OPTION_MTU => NdpOption::Mtu(u32_at(4)),
+                OPTION_RECURSIVE_DNS_SERVER => NdpOption::RecursiveDnsServer {
+                    lifetime: u32_at(4),
+                    servers: (8..len)
+                        .step_by(16)
+                        .map(address_at)
+                        .take(MAX_DNS_SERVERS)
+                        .collect(),
+                },
+                OPTION_DNS_SEARCH_LIST => NdpOption::DnsSearchList {
+                    lifetime: u32_at(4),
+                    domains: dns::read_names(&option[8..])
+                        .take(MAX_SEARCH_DOMAINS)
+                        .collect(),
+                },
+                v => NdpOption::Unknown(v),
+            });
+            buffer = &buffer[len..];
+        }
+        Ok(options)
+    }
+
+    /// Unknown options are dropped, their contents aren't kept.
+    fn write(&self, buffer: &mut PacketBuffer) {
+        let start = buffer.len();
+        match self {
+            NdpOption::SourceLinkLayerAddress(address) => {
+                buffer.append(&[OPTION_SOURCE_LINK_LAYER_ADDRESS, 0]);
+                buffer.append(address);
+            }
+            NdpOption::TargetLinkLayerAddress(address) => {
+                buffer.append(&[OPTION_TARGET_LINK_LAYER_ADDRESS, 0]);
+                buffer.append(address);
+            }
+            NdpOption::PrefixInformation {
+                prefix_length,
+                on_link,
+                autonomous,
+                valid_lifetime,
+                preferred_lifetime,
+                prefix,
+            } => {
+                let flags = if *on_link { 0x80 } else { 0 } | if *autonomous { 0x40 } else { 0 };
+                buffer.append(&[OPTION_PREFIX_INFORMATION, 0, *prefix_length, flags]);
+                buffer.append(&valid_lifetime.to_be_bytes());
+                buffer.append(&preferred_lifetime.to_be_bytes());
+                buffer.append(&[0, 0, 0, 0]);
+                buffer.append(&prefix.octets());
+            }
+            NdpOption::Mtu(mtu) => {
+                buffer.append(&[OPTION_MTU, 0, 0, 0]);
+                buffer.append(&mtu.to_be_bytes());
+            }
+            NdpOption::RecursiveDnsServer { lifetime, servers } => {
+                buffer.append(&[OPTION_RECURSIVE_DNS_SERVER, 0, 0, 0]);
+                buffer.append(&lifetime.to_be_bytes());
+                for server in servers {
+                    buffer.append(&server.octets());
+                }
+            }
+            NdpOption::DnsSearchList { lifetime, domains } => {
+                buffer.append(&[OPTION_DNS_SEARCH_LIST, 0, 0, 0]);
+                buffer.append(&lifetime.to_be_bytes());
+                for domain in domains {
+                    dns::write_name(domain, |bytes| buffer.append(bytes));
+                }
+            }
+            NdpOption::Unknown(_) => return,
+        }
+        // Options are padded to a multiple of 8 octets and their length is in units of 8 octets
+        let len = (buffer.len() - start).div_ceil(8) * 8;
+        buffer.append_zeroed(start + len - buffer.len());
+        buffer.data_mut()[start + 1] = (len / 8) as u8;
+    }
+
+    fn len(&self) -> usize {
+        let len = match self {
+            NdpOption::SourceLinkLayerAddress(_) | NdpOption::TargetLinkLayerAddress(_) => 8,
+            NdpOption::PrefixInformation { .. } => 32,
+            NdpOption::Mtu(_) => 8,
+            NdpOption::RecursiveDnsServer { servers, .. } => 8 + servers.len() * 16,
+            NdpOption::DnsSearchList { domains, .. } => {
+                let mut len = 8;
+                for domain in domains {
+                    dns::write_name(domain, |bytes| len += bytes.len());
+                }
+                len
+            }
+            NdpOption::Unknown(_) => 0,
+        };
+        len.div_ceil(8) * 8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MulticastAddressRecord {
+    pub record_type: u8,
+    pub multicast_address: Ipv6Addr,
+    pub sources: heapless::Vec<Ipv6Addr, MAX_SOURCES>,
+}
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
-pub enum Icmpv6 {
+pub enum Icmpv6<'a> {
+    DestinationUnreachable {
+        code: u8,
+        invoking_packet: &'a [u8],
+    },
+    PacketTooBig {
+        mtu: u32,
+        invoking_packet: &'a [u8],
+    },
+    TimeExceeded {
+        code: u8,
+        invoking_packet: &'a [u8],
+    },
+    ParameterProblem {
+        code: u8,
+        pointer: u32,
+        invoking_packet: &'a [u8],
+    },
     NeighborSolicitation {
         target_address: Ipv6Addr,
+        source_link_layer_address: Option<[u8; 6]>,
     },
     NeighborAdvertisement {
         router: bool,
         solicited: bool,
         override_: bool,
         target_address: Ipv6Addr,
-        link_layer_address: [u8; 6],
+        link_layer_address: Option<[u8; 6]>,
+    },
+    RouterSolicitation {
+        source_link_layer_address: Option<[u8; 6]>,
+    },
+    RouterAdvertisement {
+        hop_limit: u8,
+        managed: bool,
+        other: bool,
+        router_lifetime: u16,
+        reachable_time: u32,
+        retransmit_timer: u32,
+        options: heapless::Vec<NdpOption, MAX_OPTIONS>,
+    },
+    /// MLDv1 queries are parsed as MLDv2 queries without sources
+    MulticastListenerQuery {
+        maximum_response_code: u16,
+        multicast_address: Ipv6Addr,
+        sources: heapless::Vec<Ipv6Addr, MAX_SOURCES>,
+    },
+    MulticastListenerReport {
+        records: heapless::Vec<MulticastAddressRecord, MAX_RECORDS>,
+    },
+    EchoRequest {
+        identifier: u16,
+        sequence_number: u16,
+        data: &'a [u8],
+    },
+    EchoReply {
+        identifier: u16,
+        sequence_number: u16,
+        data: &'a [u8],
+    },
+    /// Messages of unknown types are ignored rather than errors (RFC 4443 section 2.4).
+    Unknown {
+        message_type: u8,
+        code: u8,
+        body: &'a [u8],
     },
-    RouterSolicitation,
-    Echo,
 }
 
-impl Icmpv6 {
-    pub fn parse(packet: &[u8]) -> Self {
+impl Icmpv6<'_> {
+    /// Packet that triggered an error message.
+    pub fn invoking_packet(&self) -> Option<&[u8]> {
+        match self {
+            Icmpv6::DestinationUnreachable {
+                invoking_packet, ..
+            }
+            | Icmpv6::PacketTooBig {
+                invoking_packet, ..
+            }
+            | Icmpv6::TimeExceeded {
+                invoking_packet, ..
+            }
+            | Icmpv6::ParameterProblem {
+                invoking_packet, ..
+            } => Some(invoking_packet),
+            _ => None,
+        }
+    }
+
+    pub fn parse(packet: &[u8]) -> Result<Icmpv6<'_>, ParseError> {
+        check_len(packet, 8)?;
         let packet_type = packet[0];
         let code = packet[1];
-        let checksum = u16::from_be_bytes([packet[2], packet[3]]);
+        let _checksum = u16::from_be_bytes([packet[2], packet[3]]);
         let body = &packet[4..];
+        let min_body_len = match packet_type {
+            133 | 134 => 12,
+            135 | 136 | 130 => 20,
+            _ => 4,
+        };
+        check_len(body, min_body_len)?;
 
-        match packet_type {
+        Ok(match packet_type {
+            1 => Icmpv6::DestinationUnreachable {
+                code,
+                invoking_packet: &body[4..],
+            },
+            2 => Icmpv6::PacketTooBig {
+                mtu: u32::from_be_bytes([body[0], body[1], body[2], body[3]]),
+                invoking_packet: &body[4..],
+            },
+            3 => Icmpv6::TimeExceeded {
+                code,
+                invoking_packet: &body[4..],
+            },
+            4 => Icmpv6::ParameterProblem {
+                code,
+                pointer: u32::from_be_bytes([body[0], body[1], body[2], body[3]]),
+                invoking_packet: &body[4..],
+            },
             135 => {
                 let target_address: [u8; 16] = (&body[4..20]).try_into().unwrap();
                 let target_address = Ipv6Addr::from(target_address);
 
-                Icmpv6::NeighborSolicitation { target_address }
+                Icmpv6::NeighborSolicitation {
+                    target_address,
+                    source_link_layer_address: source_link_layer_address(&body[20..])?,
+                }
             }
-            133 => Icmpv6::RouterSolicitation,
-            128 => Icmpv6::Echo,
-            v => todo!("{}", v),
-        }
+            136 => {
+                let target_address: [u8; 16] = (&body[4..20]).try_into().unwrap();
+                let link_layer_address = NdpOption::parse_all(&body[20..])?.into_iter().find_map(
+                    |option| match option {
+                        NdpOption::TargetLinkLayerAddress(address) => Some(address),
+                        _ => None,
+                    },
+                );
+
+                Icmpv6::NeighborAdvertisement {
+                    router: body[0] & 0b10000000 != 0,
+                    solicited: body[0] & 0b01000000 != 0,
+                    override_: body[0] & 0b00100000 != 0,
+                    target_address: Ipv6Addr::from(target_address),
+                    link_layer_address,
+                }
+            }
+            133 => Icmpv6::RouterSolicitation {
+                source_link_layer_address: source_link_layer_address(&body[4..])?,
+            },
+            134 => Icmpv6::RouterAdvertisement {
+                hop_limit: body[0],
+                managed: body[1] & 0x80 != 0,
+                other: body[1] & 0x40 != 0,
+                router_lifetime: u16::from_be_bytes([body[2], body[3]]),
+                reachable_time: u32::from_be_bytes([body[4], body[5], body[6], body[7]]),
+                retransmit_timer: u32::from_be_bytes([body[8], body[9], body[10], body[11]]),
+                options: NdpOption::parse_all(&body[12..])?,
+            },
+            128 => Icmpv6::EchoRequest {
+                identifier: u16::from_be_bytes([body[0], body[1]]),
+                sequence_number: u16::from_be_bytes([body[2], body[3]]),
+                data: &body[4..],
+            },
+            130 => {
+                let address_at = |i: usize| {
+                    let address: [u8; 16] = body[i..i + 16].try_into().unwrap();
+                    Ipv6Addr::from(address)
+                };
+                let sources = if body.len() >= 24 {
+                    let count = u16::from_be_bytes([body[22], body[23]]) as usize;
+                    check_len(body, 24 + count * 16)?;
+                    (0..count)
+                        .map(|i| address_at(24 + i * 16))
+                        .take(MAX_SOURCES)
+                        .collect()
+                } else {
+                    heapless::Vec::new()
+                };
+                Icmpv6::MulticastListenerQuery {
+                    maximum_response_code: u16::from_be_bytes([body[0], body[1]]),
+                    multicast_address: address_at(4),
+                    sources,
+                }
+            }
+            143 => {
+                let count = u16::from_be_bytes([body[2], body[3]]) as usize;
+                let mut records = heapless::Vec::new();
+                let mut buffer = &body[4..];
+                for _ in 0..count {
+                    check_len(buffer, 20)?;
+                    let aux_len = buffer[1] as usize * 4;
+                    let sources_count = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+                    check_len(buffer, 20 + sources_count * 16 + aux_len)?;
+                    let address_at = |i: usize| {
+                        let address: [u8; 16] = buffer[i..i + 16].try_into().unwrap();
+                        Ipv6Addr::from(address)
+                    };
+                    let _ = records.push(MulticastAddressRecord {
+                        record_type: buffer[0],
+                        multicast_address: address_at(4),
+                        sources: (0..sources_count)
+                            .map(|i| address_at(20 + i * 16))
+                            .take(MAX_SOURCES)
+                            .collect(),
+                    });
+                    buffer = &buffer[20 + sources_count * 16 + aux_len..];
+                }
+                Icmpv6::MulticastListenerReport { records }
+            }
+            129 => Icmpv6::EchoReply {
+                identifier: u16::from_be_bytes([body[0], body[1]]),
+                sequence_number: u16::from_be_bytes([body[2], body[3]]),
+                data: &body[4..],
+            },
+            message_type => Icmpv6::Unknown {
+                message_type,
+                code,
+                body,
+            },
+        })
     }
-    pub fn to_bytes(&self, src_addr: &Ipv6Addr, dst_addr: &Ipv6Addr) -> Vec<u8> {
+    /// Append the message to `buffer`.
+    pub fn emit(&self, buffer: &mut PacketBuffer, src_addr: &Ipv6Addr, dst_addr: &Ipv6Addr) {
+        let start = buffer.len();
         match self {
+            Icmpv6::DestinationUnreachable {
+                code,
+                invoking_packet,
+            } => {
+                buffer.append(&[1, *code, 0, 0, 0, 0, 0, 0]);
+                buffer.append(quote(invoking_packet));
+            }
+            Icmpv6::PacketTooBig {
+                mtu,
+                invoking_packet,
+            } => {
+                buffer.append(&[2, 0, 0, 0]);
+                buffer.append(&mtu.to_be_bytes());
+                buffer.append(quote(invoking_packet));
+            }
+            Icmpv6::TimeExceeded {
+                code,
+                invoking_packet,
+            } => {
+                buffer.append(&[3, *code, 0, 0, 0, 0, 0, 0]);
+                buffer.append(quote(invoking_packet));
+            }
+            Icmpv6::ParameterProblem {
+                code,
+                pointer,
+                invoking_packet,
+            } => {
+                buffer.append(&[4, *code, 0, 0]);
+                buffer.append(&pointer.to_be_bytes());
+                buffer.append(quote(invoking_packet));
+            }
             Icmpv6::NeighborAdvertisement {
                 router,
                 solicited,
@@ -49,21 +476,467 @@ impl Icmpv6 {
                     | if *solicited { 0b01000000 } else { 0 }
                     | if *override_ { 0b00100000 } else { 0 };
 
-                let mut packet = vec![136, 0, 0, 0];
+                buffer.append(&[136, 0, 0, 0]);
+
+                buffer.append(&[flags, 0, 0, 0]);
+                buffer.append(&target_address.octets());
+                if let Some(link_layer_address) = link_layer_address {
+                    NdpOption::TargetLinkLayerAddress(*link_layer_address).write(buffer);
+                }
+            }
+            Icmpv6::NeighborSolicitation {
+                target_address,
+                source_link_layer_address,
+            } => {
+                buffer.append(&[135, 0, 0, 0, 0, 0, 0, 0]);
+                buffer.append(&target_address.octets());
+                if let Some(link_layer_address) = source_link_layer_address {
+                    NdpOption::SourceLinkLayerAddress(*link_layer_address).write(buffer);
+                }
+            }
+            Icmpv6::RouterSolicitation {
+                source_link_layer_address,
+            } => {
+                buffer.append(&[133, 0, 0, 0, 0, 0, 0, 0]);
+                if let Some(link_layer_address) = source_link_layer_address {
+                    NdpOption::SourceLinkLayerAddress(*link_layer_address).write(buffer);
+                }
+            }
+            Icmpv6::RouterAdvertisement {
+                hop_limit,
+                managed,
+                other,
+                router_lifetime,
+                reachable_time,
+                retransmit_timer,
+                options,
+            } => {
+                let flags =
+                    if *managed { 0b10000000 } else { 0 } | if *other { 0b01000000 } else { 0 };
+
+                buffer.append(&[134, 0, 0, 0, *hop_limit, flags]);
+                buffer.append(&router_lifetime.to_be_bytes());
+                buffer.append(&reachable_time.to_be_bytes());
+                buffer.append(&retransmit_timer.to_be_bytes());
+                for option in options {
+                    option.write(buffer);
+                }
+            }
+            Icmpv6::MulticastListenerQuery {
+                maximum_response_code,
+                multicast_address,
+                sources,
+            } => {
+                buffer.append(&[130, 0, 0, 0]);
+                buffer.append(&maximum_response_code.to_be_bytes());
+                buffer.append(&[0, 0]);
+                buffer.append(&multicast_address.octets());
+                buffer.append(&[0, 0]);
+                buffer.append(&(sources.len() as u16).to_be_bytes());
+                for source in sources {
+                    buffer.append(&source.octets());
+                }
+            }
+            Icmpv6::MulticastListenerReport { records } => {
+                buffer.append(&[143, 0, 0, 0, 0, 0]);
+                buffer.append(&(records.len() as u16).to_be_bytes());
+                for record in records {
+                    buffer.append(&[record.record_type, 0]);
+                    buffer.append(&(record.sources.len() as u16).to_be_bytes());
+                    buffer.append(&record.multicast_address.octets());
+                    for source in &record.sources {
+                        buffer.append(&source.octets());
+                    }
+                }
+            }
+            Icmpv6::EchoRequest {
+                identifier,
+                sequence_number,
+                data,
+            }
+            | Icmpv6::EchoReply {
+                identifier,
+                sequence_number,
+                data,
+            } => {
+                let packet_type = if let Icmpv6::EchoRequest { .. } = self {
+                    128
+                } else {
+                    129
+                };
+                buffer.append(&[packet_type, 0, 0, 0]);
+                buffer.append(&identifier.to_be_bytes());
+                buffer.append(&sequence_number.to_be_bytes());
+                buffer.append(data);
+            }
+            Icmpv6::Unknown {
+                message_type,
+                code,
+                body,
+            } => {
+                buffer.append(&[*message_type, *code, 0, 0]);
+                buffer.append(body);
+            }
+        }
 
-                packet.extend_from_slice(&[flags, 0, 0, 0]);
-                packet.extend_from_slice(&target_address.octets());
-                packet.extend_from_slice(&[2, 1]);
-                packet.extend_from_slice(link_layer_address);
+        let message = &mut buffer.data_mut()[start..];
+        let crc = !checksum::combine(&[
+            checksum::pseudo_header(src_addr, dst_addr, 58, message.len() as u32),
+            checksum::data(message),
+        ]);
+        message[2..4].copy_from_slice(&crc.to_be_bytes());
+    }
 
-                let crc = !checksum::combine(&[
-                    checksum::pseudo_header(src_addr, dst_addr, 58, packet.len() as u32),
-                    checksum::data(&packet),
-                ]);
-                (&mut packet[2..4]).copy_from_slice(&crc.to_be_bytes());
-                packet
+    /// Length of the message written by `emit`.
+    pub fn buffer_len(&self) -> usize {
+        match self {
+            Icmpv6::DestinationUnreachable {
+                invoking_packet, ..
             }
-            v => todo!("{:?}", v),
+            | Icmpv6::PacketTooBig {
+                invoking_packet, ..
+            }
+            | Icmpv6::TimeExceeded {
+                invoking_packet, ..
+            }
+            | Icmpv6::ParameterProblem {
+                invoking_packet, ..
+            } => 8 + quote(invoking_packet).len(),
+            Icmpv6::NeighborAdvertisement {
+                link_layer_address, ..
+            } => 24 + if link_layer_address.is_some() { 8 } else { 0 },
+            Icmpv6::NeighborSolicitation {
+                source_link_layer_address,
+                ..
+            } => {
+                24 + if source_link_layer_address.is_some() {
+                    8
+                } else {
+                    0
+                }
+            }
+            Icmpv6::RouterSolicitation {
+                source_link_layer_address,
+            } => {
+                8 + if source_link_layer_address.is_some() {
+                    8
+                } else {
+                    0
+                }
+            }
+            Icmpv6::RouterAdvertisement { options, .. } => {
+                16 + options.iter().map(NdpOption::len).sum::<usize>()
+            }
+            Icmpv6::MulticastListenerQuery { sources, .. } => 28 + sources.len() * 16,
+            Icmpv6::MulticastListenerReport { records } => {
+                8 + records
+                    .iter()
+                    .map(|record| 20 + record.sources.len() * 16)
+                    .sum::<usize>()
+            }
+            Icmpv6::EchoRequest { data, .. } | Icmpv6::EchoReply { data, .. } => 8 + data.len(),
+            Icmpv6::Unknown { body, .. } => 4 + body.len(),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self, src_addr: &Ipv6Addr, dst_addr: &Ipv6Addr) -> Vec<u8> {
+        let mut packet = vec![0; self.buffer_len()];
+        let mut buffer = PacketBuffer::new(&mut packet, 0);
+        self.emit(&mut buffer, src_addr, dst_addr);
+        packet
+    }
+}
+
+fn source_link_layer_address(options: &[u8]) -> Result<Option<[u8; 6]>, ParseError> {
+    let options = NdpOption::parse_all(options)?;
+    Ok(options.into_iter().find_map(|option| match option {
+        NdpOption::SourceLinkLayerAddress(address) => Some(address),
+        _ => None,
+    }))
+}
+
+fn quote(invoking_packet: &[u8]) -> &[u8] {
+    &invoking_packet[..core::cmp::min(invoking_packet.len(), MAX_INVOKING_PACKET_LEN)]
+}
+
+/// Whether an error message may be sent in response to a packet (RFC 4443 section 2.4 (e)).
+///
+/// Errors are never sent about errors, to packets from a source that doesn't identify a
+/// single node, and, except for Packet Too Big and unrecognized options, to multicast.
+pub fn may_reply_with_error(
+    error: &Icmpv6,
+    invoking_source: &Ipv6Addr,
+    invoking_destination: &Ipv6Addr,
+    invoking_icmpv6_type: Option<u8>,
+) -> bool {
+    if invoking_icmpv6_type.is_some_and(|packet_type| packet_type < 128) {
+        return false;
+    }
+    if invoking_source.is_unspecified() || invoking_source.is_multicast() {
+        return false;
+    }
+    if invoking_destination.is_multicast() {
+        return matches!(
+            error,
+            Icmpv6::PacketTooBig { .. }
+                | Icmpv6::ParameterProblem {
+                    code: PARAMETER_PROBLEM_UNRECOGNIZED_OPTION,
+                    ..
+                }
+        );
+    }
+    true
+}
+
+/// Token bucket limiting the rate of error messages (RFC 4443 section 2.4 (f)).
+pub struct RateLimiter {
+    capacity: u32,
+    interval: Duration,
+    tokens: u32,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Allows bursts of `capacity` messages, refilled by one every `interval`.
+    pub fn new(capacity: u32, interval: Duration, now: Instant) -> Self {
+        RateLimiter {
+            capacity,
+            interval,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let refill = (now.saturating_duration_since(self.last_refill).as_nanos()
+            / self.interval.as_nanos()) as u32;
+        if refill > 0 {
+            self.tokens = core::cmp::min(self.capacity, self.tokens.saturating_add(refill));
+            self.last_refill += self.interval * refill;
+        }
+
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo_round_trip() {
+        let source: Ipv6Addr = "fe80::1".parse().unwrap();
+        let destination: Ipv6Addr = "fe80::4242".parse().unwrap();
+        let packet = Icmpv6::EchoRequest {
+            identifier: 0x1234,
+            sequence_number: 7,
+            data: b"abcdefgh",
+        }
+        .to_bytes(&source, &destination);
+
+        // A valid checksum sums to zero over the pseudo header and the packet
+        assert_eq!(
+            !checksum::combine(&[
+                checksum::pseudo_header(&source, &destination, 58, packet.len() as u32),
+                checksum::data(&packet),
+            ]),
+            0
+        );
+        match Icmpv6::parse(&packet).unwrap() {
+            Icmpv6::EchoRequest {
+                identifier: 0x1234,
+                sequence_number: 7,
+                data: b"abcdefgh",
+            } => {}
+            v => panic!("{:?}", v),
         }
     }
+
+    #[test]
+    fn test_neighbor_solicitation_round_trip() {
+        let source: Ipv6Addr = "fe80::1".parse().unwrap();
+        let target: Ipv6Addr = "fe80::4242".parse().unwrap();
+        let packet = Icmpv6::NeighborSolicitation {
+            target_address: target,
+            source_link_layer_address: Some([2, 0, 0, 0, 0, 1]),
+        }
+        .to_bytes(&source, &"ff02::1:ff00:4242".parse().unwrap());
+
+        match Icmpv6::parse(&packet).unwrap() {
+            Icmpv6::NeighborSolicitation {
+                target_address,
+                source_link_layer_address: Some([2, 0, 0, 0, 0, 1]),
+            } => assert_eq!(target_address, target),
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_multicast_listener_report_round_trip() {
+        let records: heapless::Vec<_, MAX_RECORDS> = heapless::Vec::from_slice(&[
+            MulticastAddressRecord {
+                record_type: CHANGE_TO_EXCLUDE_MODE,
+                multicast_address: "ff02::fb".parse().unwrap(),
+                sources: heapless::Vec::new(),
+            },
+            MulticastAddressRecord {
+                record_type: MODE_IS_INCLUDE,
+                multicast_address: "ff02::1:ff00:1".parse().unwrap(),
+                sources: heapless::Vec::from_slice(&["fe80::1".parse().unwrap()]).unwrap(),
+            },
+        ])
+        .unwrap();
+        let packet = Icmpv6::MulticastListenerReport {
+            records: records.clone(),
+        }
+        .to_bytes(&"fe80::4242".parse().unwrap(), &"ff02::16".parse().unwrap());
+
+        match Icmpv6::parse(&packet).unwrap() {
+            Icmpv6::MulticastListenerReport { records: parsed } => assert_eq!(parsed, records),
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_error_quotes_at_most_minimum_mtu() {
+        let source: Ipv6Addr = "fe80::4242".parse().unwrap();
+        let invoking_packet = [0xAB; 1500];
+        let packet = Icmpv6::PacketTooBig {
+            mtu: 1280,
+            invoking_packet: &invoking_packet,
+        }
+        .to_bytes(&source, &"fe80::1".parse().unwrap());
+        assert_eq!(packet.len() + 40, 1280);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(2, Duration::from_millis(100), now);
+        assert!(limiter.allow(now));
+        assert!(limiter.allow(now));
+        assert!(!limiter.allow(now));
+        assert!(limiter.allow(now + Duration::from_millis(150)));
+        assert!(!limiter.allow(now + Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn test_router_advertisement_round_trip() {
+        let source: Ipv6Addr = "fe80::4242".parse().unwrap();
+        let options: heapless::Vec<_, MAX_OPTIONS> = heapless::Vec::from_slice(&[
+            NdpOption::SourceLinkLayerAddress([42; 6]),
+            NdpOption::Mtu(1280),
+            NdpOption::PrefixInformation {
+                prefix_length: 64,
+                on_link: true,
+                autonomous: true,
+                valid_lifetime: 3600,
+                preferred_lifetime: 1800,
+                prefix: "fd42::".parse().unwrap(),
+            },
+            NdpOption::RecursiveDnsServer {
+                lifetime: 600,
+                servers: heapless::Vec::from_slice(&["fd42::1".parse().unwrap()]).unwrap(),
+            },
+            NdpOption::DnsSearchList {
+                lifetime: 600,
+                domains: heapless::Vec::from_slice(&["local".try_into().unwrap()]).unwrap(),
+            },
+        ])
+        .unwrap();
+        let packet = Icmpv6::RouterAdvertisement {
+            hop_limit: 64,
+            managed: false,
+            other: true,
+            router_lifetime: 0,
+            reachable_time: 0,
+            retransmit_timer: 0,
+            options: options.clone(),
+        }
+        .to_bytes(&source, &"ff02::1".parse().unwrap());
+
+        assert_eq!(packet.len() % 8, 0);
+        match Icmpv6::parse(&packet).unwrap() {
+            Icmpv6::RouterAdvertisement {
+                hop_limit: 64,
+                managed: false,
+                other: true,
+                options: parsed,
+                ..
+            } => assert_eq!(parsed, options),
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_unknown_options_are_dropped() {
+        let source: Ipv6Addr = "fe80::4242".parse().unwrap();
+        let options: heapless::Vec<_, MAX_OPTIONS> =
+            heapless::Vec::from_slice(&[NdpOption::Mtu(1280)]).unwrap();
+        let mut packet = Icmpv6::RouterAdvertisement {
+            hop_limit: 64,
+            managed: false,
+            other: false,
+            router_lifetime: 0,
+            reachable_time: 0,
+            retransmit_timer: 0,
+            options,
+        }
+        .to_bytes(&source, &"ff02::1".parse().unwrap());
+        // Nonce option (RFC 3971)
+        packet.extend_from_slice(&[14, 1, 1, 2, 3, 4, 5, 6]);
+
+        let Icmpv6::RouterAdvertisement { options, .. } = Icmpv6::parse(&packet).unwrap() else {
+            panic!("not a router advertisement");
+        };
+        assert_eq!(options[1], NdpOption::Unknown(14));
+        let advertisement = Icmpv6::RouterAdvertisement {
+            hop_limit: 64,
+            managed: false,
+            other: false,
+            router_lifetime: 0,
+            reachable_time: 0,
+            retransmit_timer: 0,
+            options,
+        };
+        let packet = advertisement.to_bytes(&source, &"ff02::1".parse().unwrap());
+        assert_eq!(packet.len(), 16 + 8);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let source: Ipv6Addr = "fe80::1".parse().unwrap();
+        let packet = Icmpv6::NeighborSolicitation {
+            target_address: "fe80::4242".parse().unwrap(),
+            source_link_layer_address: Some([2, 0, 0, 0, 0, 1]),
+        }
+        .to_bytes(&source, &"ff02::1:ff00:4242".parse().unwrap());
+
+        assert_eq!(
+            Icmpv6::parse(&packet[..20]).err(),
+            Some(ParseError::Truncated)
+        );
+        let mut zero_length_option = packet.clone();
+        zero_length_option[25] = 0;
+        assert_eq!(
+            Icmpv6::parse(&zero_length_option).err(),
+            Some(ParseError::BadLength)
+        );
+        let mut unknown = packet;
+        unknown[0] = 200;
+        assert!(matches!(
+            Icmpv6::parse(&unknown),
+            Ok(Icmpv6::Unknown {
+                message_type: 200,
+                code: 0,
+                ..
+            })
+        ));
+    }
 }
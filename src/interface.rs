@@ -0,0 +1,1528 @@
+use super::buffer::PacketBuffer;
+use super::error::ParseError;
+use super::socket::{SocketSet, Transmit};
+use super::time::{Duration, Instant};
+use super::{arp, cdc_eem, ethernet, icmpv6, ip, ipv4, ipv6, mld, ndp, tcp, udp};
+use core::any::Any;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use usb_device::bus::UsbBus;
+use usb_device::UsbError;
+
+const LINK_LOCAL_PREFIX: Ipv6Addr = Ipv6Addr::new(0xFE80, 0, 0, 0, 0, 0, 0, 0);
+
+// Frames are built in place in buffers of this size, on the stack
+const FRAME_HEADROOM: usize = cdc_eem::HEADER_LENGTH + ethernet::HEADER_LENGTH;
+const FRAME_BUFFER_SIZE: usize =
+    FRAME_HEADROOM + ethernet::MAX_PAYLOAD_LENGTH + ethernet::CRC_LENGTH;
+// Room for the largest headers in front of a transport payload
+const HEADROOM: usize = FRAME_HEADROOM + ipv6::HEADER_LENGTH + tcp::HEADER_LENGTH;
+// Frames sent during a poll, past that they are dropped as by a full transmit ring. There is
+// room for the fragments of the largest IPv6 packet, 1448 bytes of it per frame.
+const TRANSMIT_QUEUE_LENGTH: usize = 48;
+const MAX_FRAME_LENGTH: usize =
+    ethernet::HEADER_LENGTH + ethernet::MAX_PAYLOAD_LENGTH + ethernet::CRC_LENGTH;
+
+type Frame = heapless::Vec<u8, MAX_FRAME_LENGTH>;
+
+/// UDP datagram received for a service.
+#[derive(Debug)]
+pub struct Datagram<'a> {
+    pub source_mac: [u8; 6],
+    pub source_address: IpAddr,
+    pub destination_address: IpAddr,
+    /// Our address the datagram was received on, replies should be sent from it.
+    pub local_address: IpAddr,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub payload: &'a [u8],
+}
+
+/// UDP datagram sent by a service.
+#[derive(Debug)]
+pub struct UdpReply {
+    /// Destination of IPv4 datagrams, IPv6 ones go through Neighbor Discovery.
+    pub destination_mac: [u8; 6],
+    pub source_address: IpAddr,
+    pub destination_address: IpAddr,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Protocol answering UDP datagrams on a port, e.g. a DHCP server.
+pub trait UdpService: Any {
+    fn port(&self) -> u16;
+
+    /// Handle a datagram sent to `port`, malformed ones are dropped by returning an error.
+    fn handle(&mut self, datagram: &Datagram, now: Instant) -> Result<Vec<UdpReply>, ParseError>;
+
+    /// Datagrams sent on timers, such as announcements.
+    fn poll(&mut self, _now: Instant) -> Vec<UdpReply> {
+        vec![]
+    }
+
+    /// Time `poll` has datagrams to send by.
+    fn poll_at(&self) -> Option<Instant> {
+        None
+    }
+
+    /// The host configured the link, e.g. to announce the service.
+    fn link_up(&mut self, _now: Instant) {}
+
+    /// Duplicate Address Detection replaced `old` with `new`, services handing out the
+    /// addresses of the device switch to the new one.
+    fn address_changed(&mut self, _old: IpAddr, _new: IpAddr, _now: Instant) {}
+
+    /// Datagrams to send before the interface goes away, such as goodbyes.
+    fn shutdown(&mut self) -> Vec<UdpReply> {
+        vec![]
+    }
+}
+
+// Addresses of a received IP packet and what ICMPv6 errors about it need
+struct IpPacket<'a> {
+    source_mac: [u8; 6],
+    source_address: IpAddr,
+    destination_address: IpAddr,
+    local_address: IpAddr,
+    /// Time to live of IPv4, Neighbor Discovery checks that IPv6 packets weren't forwarded.
+    hop_limit: u8,
+    protocol: u8,
+    payload: &'a [u8],
+    /// Quoted by ICMPv6 errors, the whole packet when it was reassembled.
+    invoking_packet: &'a [u8],
+    /// Offset of the Next Header field holding `protocol` in an IPv6 packet.
+    protocol_pointer: u32,
+}
+
+/// Ethernet link to the host over CDC EEM, IPv6 packets go through Neighbor Discovery.
+///
+/// Received frames are dispatched by `poll` to the network layer, then to the services and
+/// sockets bound to their port. Everything sent is queued until the end of the poll.
+pub struct Interface {
+    mac_address: [u8; 6],
+    ipv4_address: Option<Ipv4Addr>,
+    /// Broadcast address of the IPv4 subnet.
+    ipv4_broadcast: Ipv4Addr,
+    /// Learned from received packets, there is no ARP resolution.
+    ipv4_neighbors: HashMap<Ipv4Addr, [u8; 6]>,
+    link_local_address: Ipv6Addr,
+    addresses: ndp::AddressTable,
+    multicast_groups: mld::MulticastGroups,
+    neighbor_cache: ndp::NeighborCache,
+    icmpv6_rate_limiter: icmpv6::RateLimiter,
+    reassembler: ipv6::Reassembler,
+    fragment_identification: u32,
+    router_advertiser: Option<ndp::RouterAdvertiser>,
+    udp_services: Vec<Box<dyn UdpService>>,
+    sockets: SocketSet,
+    transmit_queue: heapless::Deque<Frame, TRANSMIT_QUEUE_LENGTH>,
+    dropped_frames: usize,
+    /// Time of the current poll.
+    now: Instant,
+}
+
+impl Interface {
+    /// Start Duplicate Address Detection of the EUI-64 link-local address, `secret_key` derives
+    /// the addresses replacing duplicate ones.
+    pub fn new(mac_address: [u8; 6], secret_key: [u8; 16], now: Instant) -> Self {
+        let mut addresses =
+            ndp::AddressTable::new(ndp::eui64_interface_identifier(&mac_address), secret_key);
+        let link_local_address = addresses.add(
+            &LINK_LOCAL_PREFIX,
+            ndp::INFINITE_LIFETIME,
+            ndp::INFINITE_LIFETIME,
+            now,
+        );
+        let mut multicast_groups = mld::MulticastGroups::new();
+        multicast_groups.join(ndp::ALL_NODES_MULTICAST_ADDR, now);
+        multicast_groups.join(ndp::solicited_node_multicast_addr(&link_local_address), now);
+
+        Interface {
+            mac_address,
+            ipv4_address: None,
+            ipv4_broadcast: Ipv4Addr::BROADCAST,
+            ipv4_neighbors: HashMap::new(),
+            link_local_address,
+            addresses,
+            multicast_groups,
+            neighbor_cache: ndp::NeighborCache::new(),
+            icmpv6_rate_limiter: icmpv6::RateLimiter::new(10, Duration::from_millis(100), now),
+            reassembler: ipv6::Reassembler::new(),
+            fragment_identification: 0,
+            router_advertiser: None,
+            udp_services: vec![],
+            sockets: SocketSet::new(),
+            transmit_queue: heapless::Deque::new(),
+            dropped_frames: 0,
+            now,
+        }
+    }
+
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.mac_address
+    }
+
+    pub fn link_local_address(&self) -> Ipv6Addr {
+        self.link_local_address
+    }
+
+    pub fn ipv4_address(&self) -> Option<Ipv4Addr> {
+        self.ipv4_address
+    }
+
+    /// IPv4 and ARP are ignored until the interface has an address.
+    pub fn set_ipv4_address(&mut self, address: Ipv4Addr, netmask: Ipv4Addr) {
+        self.ipv4_address = Some(address);
+        self.ipv4_broadcast = Ipv4Addr::from(u32::from(address) | !u32::from(netmask));
+    }
+
+    /// Add a permanent address in `prefix`, which becomes usable once it is unique on the
+    /// link.
+    pub fn add_address(&mut self, prefix: &Ipv6Addr, now: Instant) -> Ipv6Addr {
+        let address =
+            self.addresses
+                .add(prefix, ndp::INFINITE_LIFETIME, ndp::INFINITE_LIFETIME, now);
+        self.update_solicited_node_groups(now);
+        address
+    }
+
+    /// Whether `address` is ours and passed Duplicate Address Detection.
+    pub fn is_assigned(&self, address: &Ipv6Addr) -> bool {
+        self.addresses.is_assigned(address)
+    }
+
+    /// State of our `address`, `Duplicate` when Duplicate Address Detection kept failing and
+    /// it can't be used.
+    pub fn address_state(&self, address: &Ipv6Addr) -> Option<ndp::AddressState> {
+        self.addresses.state(address)
+    }
+
+    pub fn join_multicast_group(&mut self, group: Ipv6Addr, now: Instant) {
+        self.multicast_groups.join(group, now);
+    }
+
+    /// Answer Router Solicitations and advertise periodically, without being a default
+    /// router.
+    pub fn enable_router_advertisements(&mut self, config: ndp::RouterConfig, now: Instant) {
+        self.router_advertiser = Some(ndp::RouterAdvertiser::new(config, now));
+    }
+
+    pub fn add_udp_service(&mut self, service: Box<dyn UdpService>) {
+        self.udp_services.push(service);
+    }
+
+    /// First service of type `T`, e.g. to add records to a responder.
+    pub fn udp_service_mut<T: UdpService>(&mut self) -> Option<&mut T> {
+        self.udp_services
+            .iter_mut()
+            .find_map(|service| (service.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    pub fn sockets(&mut self) -> &mut SocketSet {
+        &mut self.sockets
+    }
+
+    /// Tell the services that the host configured the link.
+    pub fn link_up(&mut self, now: Instant) {
+        for service in self.udp_services.iter_mut() {
+            service.link_up(now);
+        }
+    }
+
+    /// Queue the last datagrams of the services, sent by the next `poll`.
+    pub fn shutdown(&mut self) {
+        let mut services = std::mem::take(&mut self.udp_services);
+        for service in services.iter_mut() {
+            for reply in service.shutdown() {
+                self.send_udp_reply(&reply);
+            }
+        }
+        self.udp_services = services;
+    }
+
+    /// Number of malformed frames dropped so far.
+    pub fn dropped_frames(&self) -> usize {
+        self.dropped_frames
+    }
+
+    /// Run the timers, process the frames received by `device` and send the frames queued
+    /// in the meantime.
+    pub fn poll<B: UsbBus>(
+        &mut self,
+        now: Instant,
+        device: &mut cdc_eem::CdcEemClass<'_, B>,
+    ) -> Result<(), UsbError> {
+        self.now = now;
+        self.poll_timers();
+
+        let result = match device.read() {
+            Ok(read) => {
+                for packet in read.iter() {
+                    let cdc_eem::CdcEemPacket::Data { crc: _, frame } = packet;
+                    if let Err(error) = self.process_frame(frame) {
+                        self.dropped_frames += 1;
+                        log!(
+                            warn,
+                            "Dropped malformed frame ({}), {} so far",
+                            error,
+                            self.dropped_frames
+                        );
+                    }
+                }
+                Ok(())
+            }
+            Err(UsbError::WouldBlock) => Ok(()),
+            Err(error) => Err(error),
+        };
+
+        for transmit in self.sockets.dispatch(now) {
+            self.send_transmit(transmit);
+        }
+
+        while let Some(frame) = self.transmit_queue.pop_front() {
+            let mut storage = [0; FRAME_BUFFER_SIZE];
+            let mut buffer = PacketBuffer::new(&mut storage, cdc_eem::HEADER_LENGTH);
+            buffer.append(&frame);
+            device.write(&mut buffer);
+        }
+        result
+    }
+
+    /// Time a timer of the interface expires, it must be polled again by then even if no
+    /// frame is received.
+    pub fn poll_at(&self) -> Option<Instant> {
+        // Advertisements wait for the link-local address, which has its own timer
+        let router_advertiser = self
+            .router_advertiser
+            .as_ref()
+            .filter(|_| self.addresses.is_assigned(&self.link_local_address))
+            .map(ndp::RouterAdvertiser::poll_at);
+        [
+            self.multicast_groups.poll_at(),
+            self.reassembler.poll_at(),
+            self.addresses.poll_at(),
+            self.neighbor_cache.poll_at(),
+            router_advertiser,
+            self.sockets.poll_at(),
+        ]
+        .into_iter()
+        .chain(self.udp_services.iter().map(|service| service.poll_at()))
+        .flatten()
+        .min()
+    }
+
+    fn poll_timers(&mut self) {
+        let now = self.now;
+        if let Some(report) = self.multicast_groups.poll(now) {
+            self.send_multicast_listener_report(&report);
+        }
+
+        for first_fragment in self.reassembler.poll(now) {
+            self.send_icmpv6_error(
+                self.link_local_address,
+                &icmpv6::Icmpv6::TimeExceeded {
+                    code: icmpv6::TIME_EXCEEDED_FRAGMENT_REASSEMBLY,
+                    invoking_packet: &first_fragment,
+                },
+            );
+        }
+
+        for tentative_address in self.addresses.poll(now) {
+            self.send_neighbor_solicitation(Ipv6Addr::UNSPECIFIED, tentative_address, None);
+        }
+        self.update_solicited_node_groups(now);
+
+        for event in self.neighbor_cache.poll(now) {
+            match event {
+                ndp::NeighborEvent::Solicit(solicitation) => {
+                    self.send_neighbor_solicitation(
+                        self.link_local_address,
+                        solicitation.target_address,
+                        solicitation.unicast,
+                    );
+                }
+                ndp::NeighborEvent::Unreachable { address, pending } => {
+                    log!(
+                        warn,
+                        "{} unreachable, dropping {} packets",
+                        address,
+                        pending.len()
+                    );
+                }
+            }
+        }
+
+        // Advertisements can only be sent once the link-local address is unique
+        if self.addresses.is_assigned(&self.link_local_address) {
+            let advertisement = self
+                .router_advertiser
+                .as_mut()
+                .and_then(|router_advertiser| router_advertiser.poll(now));
+            if let Some(advertisement) = advertisement {
+                self.send_icmpv6(ndp::ALL_NODES_MULTICAST_ADDR, &advertisement);
+            }
+        }
+
+        let mut services = std::mem::take(&mut self.udp_services);
+        for service in services.iter_mut() {
+            for reply in service.poll(now) {
+                self.send_udp_reply(&reply);
+            }
+        }
+        self.udp_services = services;
+    }
+
+    /// Handle a frame received from the host, malformed packets are reported to the caller to
+    /// be dropped.
+    fn process_frame(&mut self, frame: &[u8]) -> Result<(), ParseError> {
+        let ethernet_frame = ethernet::EthernetFrame::parse(frame)?;
+        // Broadcast is a multicast address
+        let destination_mac = ethernet_frame.destination_mac;
+        if destination_mac != self.mac_address && destination_mac[0] & 0x01 == 0 {
+            return Ok(());
+        }
+        match ethernet_frame.ether_type {
+            ethernet::EtherType::Arp => self.process_arp(&ethernet_frame),
+            ethernet::EtherType::Ipv4 => self.process_ipv4(&ethernet_frame),
+            ethernet::EtherType::Ipv6 => self.process_ipv6(&ethernet_frame),
+            ref ether_type => {
+                log!(debug, "Unhandled {:?}", ether_type);
+                Ok(())
+            }
+        }
+    }
+
+    fn process_arp(&mut self, ethernet_frame: &ethernet::EthernetFrame) -> Result<(), ParseError> {
+        let arp = arp::Arp::parse(ethernet_frame.payload)?;
+        log!(debug, "{:?}", arp);
+        self.learn_ipv4_neighbor(arp.sender_address, arp.sender_mac);
+        let Some(ipv4_address) = self.ipv4_address else {
+            return Ok(());
+        };
+        if arp.operation == arp::Operation::Request && arp.target_address == ipv4_address {
+            let arp_payload = arp::Arp {
+                operation: arp::Operation::Reply,
+                sender_mac: self.mac_address,
+                sender_address: ipv4_address,
+                target_mac: arp.sender_mac,
+                target_address: arp.sender_address,
+            }
+            .to_bytes();
+
+            self.send_frame(arp.sender_mac, ethernet::EtherType::Arp, &arp_payload);
+        }
+        Ok(())
+    }
+
+    fn process_ipv4(&mut self, ethernet_frame: &ethernet::EthernetFrame) -> Result<(), ParseError> {
+        let ipv4 = ipv4::Ipv4::parse(ethernet_frame.payload)?;
+        let Some(ipv4_address) = self.ipv4_address else {
+            return Ok(());
+        };
+        // The host may route other destinations through us, we aren't a router
+        let destination_address = ipv4.destination_address;
+        if destination_address != ipv4_address
+            && destination_address != self.ipv4_broadcast
+            && !destination_address.is_broadcast()
+            && !destination_address.is_multicast()
+        {
+            return Ok(());
+        }
+        self.learn_ipv4_neighbor(ipv4.source_address, ethernet_frame.source_mac);
+        self.process_ip(&IpPacket {
+            source_mac: ethernet_frame.source_mac,
+            source_address: IpAddr::V4(ipv4.source_address),
+            destination_address: IpAddr::V4(ipv4.destination_address),
+            local_address: IpAddr::V4(ipv4_address),
+            hop_limit: ipv4.time_to_live,
+            protocol: ipv4.protocol,
+            payload: ipv4.payload,
+            invoking_packet: ethernet_frame.payload,
+            protocol_pointer: 0,
+        })
+    }
+
+    fn process_ipv6(&mut self, ethernet_frame: &ethernet::EthernetFrame) -> Result<(), ParseError> {
+        let mut ipv6 = ipv6::Ipv6::parse(ethernet_frame.payload)?;
+        // Tentative addresses don't receive traffic yet, and we aren't a router
+        if ipv6.destination_address.is_multicast() {
+            if !self.multicast_groups.is_member(&ipv6.destination_address) {
+                return Ok(());
+            }
+        } else if !self.addresses.is_assigned(&ipv6.destination_address) {
+            return Ok(());
+        }
+        if ethernet_frame.payload.len() > ethernet::MTU {
+            self.send_icmpv6_error(
+                self.link_local_address,
+                &icmpv6::Icmpv6::PacketTooBig {
+                    mtu: ethernet::MTU as u32,
+                    invoking_packet: ethernet_frame.payload,
+                },
+            );
+            return Ok(());
+        }
+        let Some(mut headers) = self.extension_headers(&ipv6, ethernet_frame.payload) else {
+            return Ok(());
+        };
+        let mut invoking_packet = ethernet_frame.payload;
+        let reassembled;
+        if headers.protocol == ipv6::NEXT_HEADER_FRAGMENT {
+            let result = self.reassembler.process(&ipv6, &headers, self.now);
+            reassembled = match result {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Ok(()),
+                Err(error) => {
+                    self.report_header_error(&error, ethernet_frame.payload);
+                    return Ok(());
+                }
+            };
+            ipv6 = ipv6::Ipv6::parse(&reassembled)?;
+            headers = match self.extension_headers(&ipv6, &reassembled) {
+                Some(headers) => headers,
+                None => return Ok(()),
+            };
+            invoking_packet = &reassembled;
+        }
+        if !headers.chain.is_empty() {
+            log!(debug, "Extension headers {:?}", headers.chain);
+        }
+        if headers.protocol == ipv6::NEXT_HEADER_NO_NEXT_HEADER {
+            return Ok(());
+        }
+
+        let local_address = if self.addresses.is_assigned(&ipv6.destination_address) {
+            ipv6.destination_address
+        } else {
+            self.link_local_address
+        };
+        self.process_ip(&IpPacket {
+            source_mac: ethernet_frame.source_mac,
+            source_address: IpAddr::V6(ipv6.source_address),
+            destination_address: IpAddr::V6(ipv6.destination_address),
+            local_address: IpAddr::V6(local_address),
+            hop_limit: ipv6.hop_limit,
+            protocol: headers.protocol,
+            payload: &ipv6.payload[headers.offset..],
+            invoking_packet,
+            protocol_pointer: headers.protocol_pointer(),
+        })
+    }
+
+    fn process_ip(&mut self, packet: &IpPacket) -> Result<(), ParseError> {
+        match packet.protocol {
+            ip::PROTOCOL_NUMBER_TCP => self.process_tcp(packet),
+            ip::PROTOCOL_NUMBER_UDP => self.process_udp(packet),
+            ip::PROTOCOL_NUMBER_ICMPV6 => self.process_icmpv6(packet),
+            _ => {
+                if let IpAddr::V6(local_address) = packet.local_address {
+                    self.send_icmpv6_error(
+                        local_address,
+                        &icmpv6::Icmpv6::ParameterProblem {
+                            code: icmpv6::PARAMETER_PROBLEM_UNRECOGNIZED_NEXT_HEADER,
+                            pointer: packet.protocol_pointer,
+                            invoking_packet: packet.invoking_packet,
+                        },
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn process_tcp(&mut self, packet: &IpPacket) -> Result<(), ParseError> {
+        let tcp = tcp::Tcp::parse(packet.payload)?;
+        ip::verify(
+            &packet.source_address,
+            &packet.destination_address,
+            packet.protocol,
+            packet.payload,
+        )?;
+        log!(debug, "tcp {:?}", tcp);
+
+        // TCP is unicast only
+        if packet.destination_address != packet.local_address {
+            return Ok(());
+        }
+
+        let local = SocketAddr::new(packet.local_address, tcp.destination_port);
+        let remote = SocketAddr::new(packet.source_address, tcp.source_port);
+        if let Some(reset) = self.sockets.process_tcp(local, remote, &tcp, self.now) {
+            self.send_transmit(Transmit::Tcp {
+                source_address: packet.local_address,
+                destination_address: packet.source_address,
+                header: reset,
+                payload: vec![],
+            });
+        }
+        Ok(())
+    }
+
+    fn process_udp(&mut self, packet: &IpPacket) -> Result<(), ParseError> {
+        let udp = udp::Udp::parse(packet.payload)?;
+        ip::verify(
+            &packet.source_address,
+            &packet.destination_address,
+            packet.protocol,
+            packet.payload,
+        )?;
+
+        let mut services = std::mem::take(&mut self.udp_services);
+        let service = services
+            .iter_mut()
+            .find(|service| service.port() == udp.destination_port);
+        let result = match service {
+            Some(service) => {
+                let datagram = Datagram {
+                    source_mac: packet.source_mac,
+                    source_address: packet.source_address,
+                    destination_address: packet.destination_address,
+                    local_address: packet.local_address,
+                    source_port: udp.source_port,
+                    destination_port: udp.destination_port,
+                    payload: udp.payload,
+                };
+                service.handle(&datagram, self.now).map(|replies| {
+                    for reply in replies {
+                        self.send_udp_reply(&reply);
+                    }
+                })
+            }
+            None => {
+                let source = SocketAddr::new(packet.source_address, udp.source_port);
+                let bound = self
+                    .sockets
+                    .process_udp(udp.destination_port, source, udp.payload);
+                if let (false, IpAddr::V6(local_address)) = (bound, packet.local_address) {
+                    self.send_icmpv6_error(
+                        local_address,
+                        &icmpv6::Icmpv6::DestinationUnreachable {
+                            code: icmpv6::DESTINATION_UNREACHABLE_PORT,
+                            invoking_packet: packet.invoking_packet,
+                        },
+                    );
+                }
+                Ok(())
+            }
+        };
+        self.udp_services = services;
+        result
+    }
+
+    fn process_icmpv6(&mut self, packet: &IpPacket) -> Result<(), ParseError> {
+        let (IpAddr::V6(source_address), IpAddr::V6(local_address)) =
+            (packet.source_address, packet.local_address)
+        else {
+            return Ok(());
+        };
+        let icmpv6 = icmpv6::Icmpv6::parse(packet.payload)?;
+        ip::verify(
+            &packet.source_address,
+            &packet.destination_address,
+            packet.protocol,
+            packet.payload,
+        )?;
+        log!(debug, "{:?}", icmpv6);
+        // Neighbor Discovery messages must come from the link, with a code of 0
+        // (RFC 4861 sections 6.1 and 7.1)
+        let is_neighbor_discovery = matches!(
+            icmpv6,
+            icmpv6::Icmpv6::RouterSolicitation { .. }
+                | icmpv6::Icmpv6::RouterAdvertisement { .. }
+                | icmpv6::Icmpv6::NeighborSolicitation { .. }
+                | icmpv6::Icmpv6::NeighborAdvertisement { .. }
+        );
+        if is_neighbor_discovery && (packet.hop_limit != 255 || packet.payload[1] != 0) {
+            return Ok(());
+        }
+        let reply = match icmpv6 {
+            icmpv6::Icmpv6::NeighborSolicitation {
+                target_address,
+                source_link_layer_address,
+            } if self.addresses.is_assigned(&target_address) => {
+                self.learn_neighbor(&source_address, source_link_layer_address);
+                icmpv6::Icmpv6::NeighborAdvertisement {
+                    router: false,
+                    solicited: !source_address.is_unspecified(),
+                    override_: true,
+                    target_address,
+                    link_layer_address: Some(self.mac_address),
+                }
+            }
+            icmpv6::Icmpv6::NeighborSolicitation { target_address, .. } => {
+                // Another node is performing Duplicate Address Detection
+                if source_address.is_unspecified() {
+                    self.address_conflict(&target_address);
+                }
+                return Ok(());
+            }
+            icmpv6::Icmpv6::NeighborAdvertisement {
+                solicited,
+                override_,
+                target_address,
+                link_layer_address,
+                ..
+            } => {
+                self.address_conflict(&target_address);
+                let pending = self.neighbor_cache.update_from_advertisement(
+                    &target_address,
+                    link_layer_address,
+                    solicited,
+                    override_,
+                    self.now,
+                );
+                if let Some(link_layer_address) = link_layer_address {
+                    self.flush_pending(link_layer_address, pending);
+                }
+                return Ok(());
+            }
+            icmpv6::Icmpv6::RouterSolicitation {
+                source_link_layer_address,
+            } => {
+                let Some(router_advertiser) = &self.router_advertiser else {
+                    return Ok(());
+                };
+                let advertisement = router_advertiser.advertisement();
+                self.learn_neighbor(&source_address, source_link_layer_address);
+                advertisement
+            }
+            icmpv6::Icmpv6::MulticastListenerQuery {
+                multicast_address, ..
+            } => {
+                if let Some(report) = self.multicast_groups.query(&multicast_address) {
+                    self.send_multicast_listener_report(&report);
+                }
+                return Ok(());
+            }
+            icmpv6::Icmpv6::RouterAdvertisement { options, .. } => {
+                self.addresses
+                    .process_router_advertisement(&options, self.now);
+                self.update_solicited_node_groups(self.now);
+                return Ok(());
+            }
+            icmpv6::Icmpv6::EchoRequest {
+                identifier,
+                sequence_number,
+                data,
+            } => icmpv6::Icmpv6::EchoReply {
+                identifier,
+                sequence_number,
+                data,
+            },
+            _ => return Ok(()),
+        };
+
+        // Solicitations sent before the host has an address are answered on the
+        // all-nodes group
+        let destination_address = if source_address.is_unspecified() {
+            ndp::ALL_NODES_MULTICAST_ADDR
+        } else {
+            source_address
+        };
+        self.send_ip(
+            packet.source_mac,
+            IpAddr::V6(local_address),
+            IpAddr::V6(destination_address),
+            ip::PROTOCOL_NUMBER_ICMPV6,
+            &reply.to_bytes(&local_address, &destination_address),
+        );
+        Ok(())
+    }
+
+    fn send_frame(
+        &mut self,
+        destination_mac: [u8; 6],
+        ether_type: ethernet::EtherType,
+        payload: &[u8],
+    ) {
+        let mut frame = [0; FRAME_BUFFER_SIZE];
+        let mut buffer = PacketBuffer::new(&mut frame, FRAME_HEADROOM);
+        buffer.append(payload);
+        self.send_buffer(destination_mac, ether_type, &mut buffer);
+    }
+
+    /// Queue the payload in `buffer` in a frame, headers are added in place.
+    fn send_buffer(
+        &mut self,
+        destination_mac: [u8; 6],
+        ether_type: ethernet::EtherType,
+        buffer: &mut PacketBuffer,
+    ) {
+        ethernet::EthernetFrame {
+            destination_mac,
+            source_mac: self.mac_address,
+            ether_type,
+            payload: &[],
+            crc: 0xdeadbeef,
+        }
+        .emit(buffer);
+
+        // Frames fit, their payload is at most the MTU
+        let frame = Frame::from_slice(buffer.data()).unwrap();
+        let _ = self.transmit_queue.push_back(frame);
+    }
+
+    /// IPv4 has no address resolution, packets are sent to `ipv4_destination_mac`.
+    fn send_ip(
+        &mut self,
+        ipv4_destination_mac: [u8; 6],
+        source_address: IpAddr,
+        destination_address: IpAddr,
+        protocol: u8,
+        payload: &[u8],
+    ) {
+        with_buffer(payload, |buffer| {
+            self.send_ip_buffer(
+                ipv4_destination_mac,
+                source_address,
+                destination_address,
+                protocol,
+                buffer,
+            )
+        });
+    }
+
+    /// Send the transport payload in `buffer`, headers are added in place.
+    fn send_ip_buffer(
+        &mut self,
+        ipv4_destination_mac: [u8; 6],
+        source_address: IpAddr,
+        destination_address: IpAddr,
+        protocol: u8,
+        buffer: &mut PacketBuffer,
+    ) {
+        match (source_address, destination_address) {
+            (IpAddr::V4(source_address), IpAddr::V4(destination_address)) => {
+                // IPv4 packets aren't fragmented
+                if ipv4::HEADER_LENGTH + buffer.len() > ethernet::MAX_PAYLOAD_LENGTH {
+                    log!(
+                        warn,
+                        "Dropped IPv4 packet of {} bytes to {}, larger than a frame",
+                        ipv4::HEADER_LENGTH + buffer.len(),
+                        destination_address
+                    );
+                    return;
+                }
+                ipv4::Ipv4 {
+                    type_of_service: 0,
+                    identification: 0,
+                    flags: 0,
+                    time_to_live: 255,
+                    protocol,
+                    source_address,
+                    destination_address,
+                    payload: &[],
+                }
+                .emit(buffer);
+                self.send_buffer(ipv4_destination_mac, ethernet::EtherType::Ipv4, buffer);
+            }
+            (IpAddr::V6(source_address), IpAddr::V6(destination_address)) => {
+                ipv6::Ipv6 {
+                    flags: 0x60000000,
+                    next_header: protocol,
+                    hop_limit: 255,
+                    source_address,
+                    destination_address,
+                    payload: &[],
+                }
+                .emit(buffer);
+
+                let destination_mac = if destination_address.is_multicast() {
+                    Some(ethernet::ipv6_multicast_mac(&destination_address))
+                } else {
+                    self.neighbor_cache.resolve(&destination_address, self.now)
+                };
+                match destination_mac {
+                    Some(destination_mac) if buffer.len() <= ethernet::MTU => {
+                        self.send_buffer(destination_mac, ethernet::EtherType::Ipv6, buffer)
+                    }
+                    // Queued until resolution completes or fragmented
+                    _ => self.send_ipv6_packet(&destination_address, buffer.data()),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Send a UDP datagram built in place.
+    fn send_udp_reply(&mut self, reply: &UdpReply) {
+        with_buffer(&reply.payload, |buffer| {
+            udp::Udp {
+                source_port: reply.source_port,
+                destination_port: reply.destination_port,
+                payload: &[],
+            }
+            .emit(buffer, &reply.source_address, &reply.destination_address);
+            self.send_ip_buffer(
+                reply.destination_mac,
+                reply.source_address,
+                reply.destination_address,
+                ip::PROTOCOL_NUMBER_UDP,
+                buffer,
+            );
+        });
+    }
+
+    /// Send a packet from the sockets, UDP ones from the address matching the destination.
+    fn send_transmit(&mut self, transmit: Transmit) {
+        match transmit {
+            Transmit::Tcp {
+                source_address,
+                destination_address,
+                header,
+                payload,
+            } => {
+                let destination_mac = self.ipv4_destination_mac(&destination_address);
+                with_buffer(&payload, |buffer| {
+                    header.emit(buffer, &source_address, &destination_address);
+                    self.send_ip_buffer(
+                        destination_mac,
+                        source_address,
+                        destination_address,
+                        ip::PROTOCOL_NUMBER_TCP,
+                        buffer,
+                    );
+                });
+            }
+            Transmit::Udp {
+                source_port,
+                destination,
+                payload,
+            } => {
+                let Some(source_address) = self.source_address(&destination.ip()) else {
+                    log!(warn, "No address to send to {}", destination);
+                    return;
+                };
+                self.send_udp_reply(&UdpReply {
+                    destination_mac: self.ipv4_destination_mac(&destination.ip()),
+                    source_address,
+                    destination_address: destination.ip(),
+                    source_port,
+                    destination_port: destination.port(),
+                    payload,
+                });
+            }
+        }
+    }
+
+    /// Address to send to `destination` from: link-local addresses are only used on the link.
+    fn source_address(&self, destination: &IpAddr) -> Option<IpAddr> {
+        match destination {
+            IpAddr::V4(_) => self.ipv4_address.map(IpAddr::V4),
+            IpAddr::V6(destination)
+                if destination.is_multicast() || destination.is_unicast_link_local() =>
+            {
+                Some(IpAddr::V6(self.link_local_address))
+                    .filter(|_| self.addresses.is_assigned(&self.link_local_address))
+            }
+            IpAddr::V6(_) => self
+                .addresses
+                .preferred()
+                .find(|address| !address.is_unicast_link_local())
+                .map(IpAddr::V6),
+        }
+    }
+
+    fn learn_ipv4_neighbor(&mut self, address: Ipv4Addr, mac_address: [u8; 6]) {
+        if !address.is_unspecified() && !address.is_broadcast() {
+            self.ipv4_neighbors.insert(address, mac_address);
+        }
+    }
+
+    // IPv6 destinations are resolved when the packet is sent
+    fn ipv4_destination_mac(&self, destination: &IpAddr) -> [u8; 6] {
+        match destination {
+            IpAddr::V4(address) if address.is_multicast() => ethernet::ipv4_multicast_mac(address),
+            IpAddr::V4(address) => self
+                .ipv4_neighbors
+                .get(address)
+                .copied()
+                .unwrap_or(ethernet::BROADCAST_MAC),
+            IpAddr::V6(_) => [0; 6],
+        }
+    }
+
+    /// Send an ICMPv6 message from the link-local address.
+    fn send_icmpv6(&mut self, destination_address: Ipv6Addr, message: &icmpv6::Icmpv6) {
+        let source_address = self.link_local_address;
+        self.send_ip(
+            ethernet::ipv6_multicast_mac(&destination_address),
+            IpAddr::V6(source_address),
+            IpAddr::V6(destination_address),
+            ip::PROTOCOL_NUMBER_ICMPV6,
+            &message.to_bytes(&source_address, &destination_address),
+        );
+    }
+
+    fn send_ipv6_packet(&mut self, destination_address: &Ipv6Addr, packet: &[u8]) {
+        if destination_address.is_multicast() {
+            let destination_mac = ethernet::ipv6_multicast_mac(destination_address);
+            self.send_ipv6_frame(destination_mac, packet);
+            return;
+        }
+
+        match self.neighbor_cache.resolve(destination_address, self.now) {
+            Some(destination_mac) => self.send_ipv6_frame(destination_mac, packet),
+            None => self
+                .neighbor_cache
+                .enqueue(destination_address, packet.to_vec()),
+        }
+    }
+
+    /// Packets are queued for address resolution whole and only fragmented when sent.
+    fn send_ipv6_frame(&mut self, destination_mac: [u8; 6], packet: &[u8]) {
+        if packet.len() <= ethernet::MTU {
+            self.send_frame(destination_mac, ethernet::EtherType::Ipv6, packet);
+            return;
+        }
+
+        let Ok(packet) = ipv6::Ipv6::parse(packet) else {
+            return;
+        };
+        self.fragment_identification = self.fragment_identification.wrapping_add(1);
+        for fragment in packet.fragment(self.fragment_identification, ethernet::MTU) {
+            self.send_frame(destination_mac, ethernet::EtherType::Ipv6, &fragment);
+        }
+    }
+
+    fn flush_pending(&mut self, destination_mac: [u8; 6], pending: Vec<Vec<u8>>) {
+        for packet in pending {
+            self.send_ipv6_frame(destination_mac, &packet);
+        }
+    }
+
+    /// Another node uses our tentative `address`, probe for its replacement instead.
+    fn address_conflict(&mut self, address: &Ipv6Addr) {
+        let replacement = self.addresses.conflict(address, self.now);
+        self.update_solicited_node_groups(self.now);
+        let Some(replacement) = replacement else {
+            return;
+        };
+        if *address == self.link_local_address {
+            self.link_local_address = replacement;
+        }
+        if let Some(router_advertiser) = &mut self.router_advertiser {
+            router_advertiser.address_changed(address, &replacement);
+        }
+        for service in &mut self.udp_services {
+            service.address_changed(IpAddr::V6(*address), IpAddr::V6(replacement), self.now);
+        }
+    }
+
+    /// Join the solicited-node groups of our addresses, and leave those of the addresses that
+    /// were replaced, expired or found duplicate with a State Change Report.
+    fn update_solicited_node_groups(&mut self, now: Instant) {
+        let groups: Vec<Ipv6Addr> = self
+            .addresses
+            .active()
+            .map(|address| ndp::solicited_node_multicast_addr(&address))
+            .collect();
+        let stale: Vec<Ipv6Addr> = self
+            .multicast_groups
+            .groups()
+            .filter(|group| ndp::is_solicited_node_multicast_addr(group) && !groups.contains(group))
+            .collect();
+        for group in stale {
+            self.multicast_groups.leave(&group, now);
+        }
+        for group in groups {
+            self.multicast_groups.join(group, now);
+        }
+    }
+
+    /// Record the source link-layer address option of a solicitation.
+    fn learn_neighbor(&mut self, address: &Ipv6Addr, link_layer_address: Option<[u8; 6]>) {
+        let Some(link_layer_address) = link_layer_address else {
+            return;
+        };
+        if address.is_unspecified() {
+            return;
+        }
+        let pending =
+            self.neighbor_cache
+                .update_from_solicitation(address, link_layer_address, self.now);
+        self.flush_pending(link_layer_address, pending);
+    }
+
+    /// Duplicate Address Detection probes are sent from the unspecified address, without a
+    /// source link-layer address option.
+    fn send_neighbor_solicitation(
+        &mut self,
+        source_address: Ipv6Addr,
+        target_address: Ipv6Addr,
+        unicast: Option<[u8; 6]>,
+    ) {
+        let destination_address = match unicast {
+            Some(_) => target_address,
+            None => ndp::solicited_node_multicast_addr(&target_address),
+        };
+        let destination_mac =
+            unicast.unwrap_or_else(|| ethernet::ipv6_multicast_mac(&destination_address));
+        let icmpv6_payload = icmpv6::Icmpv6::NeighborSolicitation {
+            target_address,
+            source_link_layer_address: if source_address.is_unspecified() {
+                None
+            } else {
+                Some(self.mac_address)
+            },
+        }
+        .to_bytes(&source_address, &destination_address);
+
+        let packet = ipv6::Ipv6 {
+            flags: 0x60000000,
+            next_header: ip::PROTOCOL_NUMBER_ICMPV6,
+            hop_limit: 255,
+            source_address,
+            destination_address,
+            payload: &icmpv6_payload,
+        }
+        .to_bytes();
+        self.send_frame(destination_mac, ethernet::EtherType::Ipv6, &packet);
+    }
+
+    /// Reports are sent from the unspecified address until the link-local address is unique
+    /// (RFC 3590).
+    fn send_multicast_listener_report(&mut self, report: &icmpv6::Icmpv6) {
+        let source_address = if self.addresses.is_assigned(&self.link_local_address) {
+            self.link_local_address
+        } else {
+            Ipv6Addr::UNSPECIFIED
+        };
+        let destination_address = mld::ALL_MLDV2_ROUTERS_MULTICAST_ADDR;
+
+        let mut payload = ipv6::router_alert_header(ip::PROTOCOL_NUMBER_ICMPV6).to_vec();
+        payload.extend_from_slice(&report.to_bytes(&source_address, &destination_address));
+        let packet = ipv6::Ipv6 {
+            flags: 0x60000000,
+            next_header: ipv6::NEXT_HEADER_HOP_BY_HOP,
+            hop_limit: 1,
+            source_address,
+            destination_address,
+            payload: &payload,
+        }
+        .to_bytes();
+        self.send_ipv6_packet(&destination_address, &packet);
+    }
+
+    /// Walk the extension headers of a received packet, reporting malformed ones.
+    fn extension_headers(
+        &mut self,
+        packet: &ipv6::Ipv6,
+        invoking_packet: &[u8],
+    ) -> Option<ipv6::Headers> {
+        let headers = packet.headers();
+        if let Err(error) = &headers {
+            self.report_header_error(error, invoking_packet);
+        }
+        headers.ok()
+    }
+
+    fn report_header_error(&mut self, error: &ipv6::HeaderError, invoking_packet: &[u8]) {
+        if let ipv6::HeaderError::ParameterProblem { code, pointer } = *error {
+            self.send_icmpv6_error(
+                self.link_local_address,
+                &icmpv6::Icmpv6::ParameterProblem {
+                    code,
+                    pointer,
+                    invoking_packet,
+                },
+            );
+        }
+    }
+
+    fn send_icmpv6_error(&mut self, source_address: Ipv6Addr, error: &icmpv6::Icmpv6) {
+        let Ok(invoking) = ipv6::Ipv6::parse(error.invoking_packet().unwrap()) else {
+            return;
+        };
+        let invoking_icmpv6_type = match invoking.headers() {
+            Ok(headers) if headers.protocol == ip::PROTOCOL_NUMBER_ICMPV6 => {
+                invoking.payload.get(headers.offset).copied()
+            }
+            _ => None,
+        };
+        if !icmpv6::may_reply_with_error(
+            error,
+            &invoking.source_address,
+            &invoking.destination_address,
+            invoking_icmpv6_type,
+        ) || !self.icmpv6_rate_limiter.allow(self.now)
+        {
+            return;
+        }
+
+        self.send_ip(
+            [0; 6],
+            IpAddr::V6(source_address),
+            IpAddr::V6(invoking.source_address),
+            ip::PROTOCOL_NUMBER_ICMPV6,
+            &error.to_bytes(&source_address, &invoking.source_address),
+        );
+    }
+}
+
+/// Run `f` with a buffer holding `payload` after room for every header. It is on the stack
+/// unless the packet is larger than a frame, and will be fragmented.
+fn with_buffer(payload: &[u8], f: impl FnOnce(&mut PacketBuffer)) {
+    let size = HEADROOM + payload.len() + ethernet::CRC_LENGTH;
+    let mut frame = [0; FRAME_BUFFER_SIZE];
+    let mut large_frame;
+    let storage: &mut [u8] = if size <= FRAME_BUFFER_SIZE {
+        &mut frame
+    } else {
+        large_frame = vec![0; size];
+        &mut large_frame
+    };
+    let mut buffer = PacketBuffer::new(storage, HEADROOM);
+    buffer.append(payload);
+    f(&mut buffer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl UdpService for Echo {
+        fn port(&self) -> u16 {
+            7
+        }
+
+        fn handle(
+            &mut self,
+            datagram: &Datagram,
+            _now: Instant,
+        ) -> Result<Vec<UdpReply>, ParseError> {
+            Ok(vec![UdpReply {
+                destination_mac: datagram.source_mac,
+                source_address: datagram.local_address,
+                destination_address: datagram.source_address,
+                source_port: datagram.destination_port,
+                destination_port: datagram.source_port,
+                payload: datagram.payload.to_vec(),
+            }])
+        }
+    }
+
+    const MAC_ADDRESS: [u8; 6] = [2, 0, 0, 0, 0, 1];
+    const HOST_MAC: [u8; 6] = [2, 0, 0, 0, 0, 2];
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 42, 1);
+    const HOST: Ipv4Addr = Ipv4Addr::new(192, 168, 42, 2);
+
+    fn interface() -> Interface {
+        let mut interface = Interface::new(MAC_ADDRESS, [0x42; 16], Instant::now());
+        interface.set_ipv4_address(ADDRESS, Ipv4Addr::new(255, 255, 255, 252));
+        interface.add_udp_service(Box::new(Echo));
+        interface
+    }
+
+    fn frame(ether_type: ethernet::EtherType, payload: &[u8]) -> Vec<u8> {
+        ethernet::EthernetFrame {
+            destination_mac: MAC_ADDRESS,
+            source_mac: HOST_MAC,
+            ether_type,
+            payload,
+            crc: 0,
+        }
+        .to_bytes()
+    }
+
+    fn udp_frame(destination_address: Ipv4Addr, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+        let udp = udp::Udp {
+            source_port: 1234,
+            destination_port,
+            payload,
+        }
+        .to_bytes(&IpAddr::V4(HOST), &IpAddr::V4(destination_address));
+        let ipv4 = ipv4::Ipv4 {
+            type_of_service: 0,
+            identification: 0,
+            flags: 0,
+            time_to_live: 64,
+            protocol: ip::PROTOCOL_NUMBER_UDP,
+            source_address: HOST,
+            destination_address,
+            payload: &udp,
+        }
+        .to_bytes();
+        frame(ethernet::EtherType::Ipv4, &ipv4)
+    }
+
+    #[test]
+    fn test_udp_services() {
+        let mut interface = interface();
+        assert!(interface.udp_service_mut::<Echo>().is_some());
+
+        interface
+            .process_frame(&udp_frame(ADDRESS, 7, b"ping"))
+            .unwrap();
+        let frame = interface.transmit_queue.pop_front().unwrap();
+        let ethernet_frame = ethernet::EthernetFrame::parse(&frame).unwrap();
+        assert_eq!(ethernet_frame.destination_mac, HOST_MAC);
+        let ipv4 = ipv4::Ipv4::parse(ethernet_frame.payload).unwrap();
+        let udp = udp::Udp::parse(ipv4.payload).unwrap();
+        assert_eq!((udp.source_port, udp.destination_port), (7, 1234));
+        assert_eq!(udp.payload, b"ping");
+
+        // Nothing listens on other ports, and IPv4 has no port unreachable error
+        interface
+            .process_frame(&udp_frame(ADDRESS, 8, b"ping"))
+            .unwrap();
+        assert!(interface.transmit_queue.is_empty());
+
+        // The subnet broadcast address is ours too
+        let broadcast = Ipv4Addr::new(192, 168, 42, 3);
+        interface
+            .process_frame(&udp_frame(broadcast, 7, b"ping"))
+            .unwrap();
+        assert_eq!(interface.transmit_queue.len(), 1);
+    }
+
+    #[test]
+    fn test_foreign_destinations_are_dropped() {
+        let mut interface = interface();
+        let foreign = Ipv4Addr::new(8, 8, 8, 8);
+        interface
+            .process_frame(&udp_frame(foreign, 7, b"ping"))
+            .unwrap();
+        assert!(interface.transmit_queue.is_empty());
+
+        let mut other_mac_frame = udp_frame(ADDRESS, 7, b"ping");
+        other_mac_frame[..6].copy_from_slice(&[2, 0, 0, 0, 0, 3]);
+        interface.process_frame(&other_mac_frame).unwrap();
+        assert!(interface.transmit_queue.is_empty());
+
+        // Nor is IPv6 accepted for addresses that aren't ours
+        let source_address: Ipv6Addr = "fe80::2".parse().unwrap();
+        let destination_address: Ipv6Addr = "fd42::1".parse().unwrap();
+        let payload = icmpv6::Icmpv6::EchoRequest {
+            identifier: 1,
+            sequence_number: 1,
+            data: b"ping",
+        }
+        .to_bytes(&source_address, &destination_address);
+        let ipv6 = ipv6::Ipv6 {
+            flags: 0x60000000,
+            next_header: ip::PROTOCOL_NUMBER_ICMPV6,
+            hop_limit: 64,
+            source_address,
+            destination_address,
+            payload: &payload,
+        }
+        .to_bytes();
+        interface
+            .process_frame(&frame(ethernet::EtherType::Ipv6, &ipv6))
+            .unwrap();
+        assert!(interface.transmit_queue.is_empty());
+    }
+
+    #[test]
+    fn test_oversized_ipv4_packet_is_dropped() {
+        let mut interface = interface();
+        let reply = |length| UdpReply {
+            destination_mac: HOST_MAC,
+            source_address: IpAddr::V4(ADDRESS),
+            destination_address: IpAddr::V4(HOST),
+            source_port: 7,
+            destination_port: 1234,
+            payload: vec![0; length],
+        };
+        interface.send_udp_reply(&reply(1473));
+        assert!(interface.transmit_queue.is_empty());
+        interface.send_udp_reply(&reply(1472));
+        let frame = interface.transmit_queue.pop_front().unwrap();
+        assert_eq!(
+            frame.len(),
+            ethernet::HEADER_LENGTH + ethernet::MAX_PAYLOAD_LENGTH + ethernet::CRC_LENGTH
+        );
+    }
+
+    #[test]
+    fn test_packet_too_big() {
+        let mut interface = interface();
+        let now = interface.now;
+        interface.addresses.poll(now);
+        interface.addresses.poll(now + Duration::from_secs(1));
+        let local_address = interface.link_local_address;
+        assert!(interface.is_assigned(&local_address));
+        let source_address: Ipv6Addr = "fe80::2".parse().unwrap();
+        interface
+            .neighbor_cache
+            .update_from_solicitation(&source_address, HOST_MAC, now);
+
+        let payload = [0; ethernet::MTU];
+        let ipv6 = ipv6::Ipv6 {
+            flags: 0x60000000,
+            next_header: ipv6::NEXT_HEADER_NO_NEXT_HEADER,
+            hop_limit: 64,
+            source_address,
+            destination_address: local_address,
+            payload: &payload,
+        }
+        .to_bytes();
+        interface
+            .process_frame(&frame(ethernet::EtherType::Ipv6, &ipv6))
+            .unwrap();
+
+        let frame = interface.transmit_queue.pop_front().unwrap();
+        let ethernet_frame = ethernet::EthernetFrame::parse(&frame).unwrap();
+        assert_eq!(ethernet_frame.destination_mac, HOST_MAC);
+        let reply = ipv6::Ipv6::parse(ethernet_frame.payload).unwrap();
+        assert_eq!(reply.destination_address, source_address);
+        match icmpv6::Icmpv6::parse(reply.payload).unwrap() {
+            icmpv6::Icmpv6::PacketTooBig { mtu, .. } => assert_eq!(mtu, ethernet::MTU as u32),
+            message => panic!("{:?}", message),
+        }
+    }
+
+    fn router_advertisement_frame(hop_limit: u8) -> Vec<u8> {
+        let source_address: Ipv6Addr = "fe80::2".parse().unwrap();
+        let options = heapless::Vec::from_slice(&[icmpv6::NdpOption::PrefixInformation {
+            prefix_length: 64,
+            on_link: true,
+            autonomous: true,
+            valid_lifetime: 3600,
+            preferred_lifetime: 1800,
+            prefix: "fd42::".parse().unwrap(),
+        }])
+        .unwrap();
+        let icmpv6 = icmpv6::Icmpv6::RouterAdvertisement {
+            hop_limit: 64,
+            managed: false,
+            other: false,
+            router_lifetime: 1800,
+            reachable_time: 0,
+            retransmit_timer: 0,
+            options,
+        }
+        .to_bytes(&source_address, &ndp::ALL_NODES_MULTICAST_ADDR);
+        let ipv6 = ipv6::Ipv6 {
+            flags: 0x60000000,
+            next_header: ip::PROTOCOL_NUMBER_ICMPV6,
+            hop_limit,
+            source_address,
+            destination_address: ndp::ALL_NODES_MULTICAST_ADDR,
+            payload: &icmpv6,
+        }
+        .to_bytes();
+        ethernet::EthernetFrame {
+            destination_mac: ethernet::ipv6_multicast_mac(&ndp::ALL_NODES_MULTICAST_ADDR),
+            source_mac: HOST_MAC,
+            ether_type: ethernet::EtherType::Ipv6,
+            payload: &ipv6,
+            crc: 0,
+        }
+        .to_bytes()
+    }
+
+    #[test]
+    fn test_forwarded_router_advertisement_is_ignored() {
+        let mut interface = Interface::new(MAC_ADDRESS, [0x42; 16], Instant::now());
+        let address = ndp::address_from_prefix(
+            &"fd42::".parse().unwrap(),
+            &ndp::eui64_interface_identifier(&MAC_ADDRESS),
+        );
+
+        interface
+            .process_frame(&router_advertisement_frame(64))
+            .unwrap();
+        assert_eq!(interface.addresses.state(&address), None);
+
+        interface
+            .process_frame(&router_advertisement_frame(255))
+            .unwrap();
+        assert!(interface.addresses.is_tentative(&address));
+    }
+
+    struct AddressUser(Vec<IpAddr>);
+
+    impl UdpService for AddressUser {
+        fn port(&self) -> u16 {
+            9
+        }
+
+        fn handle(&mut self, _: &Datagram, _: Instant) -> Result<Vec<UdpReply>, ParseError> {
+            Ok(vec![])
+        }
+
+        fn address_changed(&mut self, old: IpAddr, new: IpAddr, _now: Instant) {
+            self.0.retain(|address| *address != old);
+            self.0.push(new);
+        }
+    }
+
+    #[test]
+    fn test_services_follow_replaced_addresses() {
+        let now = Instant::now();
+        let mut interface = Interface::new(MAC_ADDRESS, [0x42; 16], now);
+        let address = interface.link_local_address();
+        interface.add_udp_service(Box::new(AddressUser(vec![IpAddr::V6(address)])));
+        interface.enable_router_advertisements(
+            ndp::RouterConfig {
+                mac_address: MAC_ADDRESS,
+                mtu: ethernet::MTU as u32,
+                prefix: "fd42::".parse().unwrap(),
+                prefix_length: 64,
+                dns_servers: vec![address],
+                search_domains: vec![],
+                managed: false,
+                other: false,
+                interval: Duration::from_secs(200),
+            },
+            now,
+        );
+
+        interface.address_conflict(&address);
+        let replacement = interface.link_local_address();
+        assert_ne!(replacement, address);
+        assert_eq!(
+            interface.udp_service_mut::<AddressUser>().unwrap().0,
+            vec![IpAddr::V6(replacement)]
+        );
+        let icmpv6::Icmpv6::RouterAdvertisement { options, .. } = interface
+            .router_advertiser
+            .as_ref()
+            .unwrap()
+            .advertisement()
+        else {
+            panic!("not a router advertisement");
+        };
+        assert!(options.iter().any(|option| matches!(
+            option,
+            icmpv6::NdpOption::RecursiveDnsServer { servers, .. } if servers[..] == [replacement]
+        )));
+    }
+
+    #[test]
+    fn test_replaced_address_leaves_its_solicited_node_group() {
+        let now = Instant::now();
+        let mut interface = Interface::new(MAC_ADDRESS, [0x42; 16], now);
+        let link_local = interface.link_local_address();
+        let address = interface.add_address(&"fd42::".parse().unwrap(), now);
+        let group = ndp::solicited_node_multicast_addr(&address);
+        assert_eq!(ndp::solicited_node_multicast_addr(&link_local), group);
+        interface.multicast_groups.poll(now);
+
+        // The link-local address still needs the group
+        interface.address_conflict(&address);
+        let replacement = interface.addresses.active().last().unwrap();
+        assert!(interface.multicast_groups.is_member(&group));
+        assert!(interface
+            .multicast_groups
+            .is_member(&ndp::solicited_node_multicast_addr(&replacement)));
+
+        interface.address_conflict(&link_local);
+        assert!(!interface.multicast_groups.is_member(&group));
+        let Some(icmpv6::Icmpv6::MulticastListenerReport { records }) =
+            interface.multicast_groups.poll(now)
+        else {
+            panic!("no report");
+        };
+        assert!(records
+            .iter()
+            .any(|record| record.multicast_address == group
+                && record.record_type == icmpv6::CHANGE_TO_INCLUDE_MODE));
+    }
+}
@@ -0,0 +1,74 @@
+use super::error::ParseError;
+use super::ipv6::checksum;
+use super::{ipv4, ipv6};
+use core::net::IpAddr;
+
+pub const PROTOCOL_NUMBER_TCP: u8 = 6;
+pub const PROTOCOL_NUMBER_UDP: u8 = 17;
+pub const PROTOCOL_NUMBER_ICMPV6: u8 = 58;
+
+/// Compute the pseudo header checksum matching the address family of a packet.
+pub fn pseudo_header(src_addr: &IpAddr, dst_addr: &IpAddr, protocol: u8, length: u32) -> u16 {
+    match (src_addr, dst_addr) {
+        (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => {
+            ipv4::checksum::pseudo_header(src_addr, dst_addr, protocol, length)
+        }
+        (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => {
+            ipv6::checksum::pseudo_header(src_addr, dst_addr, protocol, length)
+        }
+        _ => panic!("Mixed address families {} -> {}", src_addr, dst_addr),
+    }
+}
+
+/// Check the checksum of a received TCP, UDP or ICMPv6 `packet`: summed with the pseudo
+/// header, a valid packet gives 0xffff.
+pub fn verify(
+    src_addr: &IpAddr,
+    dst_addr: &IpAddr,
+    protocol: u8,
+    packet: &[u8],
+) -> Result<(), ParseError> {
+    // A zero UDP checksum means none was computed, which is only allowed over IPv4
+    if protocol == PROTOCOL_NUMBER_UDP && src_addr.is_ipv4() && packet.get(6..8) == Some(&[0, 0]) {
+        return Ok(());
+    }
+    let sum = checksum::combine(&[
+        pseudo_header(src_addr, dst_addr, protocol, packet.len() as u32),
+        checksum::data(packet),
+    ]);
+    if sum != 0xffff {
+        return Err(ParseError::BadChecksum);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::Udp;
+
+    #[test]
+    fn test_verify() {
+        let src_addr: IpAddr = "fe80::1".parse().unwrap();
+        let dst_addr: IpAddr = "fe80::2".parse().unwrap();
+        let mut packet = Udp {
+            source_port: 5353,
+            destination_port: 5353,
+            payload: b"hello",
+        }
+        .to_bytes(&src_addr, &dst_addr);
+        assert_eq!(verify(&src_addr, &dst_addr, 17, &packet), Ok(()));
+
+        packet[8] ^= 1;
+        assert_eq!(
+            verify(&src_addr, &dst_addr, 17, &packet),
+            Err(ParseError::BadChecksum)
+        );
+
+        // No checksum over IPv4
+        let src_addr: IpAddr = "192.168.42.2".parse().unwrap();
+        let dst_addr: IpAddr = "192.168.42.1".parse().unwrap();
+        packet[6..8].copy_from_slice(&[0, 0]);
+        assert_eq!(verify(&src_addr, &dst_addr, 17, &packet), Ok(()));
+    }
+}
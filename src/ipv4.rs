@@ -0,0 +1,110 @@
+use super::buffer::PacketBuffer;
+use super::error::{check_len, ParseError};
+use core::net::Ipv4Addr;
+
+// Without options, which we never send
+pub const HEADER_LENGTH: usize = 20;
+
+#[derive(Debug)]
+pub struct Ipv4<'a> {
+    pub type_of_service: u8,
+    pub identification: u16,
+    pub flags: u16,
+    pub time_to_live: u8,
+    pub protocol: u8,
+    pub source_address: Ipv4Addr,
+    pub destination_address: Ipv4Addr,
+    pub payload: &'a [u8],
+}
+
+impl Ipv4<'_> {
+    pub fn parse(buffer: &[u8]) -> Result<Ipv4<'_>, ParseError> {
+        check_len(buffer, HEADER_LENGTH)?;
+        if buffer[0] >> 4 != 4 {
+            return Err(ParseError::Unsupported);
+        }
+        let header_length = (buffer[0] & 0x0F) as usize * 4;
+        let type_of_service = buffer[1];
+        let total_length = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+        if header_length < HEADER_LENGTH || total_length < header_length {
+            return Err(ParseError::BadLength);
+        }
+        check_len(buffer, total_length)?;
+        if checksum::data(&buffer[..header_length]) != 0xffff {
+            return Err(ParseError::BadChecksum);
+        }
+        let identification = u16::from_be_bytes([buffer[4], buffer[5]]);
+        let flags = u16::from_be_bytes([buffer[6], buffer[7]]);
+        let time_to_live = buffer[8];
+        let protocol = buffer[9];
+        let source_address: [u8; 4] = (&buffer[12..16]).try_into().unwrap();
+        let source_address = Ipv4Addr::from(source_address);
+
+        let destination_address: [u8; 4] = (&buffer[16..20]).try_into().unwrap();
+        let destination_address = Ipv4Addr::from(destination_address);
+
+        // Short frames are padded up to the Ethernet minimum, so the payload
+        // ends where the header says and not at the end of the buffer.
+        let payload = &buffer[header_length..total_length];
+
+        Ok(Ipv4 {
+            type_of_service,
+            identification,
+            flags,
+            time_to_live,
+            protocol,
+            source_address,
+            destination_address,
+            payload,
+        })
+    }
+
+    /// Prepend the header to the data of `buffer`, which is the payload in place of
+    /// `self.payload`.
+    pub fn emit(&self, buffer: &mut PacketBuffer) {
+        let total_length = (HEADER_LENGTH + buffer.len()) as u16;
+        let header = buffer.prepend(HEADER_LENGTH);
+        header[0] = 0x45;
+        header[1] = self.type_of_service;
+        header[2..4].copy_from_slice(&total_length.to_be_bytes());
+        header[4..6].copy_from_slice(&self.identification.to_be_bytes());
+        header[6..8].copy_from_slice(&self.flags.to_be_bytes());
+        header[8] = self.time_to_live;
+        header[9] = self.protocol;
+        header[12..16].copy_from_slice(&self.source_address.octets());
+        header[16..20].copy_from_slice(&self.destination_address.octets());
+        let crc = !checksum::data(header);
+        header[10..12].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut packet = vec![0; HEADER_LENGTH + self.payload.len()];
+        let mut buffer = PacketBuffer::new(&mut packet, HEADER_LENGTH);
+        buffer.append(self.payload);
+        self.emit(&mut buffer);
+        packet
+    }
+}
+
+pub mod checksum {
+    pub use super::super::ipv6::checksum::{combine, data};
+    use core::net::Ipv4Addr;
+
+    /// Compute an IPv4 pseudo header checksum.
+    pub fn pseudo_header(
+        src_addr: &Ipv4Addr,
+        dst_addr: &Ipv4Addr,
+        protocol: u8,
+        length: u32,
+    ) -> u16 {
+        let mut proto_len = [0u8; 4];
+        proto_len[1] = protocol;
+        proto_len[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+        combine(&[
+            data(&src_addr.octets()),
+            data(&dst_addr.octets()),
+            data(&proto_len[..]),
+        ])
+    }
+}
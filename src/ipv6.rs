@@ -1,4 +1,31 @@
-use std::net::Ipv6Addr;
+use super::buffer::PacketBuffer;
+use super::error::{check_len, ParseError};
+use core::net::Ipv6Addr;
+
+pub const NEXT_HEADER_HOP_BY_HOP: u8 = 0;
+pub const NEXT_HEADER_ROUTING: u8 = 43;
+pub const NEXT_HEADER_FRAGMENT: u8 = 44;
+pub const NEXT_HEADER_NO_NEXT_HEADER: u8 = 59;
+pub const NEXT_HEADER_DESTINATION_OPTIONS: u8 = 60;
+
+pub const HEADER_LENGTH: usize = 40;
+// Longer chains are discarded
+const MAX_EXTENSION_HEADERS: usize = 8;
+
+const OPTION_PAD1: u8 = 0;
+const OPTION_PADN: u8 = 1;
+const OPTION_ROUTER_ALERT: u8 = 5;
+
+// ICMPv6 Parameter Problem codes (RFC 4443 section 3.4)
+const ERRONEOUS_HEADER: u8 = 0;
+const UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+const UNRECOGNIZED_OPTION: u8 = 2;
+
+/// Hop-by-Hop Options header with a Router Alert option for MLD (RFC 2711), padded
+/// to 8 octets with a PadN option.
+pub fn router_alert_header(next_header: u8) -> [u8; 8] {
+    [next_header, 0, 5, 2, 0, 0, 1, 0]
+}
 
 #[derive(Debug)]
 pub struct Ipv6<'a> {
@@ -11,7 +38,11 @@ pub struct Ipv6<'a> {
 }
 
 impl Ipv6<'_> {
-    pub fn parse(buffer: &[u8]) -> Ipv6<'_> {
+    pub fn parse(buffer: &[u8]) -> Result<Ipv6<'_>, ParseError> {
+        check_len(buffer, HEADER_LENGTH)?;
+        if buffer[0] >> 4 != 6 {
+            return Err(ParseError::Unsupported);
+        }
         let flags = (&buffer[0..4]).try_into().unwrap();
         let flags = u32::from_be_bytes(flags);
         let payload_length = u16::from_be_bytes([buffer[4], buffer[5]]);
@@ -23,36 +54,437 @@ impl Ipv6<'_> {
         let destination_address: [u8; 16] = (&buffer[24..40]).try_into().unwrap();
         let destination_address = Ipv6Addr::from(destination_address);
 
-        let payload = &buffer[40..];
+        // Short frames are padded up to the Ethernet minimum
+        let end = HEADER_LENGTH + payload_length as usize;
+        check_len(buffer, end)?;
+        let payload = &buffer[HEADER_LENGTH..end];
 
-        assert_eq!(payload.len(), payload_length as usize);
-
-        Ipv6 {
+        Ok(Ipv6 {
             flags,
             next_header,
             hop_limit,
             source_address,
             destination_address,
             payload,
-        }
+        })
     }
 
+    /// Prepend the header to the data of `buffer`, which is the payload in place of
+    /// `self.payload`.
+    pub fn emit(&self, buffer: &mut PacketBuffer) {
+        let payload_length = buffer.len() as u16;
+        let header = buffer.prepend(HEADER_LENGTH);
+        header[0..4].copy_from_slice(&self.flags.to_be_bytes());
+        header[4..6].copy_from_slice(&payload_length.to_be_bytes());
+        header[6] = self.next_header;
+        header[7] = self.hop_limit;
+        header[8..24].copy_from_slice(&self.source_address.octets());
+        header[24..40].copy_from_slice(&self.destination_address.octets());
+    }
+
+    #[cfg(feature = "std")]
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut packet = vec![];
-        packet.extend_from_slice(&self.flags.to_be_bytes());
-        packet.extend_from_slice(&(self.payload.len() as u16).to_be_bytes());
-        packet.push(self.next_header);
-        packet.push(self.hop_limit);
-        packet.extend_from_slice(&self.source_address.octets());
-        packet.extend_from_slice(&self.destination_address.octets());
-        packet.extend_from_slice(self.payload);
+        let mut packet = vec![0; HEADER_LENGTH + self.payload.len()];
+        let mut buffer = PacketBuffer::new(&mut packet, HEADER_LENGTH);
+        buffer.append(self.payload);
+        self.emit(&mut buffer);
         packet
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub struct ExtensionHeader {
+    pub header_type: u8,
+    /// Offset of the header in the payload.
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// Result of walking the extension header chain of a packet.
+#[derive(Debug, PartialEq)]
+pub struct Headers {
+    pub chain: heapless::Vec<ExtensionHeader, MAX_EXTENSION_HEADERS>,
+    /// Upper-layer protocol, or `NEXT_HEADER_FRAGMENT` when the payload is a fragment.
+    pub protocol: u8,
+    /// Offset of the upper-layer header in the payload.
+    pub offset: usize,
+}
+
+impl Headers {
+    /// Offset in the packet of the Next Header field that holds `protocol`.
+    pub fn protocol_pointer(&self) -> u32 {
+        match self.chain.last() {
+            Some(header) => (HEADER_LENGTH + header.offset) as u32,
+            None => 6,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    /// Drop the packet silently.
+    Discard,
+    /// Drop the packet and answer with an ICMPv6 Parameter Problem, `pointer` being an
+    /// offset in the packet.
+    ParameterProblem { code: u8, pointer: u32 },
+}
+
+impl Ipv6<'_> {
+    /// Walk the extension headers (RFC 8200 section 4) up to the upper-layer header.
+    pub fn headers(&self) -> Result<Headers, HeaderError> {
+        let mut chain = heapless::Vec::new();
+        let mut header_type = self.next_header;
+        let mut offset = 0;
+        loop {
+            match header_type {
+                NEXT_HEADER_HOP_BY_HOP | NEXT_HEADER_ROUTING | NEXT_HEADER_DESTINATION_OPTIONS => {}
+                NEXT_HEADER_FRAGMENT => {
+                    let header = self.payload.get(offset..offset + 8);
+                    let header = header.ok_or(HeaderError::Discard)?;
+                    let fragment_offset = u16::from_be_bytes([header[2], header[3]]) >> 3;
+                    let more_fragments = header[3] & 1 != 0;
+                    // Atomic fragments are processed like unfragmented packets (RFC 6946)
+                    if fragment_offset != 0 || more_fragments {
+                        return Ok(Headers {
+                            chain,
+                            protocol: header_type,
+                            offset,
+                        });
+                    }
+                }
+                _ => {
+                    return Ok(Headers {
+                        chain,
+                        protocol: header_type,
+                        offset,
+                    })
+                }
+            }
+
+            let pointer = |offset: usize| (HEADER_LENGTH + offset) as u32;
+            // Hop-by-Hop Options are only allowed right after the IPv6 header
+            if let Some(previous) = chain
+                .last()
+                .filter(|_| header_type == NEXT_HEADER_HOP_BY_HOP)
+            {
+                return Err(HeaderError::ParameterProblem {
+                    code: UNRECOGNIZED_NEXT_HEADER,
+                    pointer: pointer(previous.offset),
+                });
+            }
+
+            let length = match header_type {
+                NEXT_HEADER_FRAGMENT => 8,
+                _ => (*self.payload.get(offset + 1).ok_or(HeaderError::Discard)? as usize + 1) * 8,
+            };
+            let header = self
+                .payload
+                .get(offset..offset + length)
+                .ok_or(HeaderError::Discard)?;
+
+            match header_type {
+                NEXT_HEADER_HOP_BY_HOP | NEXT_HEADER_DESTINATION_OPTIONS => {
+                    let mut option_offset = 2;
+                    while option_offset < length {
+                        let option_type = header[option_offset];
+                        if option_type == OPTION_PAD1 {
+                            option_offset += 1;
+                            continue;
+                        }
+                        let option_length =
+                            *header.get(option_offset + 1).ok_or(HeaderError::Discard)? as usize;
+                        if !matches!(option_type, OPTION_PADN | OPTION_ROUTER_ALERT) {
+                            // The two high-order bits select the action for unknown options
+                            let problem = HeaderError::ParameterProblem {
+                                code: UNRECOGNIZED_OPTION,
+                                pointer: pointer(offset + option_offset),
+                            };
+                            match option_type >> 6 {
+                                0 => {}
+                                1 => return Err(HeaderError::Discard),
+                                2 => return Err(problem),
+                                _ if self.destination_address.is_multicast() => {
+                                    return Err(HeaderError::Discard)
+                                }
+                                _ => return Err(problem),
+                            }
+                        }
+                        option_offset += 2 + option_length;
+                    }
+                    if option_offset > length {
+                        return Err(HeaderError::Discard);
+                    }
+                }
+                NEXT_HEADER_ROUTING => {
+                    // No routing type is supported, the packet is only accepted when we are
+                    // its final destination
+                    let segments_left = header[3];
+                    if segments_left != 0 {
+                        return Err(HeaderError::ParameterProblem {
+                            code: ERRONEOUS_HEADER,
+                            pointer: pointer(offset + 2),
+                        });
+                    }
+                }
+                _ => {}
+            }
+
+            chain
+                .push(ExtensionHeader {
+                    header_type,
+                    offset,
+                    length,
+                })
+                .map_err(|_| HeaderError::Discard)?;
+            header_type = header[0];
+            offset += length;
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::fragmentation::Reassembler;
+
+// Reassembly holds whole packets of up to 64 KiB, so fragmentation is left to std builds
+#[cfg(feature = "std")]
+mod fragmentation {
+    use super::super::time::{Duration, Instant};
+    use super::*;
+
+    const FRAGMENT_HEADER_LENGTH: usize = 8;
+
+    // RFC 8200 section 4.5
+    const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+    const MAX_PACKET_LENGTH: usize = 65535;
+    // Bounds the memory held by incomplete packets
+    const MAX_REASSEMBLIES: usize = 4;
+
+    impl Ipv6<'_> {
+        /// Split the packet into fragments that fit in `mtu` (RFC 8200 section 4.5). The
+        /// Hop-by-Hop Options header is the only unfragmentable extension header we send.
+        pub fn fragment(&self, identification: u32, mtu: usize) -> Vec<Vec<u8>> {
+            let unfragmentable_length = match self.next_header {
+                NEXT_HEADER_HOP_BY_HOP => (self.payload[1] as usize + 1) * 8,
+                _ => 0,
+            };
+            let (unfragmentable, fragmentable) = self.payload.split_at(unfragmentable_length);
+            let (next_header, fragmentable_next_header) = match unfragmentable.first() {
+                Some(&next_header) => (self.next_header, next_header),
+                None => (NEXT_HEADER_FRAGMENT, self.next_header),
+            };
+            let fragment_length =
+                (mtu - HEADER_LENGTH - unfragmentable_length - FRAGMENT_HEADER_LENGTH) & !7;
+
+            fragmentable
+                .chunks(fragment_length)
+                .enumerate()
+                .map(|(index, data)| {
+                    let offset = index * fragment_length;
+                    let more_fragments = offset + data.len() < fragmentable.len();
+
+                    let mut payload = unfragmentable.to_vec();
+                    if let Some(header) = payload.first_mut() {
+                        *header = NEXT_HEADER_FRAGMENT;
+                    }
+                    payload.extend_from_slice(&[fragmentable_next_header, 0]);
+                    payload
+                        .extend_from_slice(&(offset as u16 | more_fragments as u16).to_be_bytes());
+                    payload.extend_from_slice(&identification.to_be_bytes());
+                    payload.extend_from_slice(data);
+                    Ipv6 {
+                        next_header,
+                        payload: &payload,
+                        ..*self
+                    }
+                    .to_bytes()
+                })
+                .collect()
+        }
+    }
+
+    struct Reassembly {
+        source_address: Ipv6Addr,
+        destination_address: Ipv6Addr,
+        identification: u32,
+        /// IPv6 header and unfragmentable headers of the first fragment, the Fragment header
+        /// removed.
+        headers: Option<Vec<u8>>,
+        /// Quoted in the Time Exceeded message.
+        first_fragment: Option<Vec<u8>>,
+        /// Fragment data sorted by offset.
+        fragments: Vec<(usize, Vec<u8>)>,
+        length: Option<usize>,
+        timeout: Instant,
+    }
+
+    impl Reassembly {
+        fn is_complete(&self) -> bool {
+            let mut end = 0;
+            for (offset, data) in &self.fragments {
+                if *offset != end {
+                    return false;
+                }
+                end += data.len();
+            }
+            self.headers.is_some() && self.length == Some(end)
+        }
+    }
+
+    /// Reassembly of fragmented packets keyed by source, destination and identification.
+    #[derive(Default)]
+    pub struct Reassembler {
+        reassemblies: Vec<Reassembly>,
+    }
+
+    impl Reassembler {
+        pub fn new() -> Reassembler {
+            Reassembler {
+                reassemblies: vec![],
+            }
+        }
+
+        /// Add a fragment, `headers` being the walk of its chain up to the Fragment header.
+        /// Returns the reassembled packet once every fragment has been received.
+        pub fn process(
+            &mut self,
+            packet: &Ipv6,
+            headers: &Headers,
+            now: Instant,
+        ) -> Result<Option<Vec<u8>>, HeaderError> {
+            let header = &packet.payload[headers.offset..];
+            let fragment_offset = (u16::from_be_bytes([header[2], header[3]]) & !7) as usize;
+            let more_fragments = header[3] & 1 != 0;
+            let identification = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            let data = &header[FRAGMENT_HEADER_LENGTH..];
+            let end = fragment_offset + data.len();
+
+            if more_fragments && !data.len().is_multiple_of(8) {
+                // Points at the Payload Length field
+                return Err(HeaderError::ParameterProblem {
+                    code: ERRONEOUS_HEADER,
+                    pointer: 4,
+                });
+            }
+            // The reassembled payload also holds the headers before the Fragment header
+            if headers.offset + end > MAX_PACKET_LENGTH {
+                return Err(HeaderError::ParameterProblem {
+                    code: ERRONEOUS_HEADER,
+                    pointer: (HEADER_LENGTH + headers.offset + 2) as u32,
+                });
+            }
+
+            let index = match self.reassemblies.iter().position(|reassembly| {
+                reassembly.source_address == packet.source_address
+                    && reassembly.destination_address == packet.destination_address
+                    && reassembly.identification == identification
+            }) {
+                Some(index) => index,
+                None => {
+                    if self.reassemblies.len() == MAX_REASSEMBLIES {
+                        self.reassemblies.remove(0);
+                    }
+                    self.reassemblies.push(Reassembly {
+                        source_address: packet.source_address,
+                        destination_address: packet.destination_address,
+                        identification,
+                        headers: None,
+                        first_fragment: None,
+                        fragments: vec![],
+                        length: None,
+                        timeout: now + REASSEMBLY_TIMEOUT,
+                    });
+                    self.reassemblies.len() - 1
+                }
+            };
+            let reassembly = &mut self.reassemblies[index];
+
+            // Overlapping fragments discard the whole packet (RFC 5722)
+            let overlaps = reassembly.fragments.iter().any(|(offset, fragment)| {
+                fragment_offset < offset + fragment.len() && *offset < end
+            });
+            let received_end = reassembly
+                .fragments
+                .last()
+                .map_or(0, |(offset, fragment)| offset + fragment.len());
+            let inconsistent = match reassembly.length {
+                Some(length) => end > length || (!more_fragments && end != length),
+                None => !more_fragments && received_end > end,
+            };
+            if overlaps || inconsistent {
+                self.reassemblies.remove(index);
+                return Err(HeaderError::Discard);
+            }
+
+            if !more_fragments {
+                reassembly.length = Some(end);
+            }
+            if fragment_offset == 0 {
+                let mut unfragmentable = packet.payload[..headers.offset].to_vec();
+                let next_header = match headers.chain.last() {
+                    Some(last) => {
+                        unfragmentable[last.offset] = header[0];
+                        packet.next_header
+                    }
+                    None => header[0],
+                };
+                let headers = Ipv6 {
+                    next_header,
+                    payload: &unfragmentable,
+                    ..*packet
+                };
+                reassembly.headers = Some(headers.to_bytes());
+                reassembly.first_fragment = Some(packet.to_bytes());
+            }
+            let position = reassembly
+                .fragments
+                .partition_point(|(offset, _)| *offset < fragment_offset);
+            reassembly
+                .fragments
+                .insert(position, (fragment_offset, data.to_vec()));
+
+            if !reassembly.is_complete() {
+                return Ok(None);
+            }
+            let reassembly = self.reassemblies.remove(index);
+            let mut reassembled = reassembly.headers.unwrap();
+            // Only the first fragment's headers are kept, they may be longer than the others'
+            if reassembled.len() - HEADER_LENGTH + reassembly.length.unwrap() > MAX_PACKET_LENGTH {
+                return Err(HeaderError::Discard);
+            }
+            for (_, data) in reassembly.fragments {
+                reassembled.extend_from_slice(&data);
+            }
+            let payload_length = (reassembled.len() - HEADER_LENGTH) as u16;
+            reassembled[4..6].copy_from_slice(&payload_length.to_be_bytes());
+            Ok(Some(reassembled))
+        }
+
+        pub fn poll_at(&self) -> Option<Instant> {
+            self.reassemblies
+                .iter()
+                .map(|reassembly| reassembly.timeout)
+                .min()
+        }
+
+        /// Drop expired reassemblies, returning their first fragment when it was received so
+        /// that the source can be sent a Time Exceeded message.
+        pub fn poll(&mut self, now: Instant) -> Vec<Vec<u8>> {
+            let mut first_fragments = vec![];
+            self.reassemblies.retain_mut(|reassembly| {
+                if now < reassembly.timeout {
+                    return true;
+                }
+                first_fragments.extend(reassembly.first_fragment.take());
+                false
+            });
+            first_fragments
+        }
+    }
+}
+
 // https://github.com/smoltcp-rs/smoltcp/blob/master/src/wire/ip.rs#L806
 pub mod checksum {
-    use std::net::Ipv6Addr;
+    use core::net::Ipv6Addr;
 
     fn propagate_carries(word: u32) -> u16 {
         let sum = (word >> 16) + (word & 0xffff);
@@ -109,7 +541,7 @@ pub mod checksum {
     ) -> u16 {
         let mut proto_len = [0u8; 8];
         proto_len[7] = protocol;
-        (&mut proto_len[0..4]).copy_from_slice(&length.to_be_bytes());
+        proto_len[0..4].copy_from_slice(&length.to_be_bytes());
         combine(&[
             data(&src_addr.octets()),
             data(&dst_addr.octets()),
@@ -117,3 +549,184 @@ pub mod checksum {
         ])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::time::{Duration, Instant};
+    use super::*;
+
+    fn packet(next_header: u8, payload: &[u8]) -> Ipv6<'_> {
+        Ipv6 {
+            flags: 0x60000000,
+            next_header,
+            hop_limit: 1,
+            source_address: "fe80::1".parse().unwrap(),
+            destination_address: "ff02::1".parse().unwrap(),
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_walk_extension_headers() {
+        let mut payload = router_alert_header(NEXT_HEADER_DESTINATION_OPTIONS).to_vec();
+        // Destination Options with an unknown option to skip, then an atomic fragment
+        payload.extend_from_slice(&[NEXT_HEADER_FRAGMENT, 0, 0x1e, 0, 1, 2, 0, 0]);
+        payload.extend_from_slice(&[58, 0, 0, 0, 0, 0, 0, 1]);
+        payload.extend_from_slice(&[143, 0, 0, 0]);
+
+        let headers = packet(NEXT_HEADER_HOP_BY_HOP, &payload).headers().unwrap();
+        assert_eq!(headers.protocol, 58);
+        assert_eq!(headers.offset, 24);
+        assert_eq!(headers.chain.len(), 3);
+        assert_eq!(
+            headers.chain[1].header_type,
+            NEXT_HEADER_DESTINATION_OPTIONS
+        );
+        assert_eq!(headers.protocol_pointer(), 40 + 16);
+    }
+
+    #[test]
+    fn test_unknown_option_actions() {
+        let options = |option_type| [17, 0, option_type, 2, 0, 0, 1, 0];
+
+        let payload = options(0x5e);
+        let result = packet(NEXT_HEADER_DESTINATION_OPTIONS, &payload).headers();
+        assert_eq!(result, Err(HeaderError::Discard));
+
+        let payload = options(0x9e);
+        let result = packet(NEXT_HEADER_DESTINATION_OPTIONS, &payload).headers();
+        assert_eq!(
+            result,
+            Err(HeaderError::ParameterProblem {
+                code: UNRECOGNIZED_OPTION,
+                pointer: 42
+            })
+        );
+
+        // Not reported for multicast destinations
+        let payload = options(0xde);
+        let result = packet(NEXT_HEADER_DESTINATION_OPTIONS, &payload).headers();
+        assert_eq!(result, Err(HeaderError::Discard));
+
+        let payload = [NEXT_HEADER_HOP_BY_HOP, 0, 1, 4, 0, 0, 0, 0];
+        let result = packet(NEXT_HEADER_DESTINATION_OPTIONS, &payload).headers();
+        assert_eq!(
+            result,
+            Err(HeaderError::ParameterProblem {
+                code: UNRECOGNIZED_NEXT_HEADER,
+                pointer: 40
+            })
+        );
+    }
+
+    #[test]
+    fn test_fragment_and_reassemble() {
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let mut payload = router_alert_header(17).to_vec();
+        payload.extend_from_slice(&data);
+        let fragments = packet(NEXT_HEADER_HOP_BY_HOP, &payload).fragment(7, 1280);
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 1280));
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        let mut reassembled = None;
+        for fragment in fragments.iter().rev() {
+            let fragment = Ipv6::parse(fragment).unwrap();
+            let headers = fragment.headers().unwrap();
+            assert_eq!(headers.protocol, NEXT_HEADER_FRAGMENT);
+            reassembled = reassembler.process(&fragment, &headers, now).unwrap();
+        }
+        let reassembled = reassembled.unwrap();
+        let reassembled = Ipv6::parse(&reassembled).unwrap();
+        assert_eq!(reassembled.next_header, NEXT_HEADER_HOP_BY_HOP);
+        assert_eq!(reassembled.payload, &payload[..]);
+        assert_eq!(reassembled.headers().unwrap().protocol, 17);
+    }
+
+    #[test]
+    fn test_reassembly_overlap_and_timeout() {
+        let fragment = |offset: u16, more_fragments: bool, identification: u8| {
+            let mut payload = vec![17, 0];
+            payload.extend_from_slice(&(offset | more_fragments as u16).to_be_bytes());
+            payload.extend_from_slice(&[0, 0, 0, identification]);
+            payload.extend_from_slice(&[0; 16]);
+            payload
+        };
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        let mut process = |payload: &[u8], now| {
+            let fragment = packet(NEXT_HEADER_FRAGMENT, payload);
+            let headers = fragment.headers().unwrap();
+            reassembler.process(&fragment, &headers, now)
+        };
+
+        assert_eq!(process(&fragment(0, true, 1), now), Ok(None));
+        assert_eq!(
+            process(&fragment(8, true, 1), now),
+            Err(HeaderError::Discard)
+        );
+        // The reassembly was dropped with the overlapping fragment
+        assert_eq!(process(&fragment(16, false, 1), now), Ok(None));
+
+        assert_eq!(process(&fragment(0, true, 2), now), Ok(None));
+        assert_eq!(reassembler.poll(now + Duration::from_secs(59)).len(), 0);
+        let expired = reassembler.poll(now + Duration::from_secs(60));
+        assert_eq!(
+            expired,
+            vec![packet(NEXT_HEADER_FRAGMENT, &fragment(0, true, 2)).to_bytes()]
+        );
+    }
+
+    #[test]
+    fn test_reassembled_packet_too_long() {
+        let fragment = |hop_by_hop: bool, offset: u16, more_fragments: bool, len| {
+            let mut payload = vec![];
+            if hop_by_hop {
+                payload.extend_from_slice(&router_alert_header(NEXT_HEADER_FRAGMENT));
+            }
+            payload.extend_from_slice(&[17, 0]);
+            payload.extend_from_slice(&(offset | more_fragments as u16).to_be_bytes());
+            payload.extend_from_slice(&[0, 0, 0, 1]);
+            payload.resize(payload.len() + len, 0);
+            let next_header = if hop_by_hop {
+                NEXT_HEADER_HOP_BY_HOP
+            } else {
+                NEXT_HEADER_FRAGMENT
+            };
+            (next_header, payload)
+        };
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        let mut process = |(next_header, payload): (u8, Vec<u8>)| {
+            let fragment = packet(next_header, &payload);
+            let headers = fragment.headers().unwrap();
+            reassembler.process(&fragment, &headers, now)
+        };
+
+        // Fits without the Hop-by-Hop Options header only
+        assert_eq!(
+            process(fragment(true, 65512, false, 16)),
+            Err(HeaderError::ParameterProblem {
+                code: ERRONEOUS_HEADER,
+                pointer: 50
+            })
+        );
+        assert_eq!(process(fragment(false, 65512, false, 16)), Ok(None));
+        assert_eq!(
+            process(fragment(true, 0, true, 65512)),
+            Err(HeaderError::Discard)
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let bytes = packet(17, &[0; 8]).to_bytes();
+        assert_eq!(Ipv6::parse(&bytes[..30]).err(), Some(ParseError::Truncated));
+        assert_eq!(Ipv6::parse(&bytes[..44]).err(), Some(ParseError::Truncated));
+        // Ethernet padding after the payload is ignored
+        let mut padded = bytes.clone();
+        padded.extend_from_slice(&[0; 4]);
+        assert_eq!(Ipv6::parse(&padded).unwrap().payload.len(), 8);
+    }
+}
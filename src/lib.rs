@@ -0,0 +1,57 @@
+// USB Ethernet device stack: a CDC EEM class carrying IPv4 and IPv6 to the host.
+//
+// The protocol modules (codecs, checksums and the CDC EEM class) only use fixed-capacity
+// buffers and build under no_std. Modules that keep state in collections, and `Interface`,
+// sockets and the UDP services on top of them, still need the `std` feature.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// Diagnostics go to the `log` facade with the `log` feature, and are compiled out without it.
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        ::log::$level!($($arg)+);
+        #[cfg(not(feature = "log"))]
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }};
+}
+
+pub mod arp;
+pub mod buffer;
+pub mod cdc_eem;
+#[cfg(feature = "std")]
+pub mod dhcp;
+#[cfg(feature = "std")]
+pub mod dhcpv6;
+pub mod dns;
+#[cfg(feature = "std")]
+pub mod dns_server;
+pub mod error;
+pub mod ethernet;
+pub mod icmpv6;
+#[cfg(feature = "std")]
+pub mod interface;
+pub mod ip;
+pub mod ipv4;
+pub mod ipv6;
+#[cfg(feature = "std")]
+pub mod llmnr;
+#[cfg(feature = "std")]
+pub mod mdns;
+#[cfg(feature = "std")]
+pub mod mld;
+#[cfg(feature = "std")]
+pub mod nbns;
+#[cfg(feature = "std")]
+pub mod ndp;
+#[cfg(feature = "std")]
+pub mod socket;
+#[cfg(feature = "std")]
+pub mod stack;
+pub mod tcp;
+pub mod time;
+pub mod udp;
+
+#[cfg(feature = "std")]
+pub use interface::Interface;
@@ -0,0 +1,404 @@
+use super::dns::{self, TYPE_A, TYPE_AAAA, TYPE_ANY};
+use super::error::ParseError;
+use super::ethernet;
+use super::interface::{Datagram, UdpReply, UdpService};
+use super::mdns::HostName;
+use super::time::{Duration, Instant};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const PORT: u16 = 5355;
+pub const IPV4_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 252);
+pub const IPV6_MULTICAST_ADDR: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0x0001, 0x0003);
+
+// Default TTL of answers (RFC 4795 section 2.8)
+pub const TTL: u32 = 30;
+
+const CLASS_IN: u16 = 1;
+
+// Queries checking that no other host has the name, one per timeout (RFC 4795 section 7.1)
+const VERIFICATION_QUERIES: u8 = 3;
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+enum State {
+    /// Link down, nothing is sent or answered.
+    Stopped,
+    /// Querying for `name`, answers are tentative until no other host responded.
+    Verifying {
+        name: String,
+        id: u16,
+        sent: u8,
+        next: Instant,
+    },
+    /// `name` is ours.
+    Verified { name: String },
+    /// Another host has `name`, it isn't answered for until the host name changes.
+    Conflict { name: String },
+}
+
+/// Link-Local Multicast Name Resolution responder (RFC 4795), answering queries for the
+/// single-label `hostname` with the addresses of the device, as Windows sends them.
+///
+/// LLMNR uses the DNS message format, its conflict (C) and tentative (T) flags sit where
+/// DNS has AA and RD.
+pub struct Responder {
+    hostname: HostName,
+    addresses: Vec<IpAddr>,
+    state: State,
+}
+
+impl Responder {
+    pub fn new(hostname: HostName) -> Self {
+        Responder {
+            hostname,
+            addresses: vec![],
+            state: State::Stopped,
+        }
+    }
+
+    pub fn add_address(&mut self, address: IpAddr) {
+        self.addresses.push(address);
+    }
+
+    // Check that no other host has the host name before claiming it (RFC 4795 section 4.1)
+    fn start_verifying(&mut self, now: Instant) {
+        self.state = State::Verifying {
+            name: self.hostname.get(),
+            id: RandomState::new().hash_one(now) as u16,
+            sent: 0,
+            next: now,
+        };
+    }
+
+    /// Query for the name being verified, to the LLMNR group of each address family we
+    /// have an address of.
+    fn verification_queries(&self) -> Vec<UdpReply> {
+        let State::Verifying { name, id, .. } = &self.state else {
+            return vec![];
+        };
+        let questions = [dns::Question {
+            name: dns::DomainName::from_text(name).unwrap(),
+            qtype: TYPE_ANY,
+            class: CLASS_IN,
+        }];
+        let payload = dns::Message {
+            header: header(*id, true, false),
+            questions: &questions,
+            answers: &[],
+            authority: &[],
+            additional: &[],
+        }
+        .to_bytes();
+        let ipv4_address = self.addresses.iter().find(|address| address.is_ipv4());
+        let ipv6_address = self.addresses.iter().find(
+            |address| matches!(address, IpAddr::V6(address) if address.is_unicast_link_local()),
+        );
+        ipv4_address
+            .into_iter()
+            .chain(ipv6_address)
+            .map(|source_address| {
+                let (destination_mac, destination_address) = match source_address {
+                    IpAddr::V4(_) => (
+                        ethernet::ipv4_multicast_mac(&IPV4_MULTICAST_ADDR),
+                        IpAddr::V4(IPV4_MULTICAST_ADDR),
+                    ),
+                    IpAddr::V6(_) => (
+                        ethernet::ipv6_multicast_mac(&IPV6_MULTICAST_ADDR),
+                        IpAddr::V6(IPV6_MULTICAST_ADDR),
+                    ),
+                };
+                UdpReply {
+                    destination_mac,
+                    source_address: *source_address,
+                    destination_address,
+                    source_port: PORT,
+                    destination_port: PORT,
+                    payload: payload.clone(),
+                }
+            })
+            .collect()
+    }
+
+    // A response to a verification query from another host conflicts unless it is
+    // tentative and its address is greater than ours (RFC 4795 section 4.1)
+    fn process_response(&mut self, response: &dns::ParsedDns, datagram: &Datagram) {
+        let State::Verifying { name, id, .. } = &self.state else {
+            return;
+        };
+        let ours = response.header.id == *id
+            && response
+                .questions
+                .iter()
+                .next()
+                .is_some_and(|question| question.name.eq_ignore_ascii_case(name));
+        if !ours || response.answers.is_empty() || self.addresses.contains(&datagram.source_address)
+        {
+            return;
+        }
+        let tentative = response.header.recursion_desired;
+        if !tentative || datagram.source_address < datagram.local_address {
+            log!(
+                info,
+                "llmnr {} is used by {}",
+                name,
+                datagram.source_address
+            );
+            self.state = State::Conflict { name: name.clone() };
+        }
+    }
+
+    /// Response to `query`, nothing for other names and invalid queries.
+    fn respond(&self, query: &dns::ParsedDns) -> Option<Vec<u8>> {
+        // RFC 4795 section 2.1.1
+        if !query.header.query
+            || query.header.opcode != 0
+            || query.questions.len() != 1
+            || !query.answers.is_empty()
+            || !query.authority.is_empty()
+        {
+            return None;
+        }
+        let hostname = self.hostname.get();
+        let question = query.questions.iter().next()?;
+        if question.class != CLASS_IN || !question.name.eq_ignore_ascii_case(&hostname) {
+            return None;
+        }
+        // Until the name is verified answers have the T flag (RFC 4795 section 2.1.1)
+        let tentative = match &self.state {
+            State::Stopped => return None,
+            State::Conflict { name } if *name == hostname => return None,
+            State::Verified { name } => *name != hostname,
+            _ => true,
+        };
+
+        let answers: Vec<dns::Resource> = self
+            .addresses
+            .iter()
+            .filter_map(|address| {
+                let (rtype, data) = match address {
+                    IpAddr::V4(address) => (TYPE_A, dns::RData::A(*address)),
+                    IpAddr::V6(address) => (TYPE_AAAA, dns::RData::Aaaa(*address)),
+                };
+                (question.qtype == rtype || question.qtype == TYPE_ANY).then_some(dns::Resource {
+                    name: question.name,
+                    rtype,
+                    class: CLASS_IN,
+                    ttl: TTL,
+                    data,
+                })
+            })
+            .collect();
+        if answers.is_empty() {
+            return None;
+        }
+        let response = dns::Message {
+            header: header(query.header.id, false, tentative),
+            questions: &[question],
+            answers: &answers,
+            authority: &[],
+            additional: &[],
+        };
+        Some(response.to_bytes())
+    }
+}
+
+fn header(id: u16, query: bool, tentative: bool) -> dns::Header {
+    dns::Header {
+        id,
+        query,
+        opcode: 0,
+        authoritative_answer: false,
+        truncation: false,
+        recursion_desired: tentative,
+        recursion_available: false,
+        rcode: 0,
+    }
+}
+
+impl UdpService for Responder {
+    fn port(&self) -> u16 {
+        PORT
+    }
+
+    fn handle(&mut self, datagram: &Datagram, _now: Instant) -> Result<Vec<UdpReply>, ParseError> {
+        let query = dns::parse(datagram.payload)?;
+        log!(debug, "llmnr {:?}", query);
+        if !query.header.query {
+            self.process_response(&query, datagram);
+            return Ok(vec![]);
+        }
+
+        // Responses are always unicast, even to multicast queries (RFC 4795 section 2.1.1)
+        Ok(self
+            .respond(&query)
+            .map(|payload| UdpReply {
+                destination_mac: datagram.source_mac,
+                source_address: datagram.local_address,
+                destination_address: datagram.source_address,
+                source_port: PORT,
+                destination_port: datagram.source_port,
+                payload,
+            })
+            .into_iter()
+            .collect())
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<UdpReply> {
+        // The mDNS responder renamed the host, the new name is verified again
+        let name = match &self.state {
+            State::Stopped => return vec![],
+            State::Verifying { name, .. } | State::Verified { name } | State::Conflict { name } => {
+                name
+            }
+        };
+        if *name != self.hostname.get() {
+            self.start_verifying(now);
+        }
+        let State::Verifying { sent, next, .. } = &mut self.state else {
+            return vec![];
+        };
+        if now < *next {
+            return vec![];
+        }
+        if *sent == VERIFICATION_QUERIES {
+            let name = self.hostname.get();
+            log!(info, "llmnr {} is ours", name);
+            self.state = State::Verified { name };
+            return vec![];
+        }
+        *sent += 1;
+        *next = now + TIMEOUT;
+        self.verification_queries()
+    }
+
+    fn poll_at(&self) -> Option<Instant> {
+        match self.state {
+            State::Verifying { next, .. } => Some(next),
+            _ => None,
+        }
+    }
+
+    fn link_up(&mut self, now: Instant) {
+        self.start_verifying(now);
+    }
+
+    fn address_changed(&mut self, old: IpAddr, new: IpAddr, _now: Instant) {
+        for address in &mut self.addresses {
+            if *address == old {
+                *address = new;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        dns::write_name(name, |bytes| message.extend_from_slice(bytes));
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message
+    }
+
+    #[test]
+    fn test_respond() {
+        let mut responder = Responder::new(HostName::new("licorne"));
+        responder.add_address("192.168.42.1".parse().unwrap());
+        responder.add_address("fe80::1".parse().unwrap());
+        responder.state = State::Verified {
+            name: "licorne".to_owned(),
+        };
+
+        let query_message = query("LICORNE", TYPE_AAAA);
+        let response = responder
+            .respond(&dns::parse(&query_message).unwrap())
+            .unwrap();
+        let response = dns::parse(&response).unwrap();
+        assert_eq!(response.header.id, 0x1234);
+        assert!(!response.header.query);
+        assert_eq!(response.questions.len(), 1);
+        let answers: Vec<dns::Resource> = response.answers.iter().collect();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(
+            answers[0].data,
+            dns::RData::Aaaa("fe80::1".parse().unwrap())
+        );
+
+        let query_message = query("licorne", TYPE_ANY);
+        let response = responder.respond(&dns::parse(&query_message).unwrap());
+        assert_eq!(dns::parse(&response.unwrap()).unwrap().answers.len(), 2);
+
+        // Other names and malformed queries are ignored
+        let query_message = query("other", TYPE_A);
+        assert!(responder
+            .respond(&dns::parse(&query_message).unwrap())
+            .is_none());
+        let mut query_message = query("licorne", TYPE_A);
+        query_message[7] = 1;
+        query_message.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 1, 2, 3, 4]);
+        assert!(responder
+            .respond(&dns::parse(&query_message).unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn test_verification() {
+        let mut now = Instant::now();
+        let hostname = HostName::new("licorne");
+        let mut responder = Responder::new(hostname.clone());
+        responder.add_address("192.168.42.1".parse().unwrap());
+        responder.add_address("fe80::1".parse().unwrap());
+        // The T flag of the answer for `name`
+        let tentative = |responder: &Responder, name: &str| {
+            let query_message = query(name, TYPE_A);
+            let response = responder.respond(&dns::parse(&query_message).unwrap());
+            response.map(|response| dns::parse(&response).unwrap().header.recursion_desired)
+        };
+        assert_eq!(tentative(&responder, "licorne"), None);
+
+        // One query per address family, answers are tentative meanwhile
+        responder.link_up(now);
+        let queries = responder.poll(now);
+        assert_eq!(queries.len(), 2);
+        assert_eq!(
+            queries[1].destination_address,
+            IpAddr::V6(IPV6_MULTICAST_ADDR)
+        );
+        assert_eq!(tentative(&responder, "licorne"), Some(true));
+
+        // Another host has the name
+        let verification = dns::parse(&queries[0].payload).unwrap();
+        let mut response_message = query("licorne", TYPE_ANY);
+        response_message[..2].copy_from_slice(&verification.header.id.to_be_bytes());
+        response_message[2] = 0x80;
+        response_message[7] = 1;
+        response_message.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4]);
+        response_message.extend_from_slice(&[192, 168, 42, 2]);
+        let datagram = Datagram {
+            source_mac: [2, 0, 0, 0, 0, 2],
+            source_address: "192.168.42.2".parse().unwrap(),
+            destination_address: "192.168.42.1".parse().unwrap(),
+            local_address: "192.168.42.1".parse().unwrap(),
+            source_port: PORT,
+            destination_port: PORT,
+            payload: &response_message,
+        };
+        assert!(responder.handle(&datagram, now).unwrap().is_empty());
+        assert_eq!(tentative(&responder, "licorne"), None);
+
+        // Renamed by the mDNS responder, the new name is verified
+        hostname.set("licorne-2".to_owned());
+        for _ in 0..VERIFICATION_QUERIES {
+            assert_eq!(responder.poll(now).len(), 2);
+            assert_eq!(tentative(&responder, "licorne-2"), Some(true));
+            now += TIMEOUT;
+        }
+        assert!(responder.poll(now).is_empty());
+        assert_eq!(tentative(&responder, "licorne-2"), Some(false));
+        assert_eq!(responder.poll_at(), None);
+    }
+}
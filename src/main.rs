@@ -2,23 +2,133 @@ use usb_device::bus::UsbBusAllocator;
 use usb_device::prelude::*;
 use usbip_device::UsbIpBus;
 
-use std::net::Ipv6Addr;
+use http_over_usb::dns_server;
+use http_over_usb::stack::{Stack, TcpListener};
+use http_over_usb::{cdc_eem, dhcp, dhcpv6, ethernet, llmnr, mdns, nbns, ndp, Interface};
+use std::collections::hash_map::RandomState;
+use std::future::{poll_fn, Future};
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+const HTTP_PORT: u16 = 80;
+
+/// Locally administered MAC address, random so several devices plugged in the same host
+/// don't collide.
+fn generate_mac_address() -> [u8; 6] {
+    let random = RandomState::new().build_hasher().finish().to_be_bytes();
+    [
+        (random[0] & 0xFC) | 0x02,
+        random[1],
+        random[2],
+        random[3],
+        random[4],
+        random[5],
+    ]
+}
+
+/// Key deriving addresses after Duplicate Address Detection failures, a real device would
+/// generate it once and keep it in flash so the addresses stay stable across reboots.
+fn generate_secret_key() -> [u8; 16] {
+    let mut key = [0; 16];
+    for chunk in key.chunks_mut(8) {
+        chunk.copy_from_slice(&RandomState::new().build_hasher().finish().to_be_bytes());
+    }
+    key
+}
+
+const HTTP_RESPONSE: &[u8] =
+    b"HTTP/1.1 200 OK\r\nContent-Length: 13\r\nConnection: close\r\n\r\nHello, world!";
+
+/// Answer each connection with a fixed page once its request headers are received.
+async fn serve_http(stack: &Stack) {
+    let listener = TcpListener::bind(stack, HTTP_PORT).unwrap();
+    stack.with(|interface| {
+        let mdns_responder = interface.udp_service_mut::<mdns::Responder>().unwrap();
+        mdns_responder.add_service(
+            mdns::Service {
+                instance: "Licorne".to_owned(),
+                service_type: "_http._tcp".to_owned(),
+                port: HTTP_PORT,
+                txt: vec![("path".to_owned(), "/".to_owned())],
+            },
+            Instant::now(),
+        );
+    });
+    loop {
+        let Ok(mut stream) = listener.accept().await else {
+            continue;
+        };
+        println!("http connection from {}", stream.peer_addr());
+        let mut request = vec![];
+        let mut buffer = [0; 512];
+        loop {
+            match stream.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(length) => request.extend_from_slice(&buffer[..length]),
+                Err(error) => {
+                    println!("http {}", error);
+                    break;
+                }
+            }
+            if request.windows(4).any(|window| window == b"\r\n\r\n") {
+                println!("http {}", String::from_utf8_lossy(&request));
+                if let Err(error) = stream.write_all(HTTP_RESPONSE).await {
+                    println!("http {}", error);
+                }
+                break;
+            }
+        }
+    }
+}
+
+/// Print the diagnostics of the stack.
+struct StdoutLogger;
+
+impl log::Log for StdoutLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        println!("{} {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
 
-mod cdc_eem;
-mod dns;
-mod ethernet;
-mod icmpv6;
-mod ipv6;
-mod tcp;
-mod udp;
+struct ThreadWaker(Thread);
 
-const PROTOCOL_NUMBER_TCP: u8 = 6;
-const PROTOCOL_NUMBER_UDP: u8 = 17;
-const PROTOCOL_NUMBER_ICMPV6: u8 = 58;
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
 
-const LINK_LOCAL_MULTICAST_ADDR: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 0x00FB);
+/// Run `future` on the current thread, parking it until the future is woken or the time
+/// returned by `poll_at`.
+fn block_on<F: Future>(future: F, poll_at: impl Fn() -> Option<Instant>) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        match poll_at() {
+            Some(at) => thread::park_timeout(at.saturating_duration_since(Instant::now())),
+            None => thread::park(),
+        }
+    }
+}
 
 fn main() {
+    log::set_logger(&StdoutLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
     println!("Hello, world!");
     let bus_allocator = UsbBusAllocator::new(UsbIpBus::new());
 
@@ -29,209 +139,103 @@ fn main() {
         .device_class(cdc_eem::USB_CLASS_CDC)
         .build();
 
-    let ip_addr: Ipv6Addr = "fe80::4242".parse().unwrap();
-    let mac_address = [42, 42, 42, 42, 42, 42];
-
-    loop {
-        usb_bus.poll(&mut [&mut eem_class]);
-
-        let read = match eem_class.read() {
-            Ok(r) => r,
-            Err(UsbError::WouldBlock) => continue,
-            Err(e) => panic!("Error {:?}", e),
-        };
-
-        for packet in read.iter() {
-            let frame = match packet {
-                cdc_eem::CdcEemPacket::Data { crc: _, frame } => frame,
-            };
-
-            let ethernet_frame = ethernet::EthernetFrame::parse(frame);
-            if ethernet_frame.ether_type != ethernet::EtherType::Ipv6 {
-                println!("Unhandled {:?}", ethernet_frame.ether_type);
-                continue;
-            }
+    let mac_address = generate_mac_address();
+    let ula_prefix: Ipv6Addr = "fd42:4242:4242::".parse().unwrap();
+    let mut interface = Interface::new(mac_address, generate_secret_key(), Instant::now());
+    let ip_addr = interface.link_local_address();
+    let ula_addr = interface.add_address(&ula_prefix, Instant::now());
+    let ipv4_addr = Ipv4Addr::new(192, 168, 42, 1);
+    let ipv4_netmask = Ipv4Addr::new(255, 255, 255, 252);
+    interface.set_ipv4_address(ipv4_addr, ipv4_netmask);
+
+    for group in [
+        ndp::ALL_ROUTERS_MULTICAST_ADDR,
+        dhcpv6::ALL_DHCP_RELAY_AGENTS_AND_SERVERS,
+        mdns::IPV6_MULTICAST_ADDR,
+        llmnr::IPV6_MULTICAST_ADDR,
+    ] {
+        interface.join_multicast_group(group, Instant::now());
+    }
 
-            let ipv6 = ipv6::Ipv6::parse(ethernet_frame.payload);
-
-            match ipv6.next_header {
-                PROTOCOL_NUMBER_TCP => {
-                    let tcp = tcp::Tcp::parse(ipv6.payload);
-                    if tcp.destination_port == 80
-                        && (tcp.synchronize || tcp.push_function || tcp.fin)
-                    {
-                        println!("tcp acknowledgment");
-                        let payload = if tcp.push_function {
-                            "HTTP/1.1 200 OK\r\nContent-Length: 13\r\nConnection: close\r\n\r\nHello, world!".as_bytes().to_owned()
-                        } else {
-                            vec![]
-                        };
-                        let tcp_payload = tcp::Tcp {
-                            source_port: 80,
-                            destination_port: tcp.source_port,
-                            sequence_number: tcp.acknowledgment_number,
-                            acknowledgment_number: tcp.sequence_number
-                                + std::cmp::max(tcp.payload.len() as u32, 1),
-                            data_offset: 0,
-                            urgent_pointer_is_significant: false,
-                            acknowledgment: true,
-                            push_function: payload.len() != 0,
-                            reset: false,
-                            synchronize: tcp.synchronize,
-                            fin: false,
-                            window: 64800,
-                            checksum: 0,
-                            urgent_pointer: 0,
-                            payload: &payload,
-                        }
-                        .to_bytes(&ip_addr, &ipv6.source_address);
-
-                        let ipv6_payload = ipv6::Ipv6 {
-                            flags: 0x60000000,
-                            next_header: PROTOCOL_NUMBER_TCP,
-                            hop_limit: 255,
-                            source_address: ip_addr,
-                            destination_address: ipv6.source_address,
-                            payload: &tcp_payload,
-                        }
-                        .to_bytes();
-
-                        let packet = ethernet::EthernetFrame {
-                            destination_mac: ethernet_frame.source_mac,
-                            source_mac: mac_address,
-                            ether_type: ethernet::EtherType::Ipv6,
-                            payload: &ipv6_payload,
-                            crc: 0xdeadbeef,
-                        }
-                        .to_bytes();
-
-                        eem_class.write(&packet);
-                    }
-                    println!("tcp {:?}", tcp);
-                }
-                PROTOCOL_NUMBER_UDP => {
-                    let udp = udp::Udp::parse(ipv6.payload);
-                    if ipv6.destination_address == LINK_LOCAL_MULTICAST_ADDR
-                        && udp.destination_port == 5353
-                    {
-                        let dns = dns::parse(udp.payload);
-                        println!("mdns {:?}", dns);
-
-                        for question in dns.questions.iter() {
-                            if let Some(_) = question.name.parts().nth(0) {
-                                println!("for me");
-
-                                let aaaa_data = ip_addr.octets();
-                                let ptr_data = [
-                                    0x07, 0x6c, 0x69, 0x63, 0x6f, 0x72, 0x6e, 0x65, 0x05, 0x6c,
-                                    0x6f, 0x63, 0x61, 0x6c, 0x00,
-                                ];
-
-                                let resource = match question.qtype {
-                                    12 => dns::Resource {
-                                        name: question.name,
-                                        rtype: 12,
-                                        class: 1 | 0x8000,
-                                        ttl: 1,
-                                        data: &ptr_data,
-                                    },
-                                    28 => dns::Resource {
-                                        name: question.name,
-                                        rtype: 28,
-                                        class: 1 | 0x8000,
-                                        ttl: 1,
-                                        data: &aaaa_data,
-                                    },
-                                    _ => continue,
-                                };
-
-                                let dns_payload = dns::to_bytes(
-                                    dns::Header {
-                                        id: dns.header.id,
-                                        query: false,
-                                        opcode: 0,
-                                        authoritative_answer: true,
-                                        truncation: false,
-                                        recursion_desired: false,
-                                        recursion_available: false,
-                                        rcode: 0,
-                                    },
-                                    &[resource],
-                                );
-
-                                let udp_payload = udp::Udp {
-                                    source_port: 5353,
-                                    destination_port: 5353,
-                                    payload: &dns_payload,
-                                }
-                                .to_bytes(&ip_addr, &LINK_LOCAL_MULTICAST_ADDR);
-
-                                let ipv6_payload = ipv6::Ipv6 {
-                                    flags: 0x60000000,
-                                    next_header: PROTOCOL_NUMBER_UDP,
-                                    hop_limit: 255,
-                                    source_address: ip_addr,
-                                    destination_address: LINK_LOCAL_MULTICAST_ADDR,
-                                    payload: &udp_payload,
-                                }
-                                .to_bytes();
-
-                                let packet = ethernet::EthernetFrame {
-                                    destination_mac: ethernet_frame.source_mac,
-                                    source_mac: mac_address,
-                                    ether_type: ethernet::EtherType::Ipv6,
-                                    payload: &ipv6_payload,
-                                    crc: 0xdeadbeef,
-                                }
-                                .to_bytes();
-
-                                eem_class.write(&packet);
-                            }
-                        }
-                    }
-                }
-                PROTOCOL_NUMBER_ICMPV6 => {
-                    let icmpv6 = icmpv6::Icmpv6::parse(ipv6.payload);
-                    println!("{:?}", icmpv6);
-                    match icmpv6 {
-                        icmpv6::Icmpv6::NeighborSolicitation { target_address } => {
-                            if target_address == ip_addr {
-                                let icmpv6_payload = icmpv6::Icmpv6::NeighborAdvertisement {
-                                    router: false,
-                                    solicited: true,
-                                    override_: false,
-                                    target_address,
-                                    link_layer_address: mac_address,
-                                }
-                                .to_bytes(&ip_addr, &ipv6.source_address);
-
-                                let ipv6_payload = ipv6::Ipv6 {
-                                    flags: 0x60000000,
-                                    next_header: PROTOCOL_NUMBER_ICMPV6,
-                                    hop_limit: 255,
-                                    source_address: ip_addr,
-                                    destination_address: ipv6.source_address,
-                                    payload: &icmpv6_payload,
-                                }
-                                .to_bytes();
-
-                                let packet = ethernet::EthernetFrame {
-                                    destination_mac: ethernet_frame.source_mac,
-                                    source_mac: mac_address,
-                                    ether_type: ethernet::EtherType::Ipv6,
-                                    payload: &ipv6_payload,
-                                    crc: 0xdeadbeef,
-                                }
-                                .to_bytes();
-
-                                eem_class.write(&packet);
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                _ => {}
-            }
-        }
+    interface.add_udp_service(Box::new(dhcp::Server::new(dhcp::Config {
+        address: ipv4_addr,
+        netmask: ipv4_netmask,
+        pool_start: Ipv4Addr::new(192, 168, 42, 2),
+        pool_size: 1,
+        lease_time: 3600,
+    })));
+
+    interface.add_udp_service(Box::new(dhcpv6::Server::new(dhcpv6::Config {
+        mac_address,
+        pool_start: "fd42:4242:4242::100".parse().unwrap(),
+        pool_size: 16,
+        preferred_lifetime: 3600,
+        valid_lifetime: 7200,
+        dns_servers: vec![ula_addr],
+        search_domains: vec!["home.arpa".to_owned()],
+    })));
+
+    // mDNS renames the host on conflicts, the other name services follow
+    let hostname = mdns::HostName::new("licorne");
+    let mut mdns_responder = mdns::Responder::new(hostname.clone());
+    for address in [
+        IpAddr::V4(ipv4_addr),
+        IpAddr::V6(ip_addr),
+        IpAddr::V6(ula_addr),
+    ] {
+        mdns_responder.add_address(address);
+    }
+    interface.add_udp_service(Box::new(mdns_responder));
+
+    // Windows hosts resolve single-label names with LLMNR, then NetBIOS
+    let mut llmnr_responder = llmnr::Responder::new(hostname.clone());
+    for address in [
+        IpAddr::V4(ipv4_addr),
+        IpAddr::V6(ip_addr),
+        IpAddr::V6(ula_addr),
+    ] {
+        llmnr_responder.add_address(address);
     }
+    interface.add_udp_service(Box::new(llmnr_responder));
+    interface.add_udp_service(Box::new(nbns::Responder::new(hostname.clone(), ipv4_addr)));
+
+    // For hosts using the device as their DNS server through the RA or DHCPv6
+    interface.add_udp_service(Box::new(dns_server::Server::new(dns_server::Config {
+        zones: vec!["home.arpa".to_owned(), "42.168.192.in-addr.arpa".to_owned()],
+        host: Some(dns_server::Host {
+            name: hostname,
+            addresses: vec![IpAddr::V4(ipv4_addr), IpAddr::V6(ula_addr)],
+            ttl: mdns::HOST_NAME_TTL,
+        }),
+        records: vec![],
+    })));
+
+    interface.enable_router_advertisements(
+        ndp::RouterConfig {
+            mac_address,
+            mtu: ethernet::MTU as u32,
+            prefix: ula_prefix,
+            prefix_length: 64,
+            dns_servers: vec![ula_addr],
+            search_domains: vec!["home.arpa".to_owned()],
+            managed: false,
+            other: true,
+            interval: Duration::from_secs(200),
+        },
+        Instant::now(),
+    );
+
+    let stack = Stack::new(interface);
+    let mut run = pin!(stack.run(&mut usb_bus, &mut eem_class));
+    let mut http = pin!(serve_http(&stack));
+    // The simulated bus has no interrupt, `run` polls it on a timer
+    let result = block_on(
+        poll_fn(|cx| {
+            let _ = http.as_mut().poll(cx);
+            run.as_mut().poll(cx)
+        }),
+        || stack.poll_at(),
+    );
+    let Err(error) = result;
+    panic!("Error {:?}", error);
 }
@@ -0,0 +1,1033 @@
+use super::dns::{self, TYPE_A, TYPE_AAAA, TYPE_ANY, TYPE_PTR, TYPE_SRV, TYPE_TXT};
+use super::error::ParseError;
+use super::ethernet;
+use super::interface::{Datagram, UdpReply, UdpService};
+use super::time::{Duration, Instant};
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::rc::Rc;
+
+pub const PORT: u16 = 5353;
+pub const IPV4_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const IPV6_MULTICAST_ADDR: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 0x00FB);
+
+// TTLs of records holding a host name, and of the others (RFC 6762 section 10)
+pub const HOST_NAME_TTL: u32 = 120;
+pub const DEFAULT_TTL: u32 = 4500;
+// Answers to queries not sent from the mDNS port (RFC 6762 section 6.7)
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+// Lists the service types of the domain (RFC 6763 section 9)
+const SERVICE_TYPE_ENUMERATION_NAME: &str = "_services._dns-sd._udp.local";
+
+// Probing and announcing (RFC 6762 section 8)
+const PROBE_COUNT: u8 = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+const ANNOUNCEMENT_COUNT: u8 = 2;
+const ANNOUNCEMENT_INTERVAL: Duration = Duration::from_secs(1);
+// Wait before probing again after losing a tiebreak (RFC 6762 section 8.2)
+const TIEBREAK_DELAY: Duration = Duration::from_secs(1);
+// Past 15 conflicts in 10 seconds, wait 5 seconds before each probe (RFC 6762 section 8.1)
+const MAX_CONFLICTS: usize = 15;
+const CONFLICT_PERIOD: Duration = Duration::from_secs(10);
+const CONFLICT_DELAY: Duration = Duration::from_secs(5);
+
+// Top bit of the class: unicast response requested in questions, cache flush in records
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+// Names are kept encoded, instance names may contain dots (RFC 6763 section 4.3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Data {
+    Address(IpAddr),
+    Ptr(Vec<u8>),
+    Srv {
+        port: u16,
+        target: Vec<u8>,
+    },
+    /// Encoded strings.
+    Txt(Vec<u8>),
+}
+
+/// Record we are authoritative for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: Vec<u8>,
+    ttl: u32,
+    /// Only we have records of this name and type, caches flush other data.
+    unique: bool,
+    data: Data,
+}
+
+impl Record {
+    fn rtype(&self) -> u16 {
+        match self.data {
+            Data::Address(IpAddr::V4(_)) => TYPE_A,
+            Data::Address(IpAddr::V6(_)) => TYPE_AAAA,
+            Data::Ptr(_) => TYPE_PTR,
+            Data::Srv { .. } => TYPE_SRV,
+            Data::Txt(_) => TYPE_TXT,
+        }
+    }
+
+    fn answers(&self, question: &dns::Question) -> bool {
+        let class = question.class & !CLASS_TOP_BIT;
+        (question.qtype == self.rtype() || question.qtype == TYPE_ANY)
+            && (class == CLASS_IN || class == CLASS_ANY)
+            && question.name == self.name()
+    }
+
+    // Names were checked by `HostName::new` and `add_service`, TXT strings by `txt_data`
+    fn name(&self) -> dns::DomainName<'_> {
+        dns::DomainName::new(&self.name).unwrap()
+    }
+
+    fn rdata(&self) -> dns::RData<'_> {
+        match &self.data {
+            Data::Address(IpAddr::V4(address)) => dns::RData::A(*address),
+            Data::Address(IpAddr::V6(address)) => dns::RData::Aaaa(*address),
+            Data::Ptr(name) => dns::RData::Ptr(dns::DomainName::new(name).unwrap()),
+            Data::Srv { port, target } => dns::RData::Srv {
+                priority: 0,
+                weight: 0,
+                port: *port,
+                target: dns::DomainName::new(target).unwrap(),
+            },
+            Data::Txt(strings) => dns::RData::new(TYPE_TXT, strings).unwrap(),
+        }
+    }
+
+    fn resource(&self, class: u16, ttl: u32) -> dns::Resource<'_> {
+        dns::Resource {
+            name: self.name(),
+            rtype: self.rtype(),
+            class,
+            ttl,
+            data: self.rdata(),
+        }
+    }
+
+    fn is(&self, resource: &dns::Resource) -> bool {
+        resource.rtype == self.rtype()
+            && resource.class & !CLASS_TOP_BIT == CLASS_IN
+            && resource.data == self.rdata()
+            && resource.name == self.name()
+    }
+
+    /// Whether the querier already knows the record with at least half its TTL left
+    /// (RFC 6762 section 7.1).
+    fn is_known(&self, known_answer: &dns::Resource) -> bool {
+        self.is(known_answer) && known_answer.ttl >= self.ttl / 2
+    }
+}
+
+enum State {
+    /// Link down, nothing is sent or answered.
+    Stopped,
+    /// Checking that no other host uses the unique names being probed.
+    Probing {
+        sent: u8,
+        next: Instant,
+    },
+    /// The names are ours, telling the link about our records.
+    Announcing {
+        sent: u8,
+        next: Instant,
+    },
+    Running,
+}
+
+/// Service instance advertised with DNS-SD (RFC 6763).
+pub struct Service {
+    /// User-visible name, up to 63 bytes of any text.
+    pub instance: String,
+    /// Application and transport protocols, e.g. `_http._tcp`.
+    pub service_type: String,
+    pub port: u16,
+    /// Key/value pairs of the TXT record.
+    pub txt: Vec<(String, String)>,
+}
+
+impl Service {
+    fn type_name(&self) -> Vec<u8> {
+        encode_name(&format!("{}.local", self.service_type))
+    }
+
+    // The instance is a single label, dots included
+    fn instance_name(&self) -> Vec<u8> {
+        let mut name = vec![self.instance.len() as u8];
+        name.extend_from_slice(self.instance.as_bytes());
+        name.extend_from_slice(&self.type_name());
+        name
+    }
+
+    fn txt_data(&self) -> Vec<u8> {
+        let mut data = vec![];
+        for (key, value) in &self.txt {
+            let mut entry = format!("{}={}", key, value).into_bytes();
+            // Strings are at most 255 bytes long
+            entry.truncate(u8::MAX as usize);
+            data.push(entry.len() as u8);
+            data.extend_from_slice(&entry);
+        }
+        // A TXT record holds at least one string (RFC 6763 section 6.1)
+        if data.is_empty() {
+            data.push(0);
+        }
+        data
+    }
+}
+
+/// Single-label name of the device, shared by the responders of the name services. The
+/// mDNS responder renames it when another host claims it, the others answer for the new name.
+#[derive(Clone)]
+pub struct HostName(Rc<RefCell<String>>);
+
+impl HostName {
+    /// Panics if `name` isn't a valid DNS label.
+    pub fn new(name: &str) -> Self {
+        assert!(is_label(name), "bad host name {:?}", name);
+        HostName(Rc::new(RefCell::new(name.to_owned())))
+    }
+
+    pub fn get(&self) -> String {
+        self.0.borrow().clone()
+    }
+
+    pub(crate) fn set(&self, name: String) {
+        *self.0.borrow_mut() = name;
+    }
+}
+
+/// Multicast DNS responder answering for `<hostname>.local` with the addresses of the device,
+/// and for the DNS-SD records of its services.
+///
+/// The names are probed when the link comes up and renamed on conflicts, so `hostname` may
+/// change.
+pub struct Responder {
+    hostname: HostName,
+    addresses: Vec<IpAddr>,
+    services: Vec<Service>,
+    state: State,
+    /// Unique names not ours yet, they are probed before being answered for.
+    probing: Vec<Vec<u8>>,
+    conflicts: Vec<Instant>,
+}
+
+impl Responder {
+    pub fn new(hostname: HostName) -> Self {
+        Responder {
+            hostname,
+            addresses: vec![],
+            services: vec![],
+            state: State::Stopped,
+            probing: vec![],
+            conflicts: vec![],
+        }
+    }
+
+    /// Host name with the `.local` domain.
+    pub fn hostname(&self) -> String {
+        format!("{}.local", self.hostname.get())
+    }
+
+    pub fn add_address(&mut self, address: IpAddr) {
+        self.addresses.push(address);
+    }
+
+    /// Advertise `service` as running on this host, once its instance name is probed if the
+    /// link is up. Panics if the instance name is empty or too long.
+    pub fn add_service(&mut self, service: Service, now: Instant) {
+        assert!(
+            (1..=dns::MAX_LABEL_LENGTH).contains(&service.instance.len()),
+            "bad instance name {:?}",
+            service.instance
+        );
+        assert!(
+            dns::DomainName::new(&service.instance_name()).is_ok(),
+            "bad service type {:?}",
+            service.service_type
+        );
+        let name = service.instance_name();
+        self.services.push(service);
+        if !matches!(self.state, State::Stopped) {
+            self.start_probing(vec![name], now);
+        }
+    }
+
+    fn records(&self) -> Vec<Record> {
+        self.records_with(true)
+    }
+
+    /// Records of the names that are ours, without those being probed.
+    fn owned_records(&self) -> Vec<Record> {
+        self.records_with(false)
+    }
+
+    // Records of the names being probed are left out unless `probing`
+    fn records_with(&self, probing: bool) -> Vec<Record> {
+        let included = |name: &Vec<u8>| probing || !self.probing.contains(name);
+        let hostname = encode_name(&self.hostname());
+        let mut records: Vec<Record> = self
+            .addresses
+            .iter()
+            .filter(|_| included(&hostname))
+            .map(|address| Record {
+                name: hostname.clone(),
+                ttl: HOST_NAME_TTL,
+                unique: true,
+                data: Data::Address(*address),
+            })
+            .collect();
+
+        for service in &self.services {
+            if !included(&service.instance_name()) {
+                continue;
+            }
+            // Other hosts may offer the same service types, so the PTR records are shared
+            records.push(Record {
+                name: encode_name(SERVICE_TYPE_ENUMERATION_NAME),
+                ttl: DEFAULT_TTL,
+                unique: false,
+                data: Data::Ptr(service.type_name()),
+            });
+            records.push(Record {
+                name: service.type_name(),
+                ttl: DEFAULT_TTL,
+                unique: false,
+                data: Data::Ptr(service.instance_name()),
+            });
+            records.push(Record {
+                name: service.instance_name(),
+                ttl: HOST_NAME_TTL,
+                unique: true,
+                data: Data::Srv {
+                    port: service.port,
+                    target: hostname.clone(),
+                },
+            });
+            records.push(Record {
+                name: service.instance_name(),
+                ttl: DEFAULT_TTL,
+                unique: true,
+                data: Data::Txt(service.txt_data()),
+            });
+        }
+        records
+    }
+
+    fn unique_names(&self) -> Vec<Vec<u8>> {
+        let mut names: Vec<Vec<u8>> = self
+            .records()
+            .into_iter()
+            .filter(|record| record.unique)
+            .map(|record| record.name)
+            .collect();
+        names.dedup();
+        names
+    }
+
+    // Probe `names` with the ones still being probed, after a delay random so that hosts
+    // coming up together don't collide
+    fn start_probing(&mut self, names: Vec<Vec<u8>>, now: Instant) {
+        for name in names {
+            if !self.probing.contains(&name) {
+                self.probing.push(name);
+            }
+        }
+        self.conflicts
+            .retain(|conflict| now.duration_since(*conflict) < CONFLICT_PERIOD);
+        let delay = if self.conflicts.len() >= MAX_CONFLICTS {
+            CONFLICT_DELAY
+        } else {
+            let random = RandomState::new().hash_one(now);
+            Duration::from_millis(random % PROBE_INTERVAL.as_millis() as u64)
+        };
+        self.state = State::Probing {
+            sent: 0,
+            next: now + delay,
+        };
+    }
+
+    // Pick another name after another host claimed `name` (RFC 6762 section 9)
+    fn rename(&mut self, name: &[u8], now: Instant) {
+        let mut renamed = vec![];
+        if name == encode_name(&self.hostname()) {
+            let host = self.hostname.get();
+            let hostname = alternative_name(&host, "-");
+            log!(info, "mdns {} is taken, now {}", host, hostname);
+            self.hostname.set(hostname);
+            renamed.push(encode_name(&self.hostname()));
+        }
+        for service in &mut self.services {
+            if name == service.instance_name() {
+                let instance = alternative_name(&service.instance, " #");
+                log!(info, "mdns {} is taken, now {}", service.instance, instance);
+                service.instance = instance;
+                renamed.push(service.instance_name());
+            }
+        }
+        self.probing.retain(|probing| probing != name);
+        self.conflicts.push(now);
+        self.start_probing(renamed, now);
+    }
+
+    // Check the records of a response against ours. Any record for a name being probed is a
+    // conflict, for the others only different data for our unique records is.
+    fn process_response(&mut self, response: &dns::ParsedDns, now: Instant) {
+        let records = self.records();
+        let conflict = response
+            .answers
+            .iter()
+            .chain(response.additional.iter())
+            .find_map(|resource| {
+                records.iter().find(|record| {
+                    record.unique
+                        && resource.name == record.name()
+                        && (self.probing.contains(&record.name)
+                            || (resource.rtype == record.rtype()
+                                && !records.iter().any(|record| record.is(&resource))))
+                })
+            });
+        if let Some(record) = conflict {
+            self.rename(&record.name.clone(), now);
+        }
+    }
+
+    /// Whether a probe from another host for one of the names we probe wins over ours:
+    /// comparing the sorted authority records, the lexicographically later set wins
+    /// (RFC 6762 section 8.2).
+    fn lost_tiebreak(&self, probe: &dns::ParsedDns) -> bool {
+        let records = self.records();
+        self.probing.iter().any(|encoded| {
+            let name = dns::DomainName::new(encoded).unwrap();
+            // Names in their data may be compressed, compare it written out
+            let mut theirs: Vec<(u16, u16, Vec<u8>)> = probe
+                .authority
+                .iter()
+                .filter(|resource| resource.name == name)
+                .map(|resource| {
+                    (
+                        resource.class & !CLASS_TOP_BIT,
+                        resource.rtype,
+                        resource.data.to_bytes(),
+                    )
+                })
+                .collect();
+            let mut ours: Vec<(u16, u16, Vec<u8>)> = records
+                .iter()
+                .filter(|record| record.unique && record.name == *encoded)
+                .map(|record| (CLASS_IN, record.rtype(), record.rdata().to_bytes()))
+                .collect();
+            theirs.sort();
+            ours.sort();
+            !theirs.is_empty() && ours < theirs
+        })
+    }
+
+    /// Query for the names being probed with the records we want in its authority section.
+    fn probe(&self) -> Vec<u8> {
+        let questions: Vec<dns::Question> = self
+            .probing
+            .iter()
+            .map(|name| dns::Question {
+                name: dns::DomainName::new(name).unwrap(),
+                qtype: TYPE_ANY,
+                class: CLASS_IN | CLASS_TOP_BIT,
+            })
+            .collect();
+        let records: Vec<Record> = self
+            .records()
+            .into_iter()
+            .filter(|record| self.probing.contains(&record.name))
+            .collect();
+        let authority: Vec<dns::Resource> = records
+            .iter()
+            .map(|record| record.resource(CLASS_IN, record.ttl))
+            .collect();
+        dns::Message {
+            header: header(0, true),
+            questions: &questions,
+            answers: &[],
+            authority: &authority,
+            additional: &[],
+        }
+        .to_bytes()
+    }
+
+    // Send `payload` to the mDNS group of each address family we have an address of
+    fn multicast(&self, payload: Vec<u8>) -> Vec<UdpReply> {
+        let ipv4_address = self.addresses.iter().find(|address| address.is_ipv4());
+        let ipv6_address = self
+            .addresses
+            .iter()
+            .find(
+                |address| matches!(address, IpAddr::V6(address) if address.is_unicast_link_local()),
+            )
+            .or_else(|| self.addresses.iter().find(|address| address.is_ipv6()));
+        ipv4_address
+            .into_iter()
+            .chain(ipv6_address)
+            .map(|source_address| {
+                let (destination_mac, destination_address) = multicast_group(source_address);
+                UdpReply {
+                    destination_mac,
+                    source_address: *source_address,
+                    destination_address,
+                    source_port: PORT,
+                    destination_port: PORT,
+                    payload: payload.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Records answering the questions of `query` that the querier doesn't know yet, and
+    /// whether they may be sent by unicast.
+    fn answers(&self, query: &dns::ParsedDns) -> (Vec<Record>, bool) {
+        let mut answers: Vec<Record> = vec![];
+        let mut unicast = true;
+        let records = self.owned_records();
+        for question in query.questions.iter() {
+            for record in records.iter().cloned() {
+                if record.answers(&question) && !answers.contains(&record) {
+                    unicast &= question.class & CLASS_TOP_BIT != 0;
+                    answers.push(record);
+                }
+            }
+        }
+        answers.retain(|record| !query.answers.iter().any(|known| record.is_known(&known)));
+        (answers, unicast)
+    }
+}
+
+// `name` followed by a number, the next one if it already has one. The name is cut so that
+// the label stays within 63 bytes.
+fn alternative_name(name: &str, separator: &str) -> String {
+    let (base, number) = name
+        .rsplit_once(separator)
+        .and_then(|(base, number)| Some((base, number.parse::<u32>().ok()? + 1)))
+        .unwrap_or((name, 2));
+    let suffix = format!("{}{}", separator, number);
+    let mut len = base.len().min(dns::MAX_LABEL_LENGTH - suffix.len());
+    while !base.is_char_boundary(len) {
+        len -= 1;
+    }
+    format!("{}{}", &base[..len], suffix)
+}
+
+fn multicast_group(local_address: &IpAddr) -> ([u8; 6], IpAddr) {
+    match local_address {
+        IpAddr::V4(_) => (
+            ethernet::ipv4_multicast_mac(&IPV4_MULTICAST_ADDR),
+            IpAddr::V4(IPV4_MULTICAST_ADDR),
+        ),
+        IpAddr::V6(_) => (
+            ethernet::ipv6_multicast_mac(&IPV6_MULTICAST_ADDR),
+            IpAddr::V6(IPV6_MULTICAST_ADDR),
+        ),
+    }
+}
+
+fn header(id: u16, query: bool) -> dns::Header {
+    dns::Header {
+        id,
+        query,
+        opcode: 0,
+        authoritative_answer: !query,
+        truncation: false,
+        recursion_desired: false,
+        recursion_available: false,
+        rcode: 0,
+    }
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = vec![];
+    dns::write_name(name, |bytes| encoded.extend_from_slice(bytes));
+    encoded
+}
+
+fn is_label(name: &str) -> bool {
+    !name.is_empty() && !name.contains('.') && dns::DomainName::from_text(name).is_ok()
+}
+
+// Encode `records` in a response, `legacy` ones are for a resolver that isn't an mDNS querier
+// and repeat its `questions`
+fn response(id: u16, questions: &[dns::Question], records: &[Record], legacy: bool) -> Vec<u8> {
+    let resources: Vec<dns::Resource> = records
+        .iter()
+        .map(|record| {
+            let class = match record.unique && !legacy {
+                true => CLASS_IN | CLASS_TOP_BIT,
+                false => CLASS_IN,
+            };
+            let ttl = match legacy {
+                true => record.ttl.min(LEGACY_UNICAST_TTL),
+                false => record.ttl,
+            };
+            record.resource(class, ttl)
+        })
+        .collect();
+
+    dns::Message {
+        header: header(id, false),
+        questions: if legacy { questions } else { &[] },
+        answers: &resources,
+        authority: &[],
+        additional: &[],
+    }
+    .to_bytes()
+}
+
+impl UdpService for Responder {
+    fn port(&self) -> u16 {
+        PORT
+    }
+
+    fn handle(&mut self, datagram: &Datagram, now: Instant) -> Result<Vec<UdpReply>, ParseError> {
+        let query = dns::parse(datagram.payload)?;
+        log!(debug, "mdns {:?}", query);
+        if query.header.opcode != 0 || matches!(self.state, State::Stopped) {
+            return Ok(vec![]);
+        }
+        if !query.header.query {
+            // Responses only come from the mDNS port (RFC 6762 section 11)
+            if datagram.source_port == PORT {
+                self.process_response(&query, now);
+            }
+            return Ok(vec![]);
+        }
+        if let State::Probing { .. } = self.state {
+            if self.lost_tiebreak(&query) {
+                log!(info, "mdns lost tiebreak, probing again");
+                self.state = State::Probing {
+                    sent: 0,
+                    next: now + TIEBREAK_DELAY,
+                };
+            }
+        }
+
+        let (answers, unicast) = self.answers(&query);
+        if answers.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // Queries from another port come from simple resolvers, they get a unicast reply
+        // with their ID (RFC 6762 section 6.7)
+        let legacy = datagram.source_port != PORT;
+        let payload = if legacy {
+            let questions: Vec<dns::Question> = query.questions.iter().collect();
+            response(query.header.id, &questions, &answers, true)
+        } else {
+            response(0, &[], &answers, false)
+        };
+        let (destination_mac, destination_address) = if legacy || unicast {
+            (datagram.source_mac, datagram.source_address)
+        } else {
+            multicast_group(&datagram.local_address)
+        };
+        Ok(vec![UdpReply {
+            destination_mac,
+            source_address: datagram.local_address,
+            destination_address,
+            source_port: PORT,
+            destination_port: datagram.source_port,
+            payload,
+        }])
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<UdpReply> {
+        if let State::Probing { sent, next } = self.state {
+            if now < next {
+                return vec![];
+            }
+            if sent < PROBE_COUNT {
+                self.state = State::Probing {
+                    sent: sent + 1,
+                    next: now + PROBE_INTERVAL,
+                };
+                return self.multicast(self.probe());
+            }
+            for name in self.probing.drain(..) {
+                log!(
+                    info,
+                    "mdns {:?} is ours",
+                    dns::DomainName::new(&name).unwrap()
+                );
+            }
+            self.state = State::Announcing { sent: 0, next: now };
+        }
+        match self.state {
+            State::Announcing { sent, next } if now >= next => {
+                self.state = match sent + 1 {
+                    ANNOUNCEMENT_COUNT => State::Running,
+                    sent => State::Announcing {
+                        sent,
+                        next: now + ANNOUNCEMENT_INTERVAL,
+                    },
+                };
+                self.multicast(response(0, &[], &self.records(), false))
+            }
+            _ => vec![],
+        }
+    }
+
+    fn poll_at(&self) -> Option<Instant> {
+        match self.state {
+            State::Probing { next, .. } | State::Announcing { next, .. } => Some(next),
+            _ => None,
+        }
+    }
+
+    fn link_up(&mut self, now: Instant) {
+        self.start_probing(self.unique_names(), now);
+    }
+
+    /// Announce the new address, the cache-flush bit replaces the old one in caches
+    /// (RFC 6762 section 8.4).
+    fn address_changed(&mut self, old: IpAddr, new: IpAddr, now: Instant) {
+        for address in &mut self.addresses {
+            if *address == old {
+                *address = new;
+            }
+        }
+        if matches!(self.state, State::Announcing { .. } | State::Running) {
+            self.state = State::Announcing { sent: 0, next: now };
+        }
+    }
+
+    /// Goodbyes with a TTL of 0 for the records we announced (RFC 6762 section 10.1).
+    fn shutdown(&mut self) -> Vec<UdpReply> {
+        let announced = !matches!(self.state, State::Stopped);
+        self.state = State::Stopped;
+        if !announced {
+            return vec![];
+        }
+        let goodbyes: Vec<Record> = self
+            .owned_records()
+            .into_iter()
+            .map(|record| Record { ttl: 0, ..record })
+            .collect();
+        if goodbyes.is_empty() {
+            return vec![];
+        }
+        self.multicast(response(0, &[], &goodbyes, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERIER: [u8; 6] = [2, 0, 0, 0, 0, 2];
+
+    fn query(name: &str, qtype: u16, class: u16, known_answers: &[(u16, &[u8], u32)]) -> Vec<u8> {
+        let mut message = vec![0, 0, 0, 0, 0, 1, 0, known_answers.len() as u8, 0, 0, 0, 0];
+        dns::write_name(name, |bytes| message.extend_from_slice(bytes));
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&class.to_be_bytes());
+        for (rtype, data, ttl) in known_answers {
+            // Pointer to the question name
+            message.extend_from_slice(&[0xC0, 12]);
+            message.extend_from_slice(&rtype.to_be_bytes());
+            message.extend_from_slice(&CLASS_IN.to_be_bytes());
+            message.extend_from_slice(&ttl.to_be_bytes());
+            message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            message.extend_from_slice(data);
+        }
+        message
+    }
+
+    fn handle(responder: &mut Responder, payload: &[u8], source_port: u16) -> Vec<UdpReply> {
+        let datagram = Datagram {
+            source_mac: QUERIER,
+            source_address: "fe80::2".parse().unwrap(),
+            destination_address: IpAddr::V6(IPV6_MULTICAST_ADDR),
+            local_address: "fe80::1".parse().unwrap(),
+            source_port,
+            destination_port: PORT,
+            payload,
+        };
+        responder.handle(&datagram, Instant::now()).unwrap()
+    }
+
+    fn responder() -> Responder {
+        let mut responder = Responder::new(HostName::new("licorne"));
+        responder.add_address("192.168.42.1".parse().unwrap());
+        responder.add_address("fe80::1".parse().unwrap());
+        responder.state = State::Running;
+        responder
+    }
+
+    #[test]
+    fn test_answers() {
+        let mut responder = responder();
+        let replies = handle(
+            &mut responder,
+            &query("Licorne.local", TYPE_A, CLASS_IN, &[]),
+            PORT,
+        );
+        assert_eq!(replies.len(), 1);
+        assert_eq!(
+            replies[0].destination_address,
+            IpAddr::V6(IPV6_MULTICAST_ADDR)
+        );
+        let response = dns::parse(&replies[0].payload).unwrap();
+        assert!(!response.header.query && response.header.authoritative_answer);
+        let answers: Vec<dns::Resource> = response.answers.iter().collect();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].rtype, TYPE_A);
+        assert_eq!(answers[0].class, CLASS_IN | CLASS_TOP_BIT);
+        assert_eq!(answers[0].ttl, HOST_NAME_TTL);
+        assert_eq!(
+            answers[0].data,
+            dns::RData::A(Ipv4Addr::new(192, 168, 42, 1))
+        );
+
+        let replies = handle(
+            &mut responder,
+            &query("licorne.local", TYPE_ANY, CLASS_IN, &[]),
+            PORT,
+        );
+        assert_eq!(dns::parse(&replies[0].payload).unwrap().answers.len(), 2);
+
+        // Other names and types
+        assert!(handle(
+            &mut responder,
+            &query("other.local", TYPE_A, CLASS_IN, &[]),
+            PORT
+        )
+        .is_empty());
+        assert!(handle(
+            &mut responder,
+            &query("licorne.local", 16, CLASS_IN, &[]),
+            PORT
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_unicast_response() {
+        let mut responder = responder();
+        let payload = query("licorne.local", TYPE_AAAA, CLASS_IN | CLASS_TOP_BIT, &[]);
+        let replies = handle(&mut responder, &payload, PORT);
+        assert_eq!(
+            replies[0].destination_address,
+            "fe80::2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(replies[0].destination_port, PORT);
+
+        // Legacy resolvers get their ID back and short TTLs
+        let mut payload = query("licorne.local", TYPE_AAAA, CLASS_IN, &[]);
+        payload[0..2].copy_from_slice(&[0x12, 0x34]);
+        let replies = handle(&mut responder, &payload, 40000);
+        assert_eq!(replies[0].destination_port, 40000);
+        let response = dns::parse(&replies[0].payload).unwrap();
+        assert_eq!(response.header.id, 0x1234);
+        let question = response.questions.iter().next().unwrap();
+        assert!(question.name.eq_ignore_ascii_case("licorne.local"));
+        assert_eq!((question.qtype, question.class), (TYPE_AAAA, CLASS_IN));
+        let answer = response.answers.iter().next().unwrap();
+        assert_eq!((answer.class, answer.ttl), (CLASS_IN, LEGACY_UNICAST_TTL));
+    }
+
+    #[test]
+    fn test_known_answer_suppression() {
+        let mut responder = responder();
+        let address = [192, 168, 42, 1];
+        let known = [(TYPE_A, &address[..], HOST_NAME_TTL)];
+        let payload = query("licorne.local", TYPE_A, CLASS_IN, &known);
+        assert!(handle(&mut responder, &payload, PORT).is_empty());
+
+        // Less than half the TTL left
+        let known = [(TYPE_A, &address[..], HOST_NAME_TTL / 2 - 1)];
+        let payload = query("licorne.local", TYPE_A, CLASS_IN, &known);
+        assert_eq!(handle(&mut responder, &payload, PORT).len(), 1);
+    }
+
+    #[test]
+    fn test_services() {
+        let mut now = Instant::now();
+        let mut responder = responder();
+        let service = Service {
+            instance: "Licorne v1.0".to_owned(),
+            service_type: "_http._tcp".to_owned(),
+            port: 80,
+            txt: vec![("path".to_owned(), "/".to_owned())],
+        };
+        let instance_name = service.instance_name();
+        responder.add_service(service, now);
+
+        // Only the new instance name is probed, the host name is still answered for
+        assert_eq!(responder.probing, vec![instance_name.clone()]);
+        let probe = responder.probe();
+        assert_eq!(dns::parse(&probe).unwrap().authority.len(), 2);
+        let payload = query("licorne.local", TYPE_A, CLASS_IN, &[]);
+        assert_eq!(handle(&mut responder, &payload, PORT).len(), 1);
+        let payload = query("_http._tcp.local", TYPE_PTR, CLASS_IN, &[]);
+        assert!(handle(&mut responder, &payload, PORT).is_empty());
+        while !matches!(responder.state, State::Running) {
+            now += PROBE_INTERVAL;
+            responder.poll(now);
+        }
+
+        let mut answer = |name: &[u8], qtype: u16| {
+            // The instance name has a dot, replace the root name of the question with it
+            let mut payload = query("", qtype, CLASS_IN, &[]);
+            payload.splice(12..13, name.iter().copied());
+            let replies = handle(&mut responder, &payload, PORT);
+            let response = dns::parse(&replies[0].payload).unwrap();
+            let answer = response.answers.iter().next().unwrap();
+            (answer.class, answer.data.to_bytes())
+        };
+        let (class, data) = answer(&encode_name("_services._dns-sd._udp.local"), TYPE_PTR);
+        assert_eq!((class, data), (CLASS_IN, encode_name("_http._tcp.local")));
+        let (class, data) = answer(&encode_name("_http._tcp.local"), TYPE_PTR);
+        assert_eq!((class, data), (CLASS_IN, instance_name.clone()));
+
+        let (_, data) = answer(&instance_name, TYPE_SRV);
+        assert_eq!(data[4..6], 80u16.to_be_bytes());
+        assert_eq!(data[6..], encode_name("licorne.local"));
+        let (_, data) = answer(&instance_name, TYPE_TXT);
+        assert_eq!(data, b"\x06path=/");
+    }
+
+    #[test]
+    fn test_probe_and_announce() {
+        let mut now = Instant::now();
+        let mut responder = responder();
+        responder.state = State::Stopped;
+        assert!(handle(
+            &mut responder,
+            &query("licorne.local", TYPE_A, CLASS_IN, &[]),
+            PORT
+        )
+        .is_empty());
+
+        responder.link_up(now);
+        let mut probes = 0;
+        for _ in 0..PROBE_COUNT * 2 {
+            now += PROBE_INTERVAL;
+            for reply in responder.poll(now) {
+                let probe = dns::parse(&reply.payload).unwrap();
+                assert!(probe.header.query);
+                assert_eq!(probe.authority.len(), 2);
+                probes += 1;
+            }
+            if probes == PROBE_COUNT * 2 {
+                break;
+            }
+        }
+        // One probe per address family
+        assert_eq!(probes, PROBE_COUNT * 2);
+        assert!(matches!(responder.state, State::Probing { .. }));
+
+        now += PROBE_INTERVAL;
+        let replies = responder.poll(now);
+        assert_eq!(replies.len(), 2);
+        assert!(!dns::parse(&replies[0].payload).unwrap().header.query);
+        now += ANNOUNCEMENT_INTERVAL;
+        assert_eq!(responder.poll(now).len(), 2);
+        assert!(matches!(responder.state, State::Running));
+
+        let goodbyes = responder.shutdown();
+        let goodbye = dns::parse(&goodbyes[0].payload).unwrap();
+        assert!(goodbye.answers.iter().all(|answer| answer.ttl == 0));
+        assert!(responder.poll(now + ANNOUNCEMENT_INTERVAL).is_empty());
+    }
+
+    #[test]
+    fn test_conflict() {
+        let now = Instant::now();
+        let mut responder = responder();
+        responder.start_probing(responder.unique_names(), now);
+        // Another host answering for the name
+        let answer = Record {
+            name: encode_name("licorne.local"),
+            ttl: HOST_NAME_TTL,
+            unique: true,
+            data: Data::Address("192.168.42.2".parse().unwrap()),
+        };
+        handle(
+            &mut responder,
+            &response(0, &[], core::slice::from_ref(&answer), false),
+            PORT,
+        );
+        assert_eq!(responder.hostname(), "licorne-2.local");
+
+        // Our own announcement doesn't conflict, other data does once running
+        responder.state = State::Running;
+        responder.probing.clear();
+        let announcement = response(0, &[], &responder.records(), false);
+        handle(&mut responder, &announcement, PORT);
+        assert_eq!(responder.hostname(), "licorne-2.local");
+        let answer = Record {
+            name: encode_name("licorne-2.local"),
+            ..answer
+        };
+        handle(&mut responder, &response(0, &[], &[answer], false), PORT);
+        assert_eq!(responder.hostname(), "licorne-3.local");
+        assert!(matches!(responder.state, State::Probing { .. }));
+    }
+
+    #[test]
+    fn test_rename_long_names() {
+        let now = Instant::now();
+        let host = "a".repeat(dns::MAX_LABEL_LENGTH);
+        let mut responder = Responder::new(HostName::new(&host));
+        responder.add_address("192.168.42.1".parse().unwrap());
+        // 63 bytes, cutting 3 of them would split a character
+        let instance = format!("x{}", "é".repeat(31));
+        responder.add_service(
+            Service {
+                instance: instance.clone(),
+                service_type: "_http._tcp".to_owned(),
+                port: 80,
+                txt: vec![],
+            },
+            now,
+        );
+        responder.rename(&encode_name(&format!("{}.local", host)), now);
+        responder.rename(&responder.services[0].instance_name(), now);
+
+        assert_eq!(responder.hostname.get(), format!("{}-2", &host[..61]));
+        assert_eq!(
+            responder.services[0].instance,
+            format!("x{} #2", "é".repeat(29))
+        );
+        // The records can be written
+        assert_eq!(dns::parse(&responder.probe()).unwrap().questions.len(), 2);
+    }
+
+    #[test]
+    fn test_tiebreak() {
+        let now = Instant::now();
+        let mut responder = responder();
+        responder.start_probing(responder.unique_names(), now);
+        let mut other = Responder::new(HostName::new("licorne"));
+        other.add_address("192.168.42.2".parse().unwrap());
+        other.start_probing(other.unique_names(), now);
+
+        // 192.168.42.2 is greater than our 192.168.42.1, their probe wins
+        handle(&mut responder, &other.probe(), PORT);
+        let State::Probing { sent: 0, next } = responder.state else {
+            panic!("not probing");
+        };
+        assert!(next >= now + TIEBREAK_DELAY);
+
+        // Ours wins over a lesser one
+        let mut other = Responder::new(HostName::new("licorne"));
+        other.add_address("192.168.42.0".parse().unwrap());
+        other.start_probing(other.unique_names(), now);
+        responder.start_probing(responder.unique_names(), now);
+        handle(&mut responder, &other.probe(), PORT);
+        let State::Probing { next, .. } = responder.state else {
+            panic!("not probing");
+        };
+        assert!(next < now + TIEBREAK_DELAY);
+    }
+}
@@ -0,0 +1,192 @@
+use super::icmpv6::{self, Icmpv6, MulticastAddressRecord};
+use super::ndp::ALL_NODES_MULTICAST_ADDR;
+use super::time::{Duration, Instant};
+use std::net::Ipv6Addr;
+
+pub const ALL_MLDV2_ROUTERS_MULTICAST_ADDR: Ipv6Addr =
+    Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 0x16);
+
+// RFC 3810 section 9
+const ROBUSTNESS_VARIABLE: u8 = 2;
+const UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+struct StateChange {
+    multicast_address: Ipv6Addr,
+    record_type: u8,
+    retransmissions: u8,
+    timer: Instant,
+}
+
+/// Multicast groups joined by the interface, reported with MLDv2 (RFC 3810).
+#[derive(Default)]
+pub struct MulticastGroups {
+    groups: Vec<Ipv6Addr>,
+    changes: Vec<StateChange>,
+}
+
+/// The all-nodes group is always joined and never reported, like interface-local groups.
+fn is_reported(group: &Ipv6Addr) -> bool {
+    *group != ALL_NODES_MULTICAST_ADDR && group.segments()[0] & 0x000F > 1
+}
+
+impl MulticastGroups {
+    pub fn new() -> Self {
+        MulticastGroups::default()
+    }
+
+    fn change(&mut self, multicast_address: Ipv6Addr, record_type: u8, now: Instant) {
+        self.changes
+            .retain(|change| change.multicast_address != multicast_address);
+        if is_reported(&multicast_address) {
+            self.changes.push(StateChange {
+                multicast_address,
+                record_type,
+                retransmissions: ROBUSTNESS_VARIABLE,
+                timer: now,
+            });
+        }
+    }
+
+    pub fn join(&mut self, group: Ipv6Addr, now: Instant) {
+        if !self.groups.contains(&group) {
+            self.groups.push(group);
+            self.change(group, icmpv6::CHANGE_TO_EXCLUDE_MODE, now);
+        }
+    }
+
+    pub fn leave(&mut self, group: &Ipv6Addr, now: Instant) {
+        if let Some(index) = self.groups.iter().position(|joined| joined == group) {
+            self.groups.remove(index);
+            self.change(*group, icmpv6::CHANGE_TO_INCLUDE_MODE, now);
+        }
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = Ipv6Addr> + '_ {
+        self.groups.iter().copied()
+    }
+
+    /// Whether packets sent to a multicast address are for us.
+    pub fn is_member(&self, address: &Ipv6Addr) -> bool {
+        *address == ALL_NODES_MULTICAST_ADDR || self.groups.contains(address)
+    }
+
+    /// State Change Report for joins and leaves, each retransmitted [Robustness Variable]
+    /// times.
+    pub fn poll(&mut self, now: Instant) -> Option<Icmpv6<'static>> {
+        let mut records = heapless::Vec::new();
+        self.changes.retain_mut(|change| {
+            if now < change.timer {
+                return true;
+            }
+            let record = MulticastAddressRecord {
+                record_type: change.record_type,
+                multicast_address: change.multicast_address,
+                sources: heapless::Vec::new(),
+            };
+            // Changes that don't fit wait for the next report
+            if records.push(record).is_err() {
+                return true;
+            }
+            change.retransmissions -= 1;
+            change.timer = now + UNSOLICITED_REPORT_INTERVAL;
+            change.retransmissions > 0
+        });
+
+        if records.is_empty() {
+            None
+        } else {
+            Some(Icmpv6::MulticastListenerReport { records })
+        }
+    }
+
+    /// Time the next report is due.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.changes.iter().map(|change| change.timer).min()
+    }
+
+    /// Current State Report answering a General Query (unspecified address) or a
+    /// Multicast Address Specific Query.
+    ///
+    /// With a single host on the link there is no report implosion to avoid, so the
+    /// report is sent right away instead of after a random delay.
+    pub fn query(&self, multicast_address: &Ipv6Addr) -> Option<Icmpv6<'static>> {
+        let records: heapless::Vec<_, { icmpv6::MAX_RECORDS }> = self
+            .groups
+            .iter()
+            .filter(|group| is_reported(group))
+            .filter(|group| multicast_address.is_unspecified() || *group == multicast_address)
+            .map(|group| MulticastAddressRecord {
+                record_type: icmpv6::MODE_IS_EXCLUDE,
+                multicast_address: *group,
+                sources: heapless::Vec::new(),
+            })
+            .take(icmpv6::MAX_RECORDS)
+            .collect();
+
+        if records.is_empty() {
+            None
+        } else {
+            Some(Icmpv6::MulticastListenerReport { records })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MDNS: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 0xFB);
+
+    fn reported(report: Option<Icmpv6>) -> Vec<(u8, Ipv6Addr)> {
+        match report {
+            Some(Icmpv6::MulticastListenerReport { records }) => records
+                .into_iter()
+                .map(|record| (record.record_type, record.multicast_address))
+                .collect(),
+            None => vec![],
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_join_and_leave() {
+        let now = Instant::now();
+        let mut groups = MulticastGroups::new();
+        groups.join(MDNS, now);
+        groups.join(ALL_NODES_MULTICAST_ADDR, now);
+        assert!(groups.is_member(&MDNS));
+        assert!(!groups.is_member(&"ff02::1:3".parse().unwrap()));
+
+        let joined = vec![(icmpv6::CHANGE_TO_EXCLUDE_MODE, MDNS)];
+        assert_eq!(reported(groups.poll(now)), joined);
+        assert_eq!(reported(groups.poll(now)), vec![]);
+        let now = now + UNSOLICITED_REPORT_INTERVAL;
+        assert_eq!(reported(groups.poll(now)), joined);
+        let now = now + UNSOLICITED_REPORT_INTERVAL;
+        assert_eq!(reported(groups.poll(now)), vec![]);
+
+        groups.leave(&MDNS, now);
+        assert!(!groups.is_member(&MDNS));
+        assert_eq!(
+            reported(groups.poll(now)),
+            vec![(icmpv6::CHANGE_TO_INCLUDE_MODE, MDNS)]
+        );
+    }
+
+    #[test]
+    fn test_query() {
+        let now = Instant::now();
+        let mut groups = MulticastGroups::new();
+        groups.join(MDNS, now);
+        groups.join(ALL_NODES_MULTICAST_ADDR, now);
+
+        assert_eq!(
+            reported(groups.query(&Ipv6Addr::UNSPECIFIED)),
+            vec![(icmpv6::MODE_IS_EXCLUDE, MDNS)]
+        );
+        assert_eq!(
+            reported(groups.query(&"ff02::1:3".parse().unwrap())),
+            vec![]
+        );
+    }
+}
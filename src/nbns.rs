@@ -0,0 +1,184 @@
+use super::dns;
+use super::error::ParseError;
+use super::interface::{Datagram, UdpReply, UdpService};
+use super::mdns::HostName;
+use super::time::Instant;
+use std::net::{IpAddr, Ipv4Addr};
+
+pub const PORT: u16 = 137;
+
+// Name query of a NetBIOS name (RFC 1002 section 4.2.1.3)
+const TYPE_NB: u16 = 0x0020;
+const CLASS_IN: u16 = 1;
+pub const TTL: u32 = 300;
+
+// NetBIOS names are 15 characters padded with spaces, then a suffix byte telling the
+// service: 0x00 for the workstation, 0x20 for the file server
+const NAME_LENGTH: usize = 15;
+const SUFFIXES: [u8; 2] = [0x00, 0x20];
+
+/// NetBIOS Name Service responder (RFC 1002) answering broadcast name queries for `hostname`
+/// with the IPv4 address, the fallback of Windows hosts without LLMNR or mDNS.
+///
+/// NBNS uses the DNS message format, with the NetBIOS name encoded as a single label.
+pub struct Responder {
+    hostname: HostName,
+    address: Ipv4Addr,
+}
+
+impl Responder {
+    pub fn new(hostname: HostName, address: Ipv4Addr) -> Self {
+        Responder { hostname, address }
+    }
+
+    // `hostname` upper-cased and cut to 15 characters
+    fn name(&self) -> [u8; NAME_LENGTH] {
+        let mut name = [b' '; NAME_LENGTH];
+        for (byte, character) in name.iter_mut().zip(self.hostname.get().bytes()) {
+            *byte = character.to_ascii_uppercase();
+        }
+        name
+    }
+
+    fn is_ours(&self, question: &dns::Question) -> bool {
+        let mut labels = question.name.parts();
+        // Queries without a NetBIOS scope only have the encoded name
+        let (Some(label), None) = (labels.next(), labels.next()) else {
+            return false;
+        };
+        decode_name(label).is_some_and(|name| {
+            name[..NAME_LENGTH].eq_ignore_ascii_case(&self.name())
+                && SUFFIXES.contains(&name[NAME_LENGTH])
+        })
+    }
+
+    /// Positive name query response to `query` (RFC 1002 section 4.2.13).
+    fn respond(&self, query: &dns::ParsedDns) -> Option<Vec<u8>> {
+        if !query.header.query || query.header.opcode != 0 {
+            return None;
+        }
+        let question = query
+            .questions
+            .iter()
+            .find(|question| question.qtype == TYPE_NB && self.is_ours(question))?;
+        // Flags of a unique name of a B node, then the address
+        let mut data = vec![0, 0];
+        data.extend_from_slice(&self.address.octets());
+        let answers = [dns::Resource {
+            name: question.name,
+            rtype: TYPE_NB,
+            class: CLASS_IN,
+            ttl: TTL,
+            data: dns::RData::Other(&data),
+        }];
+        let response = dns::Message {
+            header: dns::Header {
+                id: query.header.id,
+                query: false,
+                opcode: 0,
+                authoritative_answer: true,
+                truncation: false,
+                recursion_desired: query.header.recursion_desired,
+                recursion_available: false,
+                rcode: 0,
+            },
+            questions: &[],
+            answers: &answers,
+            authority: &[],
+            additional: &[],
+        };
+        Some(response.to_bytes())
+    }
+}
+
+// Undo the first-level encoding of a name, each byte is two letters from 'A' holding its
+// nibbles (RFC 1001 section 14.1)
+fn decode_name(label: &str) -> Option<[u8; NAME_LENGTH + 1]> {
+    let mut name = [0; NAME_LENGTH + 1];
+    if label.len() != name.len() * 2 {
+        return None;
+    }
+    for (byte, pair) in name.iter_mut().zip(label.as_bytes().chunks(2)) {
+        let nibble = |letter: u8| match letter.to_ascii_uppercase() {
+            letter @ b'A'..=b'P' => Some(letter - b'A'),
+            _ => None,
+        };
+        *byte = nibble(pair[0])? << 4 | nibble(pair[1])?;
+    }
+    Some(name)
+}
+
+impl UdpService for Responder {
+    fn port(&self) -> u16 {
+        PORT
+    }
+
+    fn handle(&mut self, datagram: &Datagram, _now: Instant) -> Result<Vec<UdpReply>, ParseError> {
+        if !datagram.source_address.is_ipv4() {
+            return Ok(vec![]);
+        }
+        let query = dns::parse(datagram.payload)?;
+        log!(debug, "nbns {:?}", query);
+
+        Ok(self
+            .respond(&query)
+            .map(|payload| UdpReply {
+                destination_mac: datagram.source_mac,
+                source_address: IpAddr::V4(self.address),
+                destination_address: datagram.source_address,
+                source_port: PORT,
+                destination_port: datagram.source_port,
+                payload,
+            })
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &[u8; NAME_LENGTH + 1]) -> Vec<u8> {
+        // Broadcast query with recursion desired
+        let mut message = vec![0x42, 0x42, 0x01, 0x10, 0, 1, 0, 0, 0, 0, 0, 0, 32];
+        for byte in name {
+            message.extend_from_slice(&[b'A' + (byte >> 4), b'A' + (byte & 0xF)]);
+        }
+        message.extend_from_slice(&[0, 0, 0x20, 0, 1]);
+        message
+    }
+
+    #[test]
+    fn test_respond() {
+        let hostname = HostName::new("licorne");
+        let responder = Responder::new(hostname.clone(), Ipv4Addr::new(192, 168, 42, 1));
+        let query_message = query(b"LICORNE        \x20");
+        let response = responder
+            .respond(&dns::parse(&query_message).unwrap())
+            .unwrap();
+        let response = dns::parse(&response).unwrap();
+        assert_eq!(response.header.id, 0x4242);
+        assert!(response.header.authoritative_answer);
+        let answer = response.answers.iter().next().unwrap();
+        assert_eq!(answer.rtype, TYPE_NB);
+        assert_eq!(answer.data, dns::RData::Other(&[0, 0, 192, 168, 42, 1]));
+
+        let query_message = query(b"OTHER          \x00");
+        assert!(responder
+            .respond(&dns::parse(&query_message).unwrap())
+            .is_none());
+        // Domain master browser suffix
+        let query_message = query(b"LICORNE        \x1B");
+        assert!(responder
+            .respond(&dns::parse(&query_message).unwrap())
+            .is_none());
+
+        // Renamed by the mDNS responder
+        hostname.set("licorne-2".to_owned());
+        let query_message = query(b"LICORNE-2      \x00");
+        assert!(responder
+            .respond(&dns::parse(&query_message).unwrap())
+            .is_some());
+    }
+}
@@ -0,0 +1,832 @@
+use super::dns;
+use super::icmpv6::{self, Icmpv6, NdpOption};
+use super::time::{Duration, Instant};
+use siphasher::sip::SipHasher24;
+use std::hash::Hasher;
+use std::net::Ipv6Addr;
+
+// RFC 4861 section 6.2.1 and 10
+const MAX_INITIAL_RTR_ADVERT_INTERVAL: Duration = Duration::from_secs(16);
+const MAX_INITIAL_RTR_ADVERTISEMENTS: u8 = 3;
+
+pub const ALL_NODES_MULTICAST_ADDR: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 1);
+pub const ALL_ROUTERS_MULTICAST_ADDR: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 2);
+
+pub struct RouterConfig {
+    pub mac_address: [u8; 6],
+    pub mtu: u32,
+    pub prefix: Ipv6Addr,
+    pub prefix_length: u8,
+    pub dns_servers: Vec<Ipv6Addr>,
+    pub search_domains: Vec<String>,
+    /// Addresses are available through DHCPv6
+    pub managed: bool,
+    /// Other configuration is available through DHCPv6
+    pub other: bool,
+    pub interval: Duration,
+}
+
+pub struct RouterAdvertiser {
+    config: RouterConfig,
+    next_advertisement: Instant,
+    initial_advertisements: u8,
+}
+
+impl RouterAdvertiser {
+    pub fn new(config: RouterConfig, now: Instant) -> Self {
+        RouterAdvertiser {
+            config,
+            next_advertisement: now,
+            initial_advertisements: 0,
+        }
+    }
+
+    /// Returns an unsolicited advertisement when one is due.
+    pub fn poll(&mut self, now: Instant) -> Option<Icmpv6<'static>> {
+        if now < self.next_advertisement {
+            return None;
+        }
+
+        let interval = if self.initial_advertisements < MAX_INITIAL_RTR_ADVERTISEMENTS {
+            self.initial_advertisements += 1;
+            std::cmp::min(self.config.interval, MAX_INITIAL_RTR_ADVERT_INTERVAL)
+        } else {
+            self.config.interval
+        };
+        self.next_advertisement = now + interval;
+        Some(self.advertisement())
+    }
+
+    pub fn poll_at(&self) -> Instant {
+        self.next_advertisement
+    }
+
+    /// Advertise `new` instead of `old` as DNS server.
+    pub fn address_changed(&mut self, old: &Ipv6Addr, new: &Ipv6Addr) {
+        for server in &mut self.config.dns_servers {
+            if server == old {
+                *server = *new;
+            }
+        }
+    }
+
+    pub fn advertisement(&self) -> Icmpv6<'static> {
+        // Options stay valid for three missed advertisements, as recommended by RFC 8106
+        let lifetime = 3 * self.config.interval.as_secs() as u32;
+
+        let mut options = heapless::Vec::from_slice(&[
+            NdpOption::SourceLinkLayerAddress(self.config.mac_address),
+            NdpOption::Mtu(self.config.mtu),
+            NdpOption::PrefixInformation {
+                prefix_length: self.config.prefix_length,
+                on_link: true,
+                autonomous: true,
+                valid_lifetime: lifetime,
+                preferred_lifetime: lifetime,
+                prefix: self.config.prefix,
+            },
+        ])
+        .unwrap();
+        if !self.config.dns_servers.is_empty() {
+            let servers = self.config.dns_servers.iter().copied();
+            options
+                .push(NdpOption::RecursiveDnsServer {
+                    lifetime,
+                    servers: servers.take(icmpv6::MAX_DNS_SERVERS).collect(),
+                })
+                .unwrap();
+        }
+        if !self.config.search_domains.is_empty() {
+            let domains = self
+                .config
+                .search_domains
+                .iter()
+                .filter_map(|domain| dns::Name::try_from(domain.as_str()).ok());
+            options
+                .push(NdpOption::DnsSearchList {
+                    lifetime,
+                    domains: domains.take(icmpv6::MAX_SEARCH_DOMAINS).collect(),
+                })
+                .unwrap();
+        }
+
+        Icmpv6::RouterAdvertisement {
+            hop_limit: 64,
+            managed: self.config.managed,
+            other: self.config.other,
+            // The device is not a default router, hosts must keep routing through their uplink
+            router_lifetime: 0,
+            reachable_time: 0,
+            retransmit_timer: 0,
+            options,
+        }
+    }
+}
+
+// RFC 4861 section 10
+const REACHABLE_TIME: Duration = Duration::from_secs(30);
+const RETRANS_TIMER: Duration = Duration::from_secs(1);
+const DELAY_FIRST_PROBE_TIME: Duration = Duration::from_secs(5);
+const MAX_MULTICAST_SOLICIT: u8 = 3;
+const MAX_UNICAST_SOLICIT: u8 = 3;
+// Packets kept per neighbor while its address is being resolved
+const MAX_PENDING_PACKETS: usize = 3;
+
+/// Solicited-node multicast group of an address (RFC 4291 section 2.7.1).
+pub fn solicited_node_multicast_addr(address: &Ipv6Addr) -> Ipv6Addr {
+    let octets = address.octets();
+    Ipv6Addr::from([
+        0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xFF, octets[13], octets[14], octets[15],
+    ])
+}
+
+pub fn is_solicited_node_multicast_addr(address: &Ipv6Addr) -> bool {
+    address.octets()[..13] == [0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xFF]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NeighborState {
+    Incomplete,
+    Reachable,
+    Stale,
+    Delay,
+    Probe,
+}
+
+struct Neighbor {
+    address: Ipv6Addr,
+    link_layer_address: Option<[u8; 6]>,
+    state: NeighborState,
+    timer: Instant,
+    solicitations: u8,
+    pending: Vec<Vec<u8>>,
+}
+
+/// Neighbor Solicitation the cache needs sent.
+#[derive(Debug, PartialEq)]
+pub struct Solicitation {
+    pub target_address: Ipv6Addr,
+    /// Link-layer address to probe, multicast to the solicited-node group when unknown
+    pub unicast: Option<[u8; 6]>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum NeighborEvent {
+    Solicit(Solicitation),
+    /// Resolution failed, the packets that were waiting are dropped
+    Unreachable {
+        address: Ipv6Addr,
+        pending: Vec<Vec<u8>>,
+    },
+}
+
+/// Neighbor Cache and Neighbor Unreachability Detection (RFC 4861 section 7.3).
+#[derive(Default)]
+pub struct NeighborCache {
+    neighbors: Vec<Neighbor>,
+}
+
+impl NeighborCache {
+    pub fn new() -> Self {
+        NeighborCache::default()
+    }
+
+    fn find(&mut self, address: &Ipv6Addr) -> Option<&mut Neighbor> {
+        self.neighbors
+            .iter_mut()
+            .find(|neighbor| neighbor.address == *address)
+    }
+
+    pub fn state(&self, address: &Ipv6Addr) -> Option<NeighborState> {
+        self.neighbors
+            .iter()
+            .find(|neighbor| neighbor.address == *address)
+            .map(|neighbor| neighbor.state)
+    }
+
+    /// Link-layer address to send a packet to, starting resolution when unknown.
+    pub fn resolve(&mut self, address: &Ipv6Addr, now: Instant) -> Option<[u8; 6]> {
+        match self.find(address) {
+            Some(neighbor) => {
+                if neighbor.state == NeighborState::Stale {
+                    neighbor.state = NeighborState::Delay;
+                    neighbor.timer = now + DELAY_FIRST_PROBE_TIME;
+                }
+                neighbor.link_layer_address
+            }
+            None => {
+                self.neighbors.push(Neighbor {
+                    address: *address,
+                    link_layer_address: None,
+                    state: NeighborState::Incomplete,
+                    // The first solicitation goes out on the next poll
+                    timer: now,
+                    solicitations: 0,
+                    pending: vec![],
+                });
+                None
+            }
+        }
+    }
+
+    /// Queue a packet until the neighbor's link-layer address is known.
+    pub fn enqueue(&mut self, address: &Ipv6Addr, packet: Vec<u8>) {
+        if let Some(neighbor) = self.find(address) {
+            if neighbor.pending.len() == MAX_PENDING_PACKETS {
+                neighbor.pending.remove(0);
+            }
+            neighbor.pending.push(packet);
+        }
+    }
+
+    /// Record a link-layer address learned from a Neighbor Solicitation, Router Solicitation
+    /// or Router Advertisement source link-layer address option (RFC 4861 section 7.2.3).
+    pub fn update_from_solicitation(
+        &mut self,
+        address: &Ipv6Addr,
+        link_layer_address: [u8; 6],
+        now: Instant,
+    ) -> Vec<Vec<u8>> {
+        match self.find(address) {
+            Some(neighbor) => {
+                if neighbor.link_layer_address != Some(link_layer_address) {
+                    neighbor.link_layer_address = Some(link_layer_address);
+                    neighbor.state = NeighborState::Stale;
+                    neighbor.timer = now;
+                }
+                std::mem::take(&mut neighbor.pending)
+            }
+            None => {
+                self.neighbors.push(Neighbor {
+                    address: *address,
+                    link_layer_address: Some(link_layer_address),
+                    state: NeighborState::Stale,
+                    timer: now,
+                    solicitations: 0,
+                    pending: vec![],
+                });
+                vec![]
+            }
+        }
+    }
+
+    /// Process a Neighbor Advertisement (RFC 4861 section 7.2.5), returning the packets
+    /// that can now be sent.
+    pub fn update_from_advertisement(
+        &mut self,
+        target_address: &Ipv6Addr,
+        link_layer_address: Option<[u8; 6]>,
+        solicited: bool,
+        override_: bool,
+        now: Instant,
+    ) -> Vec<Vec<u8>> {
+        let Some(neighbor) = self.find(target_address) else {
+            return vec![];
+        };
+
+        if neighbor.state == NeighborState::Incomplete {
+            let Some(link_layer_address) = link_layer_address else {
+                return vec![];
+            };
+            neighbor.link_layer_address = Some(link_layer_address);
+            if solicited {
+                neighbor.state = NeighborState::Reachable;
+                neighbor.timer = now + REACHABLE_TIME;
+            } else {
+                neighbor.state = NeighborState::Stale;
+            }
+            return std::mem::take(&mut neighbor.pending);
+        }
+
+        let changed = link_layer_address.is_some_and(|l| Some(l) != neighbor.link_layer_address);
+        if !override_ && changed {
+            if neighbor.state == NeighborState::Reachable {
+                neighbor.state = NeighborState::Stale;
+            }
+            return vec![];
+        }
+
+        if let Some(link_layer_address) = link_layer_address {
+            neighbor.link_layer_address = Some(link_layer_address);
+        }
+        if solicited {
+            neighbor.state = NeighborState::Reachable;
+            neighbor.timer = now + REACHABLE_TIME;
+        } else if changed {
+            neighbor.state = NeighborState::Stale;
+        }
+        vec![]
+    }
+
+    /// Time the next timer expires, stale entries have none.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.neighbors
+            .iter()
+            .filter(|neighbor| neighbor.state != NeighborState::Stale)
+            .map(|neighbor| neighbor.timer)
+            .min()
+    }
+
+    /// Run the state machine timers.
+    pub fn poll(&mut self, now: Instant) -> Vec<NeighborEvent> {
+        let mut events = vec![];
+        self.neighbors.retain_mut(|neighbor| {
+            if now < neighbor.timer {
+                return true;
+            }
+            match neighbor.state {
+                NeighborState::Incomplete | NeighborState::Probe => {
+                    let max = if neighbor.state == NeighborState::Incomplete {
+                        MAX_MULTICAST_SOLICIT
+                    } else {
+                        MAX_UNICAST_SOLICIT
+                    };
+                    if neighbor.solicitations == max {
+                        events.push(NeighborEvent::Unreachable {
+                            address: neighbor.address,
+                            pending: std::mem::take(&mut neighbor.pending),
+                        });
+                        return false;
+                    }
+                    neighbor.solicitations += 1;
+                    neighbor.timer = now + RETRANS_TIMER;
+                    events.push(NeighborEvent::Solicit(Solicitation {
+                        target_address: neighbor.address,
+                        unicast: neighbor.link_layer_address,
+                    }));
+                }
+                NeighborState::Reachable => neighbor.state = NeighborState::Stale,
+                NeighborState::Delay => {
+                    neighbor.state = NeighborState::Probe;
+                    neighbor.solicitations = 1;
+                    neighbor.timer = now + RETRANS_TIMER;
+                    events.push(NeighborEvent::Solicit(Solicitation {
+                        target_address: neighbor.address,
+                        unicast: neighbor.link_layer_address,
+                    }));
+                }
+                NeighborState::Stale => {}
+            }
+            true
+        });
+        events
+    }
+}
+
+// RFC 4862 section 5.1
+const DUP_ADDR_DETECT_TRANSMITS: u8 = 1;
+// Lifetime below which advertisements can't shorten an autoconfigured address (RFC 4862 5.5.3)
+const MIN_VALID_LIFETIME_UPDATE: Duration = Duration::from_secs(2 * 60 * 60);
+pub const INFINITE_LIFETIME: u32 = 0xFFFFFFFF;
+// New interface identifiers tried after Duplicate Address Detection fails (RFC 7217 section 6)
+const IDGEN_RETRIES: u8 = 3;
+const IDGEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Modified EUI-64 interface identifier of a MAC address (RFC 4291 appendix A).
+pub fn eui64_interface_identifier(mac_address: &[u8; 6]) -> [u8; 8] {
+    [
+        mac_address[0] ^ 0x02,
+        mac_address[1],
+        mac_address[2],
+        0xFF,
+        0xFE,
+        mac_address[3],
+        mac_address[4],
+        mac_address[5],
+    ]
+}
+
+/// Interface identifier in `prefix` replacing the EUI-64 one after `dad_counter` Duplicate
+/// Address Detection failures (RFC 7217 section 5), with SipHash-2-4 keyed by `secret_key`
+/// as the pseudorandom function.
+fn stable_interface_identifier(
+    secret_key: &[u8; 16],
+    interface_identifier: &[u8; 8],
+    prefix: &Ipv6Addr,
+    dad_counter: u8,
+) -> [u8; 8] {
+    let mut hasher = SipHasher24::new_with_key(secret_key);
+    hasher.write(&prefix.octets()[..8]);
+    hasher.write(interface_identifier);
+    hasher.write_u8(dad_counter);
+    hasher.finish().to_be_bytes()
+}
+
+/// Address made of the first 64 bits of `prefix` and an interface identifier.
+pub fn address_from_prefix(prefix: &Ipv6Addr, interface_identifier: &[u8; 8]) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[8..].copy_from_slice(interface_identifier);
+    Ipv6Addr::from(octets)
+}
+
+pub fn link_local_address(mac_address: &[u8; 6]) -> Ipv6Addr {
+    address_from_prefix(
+        &Ipv6Addr::new(0xFE80, 0, 0, 0, 0, 0, 0, 0),
+        &eui64_interface_identifier(mac_address),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressState {
+    Tentative,
+    Preferred,
+    Deprecated,
+    Duplicate,
+}
+
+struct AssignedAddress {
+    address: Ipv6Addr,
+    state: AddressState,
+    timer: Instant,
+    solicitations: u8,
+    /// Duplicate Address Detection failures in the prefix of `address`.
+    dad_counter: u8,
+    preferred_until: Option<Instant>,
+    valid_until: Option<Instant>,
+}
+
+fn lifetime_deadline(lifetime: u32, now: Instant) -> Option<Instant> {
+    if lifetime == INFINITE_LIFETIME {
+        None
+    } else {
+        Some(now + Duration::from_secs(lifetime as u64))
+    }
+}
+
+/// Addresses of the interface, with Duplicate Address Detection and Stateless Address
+/// Autoconfiguration (RFC 4862).
+pub struct AddressTable {
+    interface_identifier: [u8; 8],
+    secret_key: [u8; 16],
+    addresses: Vec<AssignedAddress>,
+}
+
+impl AddressTable {
+    /// `secret_key` is only used to derive new interface identifiers after address conflicts,
+    /// it should be random and kept by the device (RFC 7217 section 5).
+    pub fn new(interface_identifier: [u8; 8], secret_key: [u8; 16]) -> Self {
+        AddressTable {
+            interface_identifier,
+            secret_key,
+            addresses: vec![],
+        }
+    }
+
+    fn find(&mut self, address: &Ipv6Addr) -> Option<&mut AssignedAddress> {
+        self.addresses
+            .iter_mut()
+            .find(|assigned| assigned.address == *address)
+    }
+
+    // Address in the /64 prefix of `prefix`
+    fn find_prefix(&mut self, prefix: &Ipv6Addr) -> Option<&mut AssignedAddress> {
+        self.addresses
+            .iter_mut()
+            .find(|assigned| assigned.address.octets()[..8] == prefix.octets()[..8])
+    }
+
+    pub fn state(&self, address: &Ipv6Addr) -> Option<AddressState> {
+        self.addresses
+            .iter()
+            .find(|assigned| assigned.address == *address)
+            .map(|assigned| assigned.state)
+    }
+
+    /// Whether packets to `address` are for us, tentative addresses don't receive traffic yet.
+    pub fn is_assigned(&self, address: &Ipv6Addr) -> bool {
+        matches!(
+            self.state(address),
+            Some(AddressState::Preferred | AddressState::Deprecated)
+        )
+    }
+
+    pub fn is_tentative(&self, address: &Ipv6Addr) -> bool {
+        self.state(address) == Some(AddressState::Tentative)
+    }
+
+    /// Addresses being probed or in use, without the duplicate ones.
+    pub fn active(&self) -> impl Iterator<Item = Ipv6Addr> + '_ {
+        self.addresses
+            .iter()
+            .filter(|assigned| assigned.state != AddressState::Duplicate)
+            .map(|assigned| assigned.address)
+    }
+
+    /// Addresses that can be used for new communications.
+    pub fn preferred(&self) -> impl Iterator<Item = Ipv6Addr> + '_ {
+        self.addresses
+            .iter()
+            .filter(|assigned| assigned.state == AddressState::Preferred)
+            .map(|assigned| assigned.address)
+    }
+
+    /// Start Duplicate Address Detection of an address with the interface identifier, unless
+    /// there is already one in `prefix`.
+    pub fn add(
+        &mut self,
+        prefix: &Ipv6Addr,
+        preferred_lifetime: u32,
+        valid_lifetime: u32,
+        now: Instant,
+    ) -> Ipv6Addr {
+        if let Some(assigned) = self.find_prefix(prefix) {
+            return assigned.address;
+        }
+        let address = address_from_prefix(prefix, &self.interface_identifier);
+        self.addresses.push(AssignedAddress {
+            address,
+            state: AddressState::Tentative,
+            timer: now,
+            solicitations: 0,
+            dad_counter: 0,
+            preferred_until: lifetime_deadline(preferred_lifetime, now),
+            valid_until: lifetime_deadline(valid_lifetime, now),
+        });
+        address
+    }
+
+    /// Another node uses or is probing for `address`, as seen from a Neighbor Advertisement
+    /// or a Neighbor Solicitation with an unspecified source (RFC 4862 section 5.4.3 and 5.4.4).
+    ///
+    /// A tentative address is replaced by one with a new interface identifier, which is
+    /// returned, until `IDGEN_RETRIES` of them failed and the address stays `Duplicate`.
+    pub fn conflict(&mut self, address: &Ipv6Addr, now: Instant) -> Option<Ipv6Addr> {
+        let (interface_identifier, secret_key) = (self.interface_identifier, self.secret_key);
+        let assigned = self.find(address)?;
+        if assigned.state != AddressState::Tentative {
+            return None;
+        }
+        log!(info, "Duplicate address {} detected", address);
+        if assigned.dad_counter == IDGEN_RETRIES {
+            assigned.state = AddressState::Duplicate;
+            return None;
+        }
+        assigned.dad_counter += 1;
+        assigned.address = address_from_prefix(
+            address,
+            &stable_interface_identifier(
+                &secret_key,
+                &interface_identifier,
+                address,
+                assigned.dad_counter,
+            ),
+        );
+        assigned.timer = now + IDGEN_DELAY;
+        assigned.solicitations = 0;
+        Some(assigned.address)
+    }
+
+    /// Autoconfigure addresses from the prefix information of a Router Advertisement
+    /// (RFC 4862 section 5.5.3).
+    pub fn process_router_advertisement(&mut self, options: &[NdpOption], now: Instant) {
+        for option in options {
+            let NdpOption::PrefixInformation {
+                prefix_length: 64,
+                autonomous: true,
+                valid_lifetime,
+                preferred_lifetime,
+                prefix,
+                ..
+            } = *option
+            else {
+                continue;
+            };
+            if prefix.segments()[0] & 0xFFC0 == 0xFE80 || preferred_lifetime > valid_lifetime {
+                continue;
+            }
+
+            match self.find_prefix(&prefix) {
+                None if valid_lifetime != 0 => {
+                    self.add(&prefix, preferred_lifetime, valid_lifetime, now);
+                }
+                None => {}
+                Some(assigned) => {
+                    assigned.preferred_until = lifetime_deadline(preferred_lifetime, now);
+                    let remaining = assigned
+                        .valid_until
+                        .map(|valid_until| valid_until.saturating_duration_since(now));
+                    let received = Duration::from_secs(valid_lifetime as u64);
+                    if valid_lifetime == INFINITE_LIFETIME
+                        || received > MIN_VALID_LIFETIME_UPDATE
+                        || remaining.is_some_and(|remaining| received > remaining)
+                    {
+                        assigned.valid_until = lifetime_deadline(valid_lifetime, now);
+                    } else if remaining.is_none_or(|r| r > MIN_VALID_LIFETIME_UPDATE) {
+                        assigned.valid_until = Some(now + MIN_VALID_LIFETIME_UPDATE);
+                    }
+                    if assigned.state == AddressState::Deprecated && preferred_lifetime != 0 {
+                        assigned.state = AddressState::Preferred;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Time the next solicitation is due or a lifetime ends.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.addresses
+            .iter()
+            .flat_map(|assigned| {
+                let timer = match assigned.state {
+                    AddressState::Tentative => Some(assigned.timer),
+                    AddressState::Preferred => assigned.preferred_until,
+                    _ => None,
+                };
+                timer.into_iter().chain(assigned.valid_until)
+            })
+            .min()
+    }
+
+    /// Run Duplicate Address Detection and lifetimes, returning the tentative addresses
+    /// to send a Neighbor Solicitation for.
+    pub fn poll(&mut self, now: Instant) -> Vec<Ipv6Addr> {
+        let mut solicitations = vec![];
+        self.addresses.retain_mut(|assigned| {
+            if assigned
+                .valid_until
+                .is_some_and(|valid_until| now >= valid_until)
+            {
+                return false;
+            }
+            match assigned.state {
+                AddressState::Tentative if now >= assigned.timer => {
+                    if assigned.solicitations == DUP_ADDR_DETECT_TRANSMITS {
+                        log!(info, "Address {} is unique", assigned.address);
+                        assigned.state = AddressState::Preferred;
+                    } else {
+                        assigned.solicitations += 1;
+                        assigned.timer = now + RETRANS_TIMER;
+                        solicitations.push(assigned.address);
+                    }
+                }
+                _ => {}
+            }
+            if assigned.state == AddressState::Preferred
+                && assigned
+                    .preferred_until
+                    .is_some_and(|preferred_until| now >= preferred_until)
+            {
+                assigned.state = AddressState::Deprecated;
+            }
+            true
+        });
+        solicitations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: Ipv6Addr = Ipv6Addr::new(0xFE80, 0, 0, 0, 0, 0, 0, 1);
+    const HOST_MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+    const SECRET_KEY: [u8; 16] = [0x42; 16];
+
+    #[test]
+    fn test_resolution() {
+        let now = Instant::now();
+        let mut cache = NeighborCache::new();
+
+        assert_eq!(cache.resolve(&HOST, now), None);
+        cache.enqueue(&HOST, vec![1, 2, 3]);
+        assert_eq!(cache.state(&HOST), Some(NeighborState::Incomplete));
+        assert_eq!(
+            cache.poll(now),
+            vec![NeighborEvent::Solicit(Solicitation {
+                target_address: HOST,
+                unicast: None,
+            })]
+        );
+        assert_eq!(cache.poll(now), vec![]);
+
+        let pending = cache.update_from_advertisement(&HOST, Some(HOST_MAC), true, true, now);
+        assert_eq!(pending, vec![vec![1, 2, 3]]);
+        assert_eq!(cache.state(&HOST), Some(NeighborState::Reachable));
+        assert_eq!(cache.resolve(&HOST, now), Some(HOST_MAC));
+    }
+
+    #[test]
+    fn test_unreachability_detection() {
+        let now = Instant::now();
+        let mut cache = NeighborCache::new();
+        cache.update_from_solicitation(&HOST, HOST_MAC, now);
+        assert_eq!(cache.state(&HOST), Some(NeighborState::Stale));
+
+        assert_eq!(cache.resolve(&HOST, now), Some(HOST_MAC));
+        assert_eq!(cache.state(&HOST), Some(NeighborState::Delay));
+
+        let mut now = now + DELAY_FIRST_PROBE_TIME;
+        for _ in 0..MAX_UNICAST_SOLICIT {
+            assert_eq!(
+                cache.poll(now),
+                vec![NeighborEvent::Solicit(Solicitation {
+                    target_address: HOST,
+                    unicast: Some(HOST_MAC),
+                })]
+            );
+            assert_eq!(cache.state(&HOST), Some(NeighborState::Probe));
+            now += RETRANS_TIMER;
+        }
+        assert_eq!(
+            cache.poll(now),
+            vec![NeighborEvent::Unreachable {
+                address: HOST,
+                pending: vec![],
+            }]
+        );
+        assert_eq!(cache.state(&HOST), None);
+    }
+
+    #[test]
+    fn test_duplicate_address_detection() {
+        let now = Instant::now();
+        let mac_address = [0x42, 0, 0, 0, 0, 1];
+        let mut addresses = AddressTable::new(eui64_interface_identifier(&mac_address), SECRET_KEY);
+        let link_local = addresses.add(
+            &"fe80::".parse().unwrap(),
+            INFINITE_LIFETIME,
+            INFINITE_LIFETIME,
+            now,
+        );
+        assert_eq!(link_local, link_local_address(&mac_address));
+        assert_eq!(
+            link_local,
+            "fe80::4000:ff:fe00:1".parse::<Ipv6Addr>().unwrap()
+        );
+
+        assert!(addresses.is_tentative(&link_local));
+        assert_eq!(addresses.poll(now), vec![link_local]);
+        assert!(!addresses.is_assigned(&link_local));
+        assert!(addresses.poll(now + RETRANS_TIMER).is_empty());
+        assert!(addresses.is_assigned(&link_local));
+
+        // Each conflict moves to a new interface identifier, until giving up
+        let mut other = addresses.add(&"fd00::".parse().unwrap(), 60, 120, now);
+        for _ in 0..IDGEN_RETRIES {
+            addresses.poll(now);
+            let replacement = addresses.conflict(&other, now).unwrap();
+            assert_ne!(replacement, other);
+            assert_eq!(replacement.segments()[..4], other.segments()[..4]);
+            assert_eq!(addresses.state(&other), None);
+            assert!(addresses.is_tentative(&replacement));
+            other = replacement;
+        }
+        assert!(addresses.poll(now).is_empty());
+        assert_eq!(addresses.poll(now + IDGEN_DELAY), vec![other]);
+        assert_eq!(addresses.conflict(&other, now), None);
+        assert_eq!(addresses.state(&other), Some(AddressState::Duplicate));
+        assert_eq!(addresses.preferred().collect::<Vec<_>>(), vec![link_local]);
+
+        // Replacements only depend on the secret key, prefix and number of conflicts
+        let prefix = "fd00::".parse().unwrap();
+        let identifier = stable_interface_identifier(&SECRET_KEY, &[1; 8], &prefix, 1);
+        assert_eq!(
+            stable_interface_identifier(&SECRET_KEY, &[1; 8], &prefix, 1),
+            identifier
+        );
+        assert_ne!(
+            stable_interface_identifier(&[0x43; 16], &[1; 8], &prefix, 1),
+            identifier
+        );
+        assert_ne!(
+            stable_interface_identifier(&SECRET_KEY, &[1; 8], &prefix, 2),
+            identifier
+        );
+    }
+
+    #[test]
+    fn test_stateless_autoconfiguration() {
+        let now = Instant::now();
+        let mut addresses = AddressTable::new([0, 0, 0, 0, 0, 0, 0, 1], SECRET_KEY);
+        let prefix = |valid_lifetime| NdpOption::PrefixInformation {
+            prefix_length: 64,
+            on_link: true,
+            autonomous: true,
+            valid_lifetime,
+            preferred_lifetime: 0,
+            prefix: "fd42::".parse().unwrap(),
+        };
+        let address = "fd42::1".parse().unwrap();
+
+        addresses.process_router_advertisement(&[prefix(3 * 60 * 60)], now);
+        assert!(addresses.is_tentative(&address));
+        addresses.poll(now);
+        addresses.poll(now + RETRANS_TIMER);
+        assert_eq!(addresses.state(&address), Some(AddressState::Deprecated));
+
+        // Advertisements can't shorten the lifetime below two hours
+        addresses.process_router_advertisement(&[prefix(10)], now);
+        addresses.poll(now + Duration::from_secs(60 * 60));
+        assert!(addresses.is_assigned(&address));
+        addresses.poll(now + MIN_VALID_LIFETIME_UPDATE);
+        assert_eq!(addresses.state(&address), None);
+    }
+
+    #[test]
+    fn test_solicited_node_multicast_addr() {
+        assert_eq!(
+            solicited_node_multicast_addr(&"fe80::1:2:3:4242".parse().unwrap()),
+            "ff02::1:ff03:4242".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,1003 @@
+use super::time::{Duration, Instant};
+use super::{ethernet, ipv4, tcp, udp};
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+// Bytes buffered in each direction of a connection, within the 16-bit window without scaling
+const TCP_BUFFER_SIZE: usize = 4096;
+// Connections completing the handshake or waiting for accept, per listener
+const MAX_BACKLOG: usize = 4;
+// Datagrams queued in each direction of a UDP socket
+const MAX_DATAGRAMS: usize = 8;
+// Doubled after each retransmission, the connection is given up after the last one
+const RETRANSMISSION_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRANSMISSIONS: u32 = 6;
+// Shorter than the 2 MSL of RFC 9293, there are few connections on a USB link
+const TIME_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+// Segment sizes a peer accepts when it sent no MSS option (RFC 9293 section 3.7.1)
+const DEFAULT_IPV4_MSS: usize = 536;
+const DEFAULT_IPV6_MSS: usize = 1220;
+
+// IPv4 packets aren't fragmented, IPv6 ones are up to the UDP length field
+fn max_datagram_len(destination: &SocketAddr) -> usize {
+    match destination {
+        SocketAddr::V4(_) => {
+            ethernet::MAX_PAYLOAD_LENGTH - ipv4::HEADER_LENGTH - udp::HEADER_LENGTH
+        }
+        SocketAddr::V6(_) => u16::MAX as usize - udp::HEADER_LENGTH,
+    }
+}
+
+/// Reason a socket operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketError {
+    /// Nothing to receive or no room to send, retry after the next poll.
+    WouldBlock,
+    AddressInUse,
+    /// The connection was closed, no more data can be sent.
+    NotConnected,
+    ConnectionReset,
+    /// The peer stopped acknowledging data.
+    TimedOut,
+    /// The datagram doesn't fit in a packet to its destination.
+    MessageTooLong,
+}
+
+impl fmt::Display for SocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SocketError::WouldBlock => "would block",
+            SocketError::AddressInUse => "address in use",
+            SocketError::NotConnected => "not connected",
+            SocketError::ConnectionReset => "connection reset",
+            SocketError::TimedOut => "timed out",
+            SocketError::MessageTooLong => "message too long",
+        })
+    }
+}
+
+impl From<SocketError> for io::Error {
+    fn from(error: SocketError) -> io::Error {
+        let kind = match error {
+            SocketError::WouldBlock => io::ErrorKind::WouldBlock,
+            SocketError::AddressInUse => io::ErrorKind::AddrInUse,
+            SocketError::NotConnected => io::ErrorKind::NotConnected,
+            SocketError::ConnectionReset => io::ErrorKind::ConnectionReset,
+            SocketError::TimedOut => io::ErrorKind::TimedOut,
+            SocketError::MessageTooLong => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, error.to_string())
+    }
+}
+
+/// Transport packet produced by the sockets, sent by the interface.
+#[derive(Debug)]
+pub(crate) enum Transmit {
+    Tcp {
+        source_address: IpAddr,
+        destination_address: IpAddr,
+        /// Header in place of the payload, it is emitted in front of `payload`.
+        header: tcp::Tcp<'static>,
+        payload: Vec<u8>,
+    },
+    /// The interface picks the source address.
+    Udp {
+        source_port: u16,
+        destination: SocketAddr,
+        payload: Vec<u8>,
+    },
+}
+
+// Sequence numbers wrap around, `a` is before `b` when it is less than half the space behind
+fn sequence_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn header(source_port: u16, destination_port: u16, sequence_number: u32) -> tcp::Tcp<'static> {
+    tcp::Tcp {
+        source_port,
+        destination_port,
+        sequence_number,
+        acknowledgment_number: 0,
+        data_offset: 0,
+        urgent_pointer_is_significant: false,
+        acknowledgment: false,
+        push_function: false,
+        reset: false,
+        synchronize: false,
+        fin: false,
+        window: 0,
+        checksum: 0,
+        urgent_pointer: 0,
+        payload: &[],
+    }
+}
+
+/// Reset answering a segment that belongs to no connection (RFC 9293 section 3.10.7.1).
+fn reset_for(segment: &tcp::Tcp) -> tcp::Tcp<'static> {
+    if segment.acknowledgment {
+        tcp::Tcp {
+            reset: true,
+            ..header(
+                segment.destination_port,
+                segment.source_port,
+                segment.acknowledgment_number,
+            )
+        }
+    } else {
+        let length = segment.payload.len() + segment.synchronize as usize + segment.fin as usize;
+        tcp::Tcp {
+            reset: true,
+            acknowledgment: true,
+            acknowledgment_number: segment.sequence_number.wrapping_add(length as u32),
+            ..header(segment.destination_port, segment.source_port, 0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
+    CloseWait,
+    LastAck,
+    Closed,
+}
+
+struct Connection {
+    local: SocketAddr,
+    remote: SocketAddr,
+    state: State,
+    /// Listener the connection is queued on until it is accepted.
+    listener: Option<usize>,
+    /// The handle was closed, the slot is freed once the connection is closed.
+    released: bool,
+    error: Option<SocketError>,
+    send_unacknowledged: u32,
+    send_next: u32,
+    send_window: u16,
+    /// Sequence number of our FIN, known once the stream is closed.
+    fin_sequence: Option<u32>,
+    receive_next: u32,
+    remote_closed: bool,
+    /// Data from `send_unacknowledged`, or right after our SYN.
+    send_buffer: VecDeque<u8>,
+    receive_buffer: VecDeque<u8>,
+    ack_pending: bool,
+    retransmit_at: Option<Instant>,
+    retransmissions: u32,
+    time_wait_until: Option<Instant>,
+}
+
+impl Connection {
+    fn new(
+        local: SocketAddr,
+        remote: SocketAddr,
+        listener: usize,
+        initial_sequence_number: u32,
+        syn: &tcp::Tcp,
+    ) -> Self {
+        Connection {
+            local,
+            remote,
+            state: State::SynReceived,
+            listener: Some(listener),
+            released: false,
+            error: None,
+            send_unacknowledged: initial_sequence_number,
+            send_next: initial_sequence_number,
+            send_window: syn.window,
+            fin_sequence: None,
+            receive_next: syn.sequence_number.wrapping_add(1),
+            remote_closed: false,
+            send_buffer: VecDeque::new(),
+            receive_buffer: VecDeque::new(),
+            ack_pending: false,
+            retransmit_at: None,
+            retransmissions: 0,
+            time_wait_until: None,
+        }
+    }
+
+    fn mss(&self) -> usize {
+        match self.remote {
+            SocketAddr::V4(_) => DEFAULT_IPV4_MSS,
+            SocketAddr::V6(_) => DEFAULT_IPV6_MSS,
+        }
+    }
+
+    fn receive_window(&self) -> usize {
+        TCP_BUFFER_SIZE - self.receive_buffer.len()
+    }
+
+    // Sequence number of the first byte of the send buffer, the SYN comes before it
+    fn data_start(&self) -> u32 {
+        match self.state {
+            State::SynReceived => self.send_unacknowledged.wrapping_add(1),
+            _ => self.send_unacknowledged,
+        }
+    }
+
+    fn close(&mut self) {
+        self.released = true;
+        let state = match self.state {
+            State::Established => State::FinWait1,
+            State::CloseWait => State::LastAck,
+            _ => return,
+        };
+        self.fin_sequence = Some(
+            self.data_start()
+                .wrapping_add(self.send_buffer.len() as u32),
+        );
+        self.state = state;
+    }
+
+    fn enter_time_wait(&mut self, now: Instant) {
+        self.state = State::TimeWait;
+        self.retransmit_at = None;
+        self.time_wait_until = Some(now + TIME_WAIT_TIMEOUT);
+    }
+
+    /// Process a segment from the peer, returns whether it must be answered with a reset.
+    fn process(&mut self, segment: &tcp::Tcp, now: Instant) -> bool {
+        if segment.reset {
+            // Only a reset at the expected sequence number is accepted (RFC 5961)
+            if segment.sequence_number == self.receive_next {
+                self.state = State::Closed;
+                self.error = Some(SocketError::ConnectionReset);
+            } else {
+                self.ack_pending = true;
+            }
+            return false;
+        }
+        if segment.synchronize {
+            if self.state == State::SynReceived
+                && segment.sequence_number.wrapping_add(1) == self.receive_next
+            {
+                // Our SYN-ACK was lost, send it again
+                self.send_next = self.send_unacknowledged;
+            } else {
+                // Challenge ACK (RFC 5961)
+                self.ack_pending = true;
+            }
+            return false;
+        }
+
+        // Segments out of order are dropped, retransmitted data is trimmed
+        if sequence_lt(self.receive_next, segment.sequence_number) {
+            self.ack_pending = true;
+            return false;
+        }
+        let mut payload = segment.payload;
+        let mut fin = segment.fin;
+        let offset = self.receive_next.wrapping_sub(segment.sequence_number) as usize;
+        if offset > 0 {
+            if offset >= payload.len() + fin as usize {
+                self.ack_pending = true;
+                return false;
+            }
+            payload = &payload[offset..];
+        }
+
+        if !segment.acknowledgment {
+            return false;
+        }
+        let acknowledgment_number = segment.acknowledgment_number;
+        if sequence_lt(self.send_next, acknowledgment_number) {
+            if self.state == State::SynReceived {
+                return true;
+            }
+            self.ack_pending = true;
+            return false;
+        }
+        if sequence_lt(self.send_unacknowledged, acknowledgment_number) {
+            self.acknowledge(acknowledgment_number, now);
+        } else if self.state == State::SynReceived {
+            return true;
+        }
+        self.send_window = segment.window;
+
+        if !payload.is_empty() {
+            if matches!(
+                self.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            ) {
+                let accepted = payload.len().min(self.receive_window());
+                self.receive_buffer.extend(&payload[..accepted]);
+                self.receive_next = self.receive_next.wrapping_add(accepted as u32);
+                fin &= accepted == payload.len();
+            }
+            self.ack_pending = true;
+        }
+
+        if fin && !self.remote_closed {
+            self.receive_next = self.receive_next.wrapping_add(1);
+            self.remote_closed = true;
+            self.ack_pending = true;
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => self.enter_time_wait(now),
+                _ => {}
+            }
+        }
+        false
+    }
+
+    fn acknowledge(&mut self, acknowledgment_number: u32, now: Instant) {
+        let mut acknowledged =
+            acknowledgment_number.wrapping_sub(self.send_unacknowledged) as usize;
+        if self.state == State::SynReceived {
+            acknowledged -= 1;
+            self.state = State::Established;
+        }
+        let data = acknowledged.min(self.send_buffer.len());
+        self.send_buffer.drain(..data);
+        self.send_unacknowledged = acknowledgment_number;
+        self.retransmissions = 0;
+        self.retransmit_at =
+            (acknowledgment_number != self.send_next).then(|| now + RETRANSMISSION_TIMEOUT);
+
+        let fin_acknowledged = self
+            .fin_sequence
+            .is_some_and(|fin_sequence| sequence_lt(fin_sequence, acknowledgment_number));
+        if fin_acknowledged {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(now),
+                State::LastAck => self.state = State::Closed,
+                _ => {}
+            }
+        }
+    }
+
+    /// Queue the segments due: SYN-ACK, data and FIN the window allows, retransmissions and
+    /// acknowledgments.
+    // Time of the next retransmission, or the end of TIME-WAIT
+    fn poll_at(&self) -> Option<Instant> {
+        match self.state {
+            State::Closed => None,
+            State::TimeWait => self.time_wait_until,
+            _ => self.retransmit_at,
+        }
+    }
+
+    fn dispatch(&mut self, now: Instant, transmits: &mut Vec<Transmit>) {
+        match self.state {
+            State::Closed => return,
+            State::TimeWait if self.time_wait_until.is_some_and(|until| now >= until) => {
+                self.state = State::Closed;
+                return;
+            }
+            _ => {}
+        }
+
+        if self.retransmit_at.is_some_and(|at| now >= at) {
+            if self.retransmissions == MAX_RETRANSMISSIONS {
+                self.state = State::Closed;
+                self.error = Some(SocketError::TimedOut);
+                return;
+            }
+            self.retransmissions += 1;
+            // Go back to the oldest unacknowledged data
+            self.send_next = self.send_unacknowledged;
+            self.retransmit_at = Some(now + RETRANSMISSION_TIMEOUT * (1 << self.retransmissions));
+        }
+
+        if self.state == State::SynReceived {
+            if self.send_next == self.send_unacknowledged {
+                let mut segment = self.segment();
+                segment.synchronize = true;
+                self.push(transmits, segment, vec![]);
+                self.send_next = self.send_next.wrapping_add(1);
+                self.retransmit_at
+                    .get_or_insert(now + RETRANSMISSION_TIMEOUT);
+            }
+            return;
+        }
+
+        loop {
+            let offset = self.send_next.wrapping_sub(self.data_start()) as usize;
+            let unsent = self.send_buffer.len().saturating_sub(offset);
+            let in_flight = self.send_next.wrapping_sub(self.send_unacknowledged) as usize;
+            let usable = (self.send_window as usize).saturating_sub(in_flight);
+            let length = unsent.min(usable).min(self.mss());
+            let fin = self.fin_sequence == Some(self.send_next.wrapping_add(length as u32));
+            if length == 0 && !fin {
+                break;
+            }
+
+            let payload: Vec<u8> = self
+                .send_buffer
+                .range(offset..offset + length)
+                .copied()
+                .collect();
+            let mut segment = self.segment();
+            segment.push_function = length > 0 && length == unsent;
+            segment.fin = fin;
+            self.push(transmits, segment, payload);
+            self.send_next = self.send_next.wrapping_add((length + fin as usize) as u32);
+            self.retransmit_at
+                .get_or_insert(now + RETRANSMISSION_TIMEOUT);
+            if fin {
+                break;
+            }
+        }
+
+        if self.ack_pending {
+            let segment = self.segment();
+            self.push(transmits, segment, vec![]);
+        }
+    }
+
+    // Segment at `send_next` acknowledging everything received
+    fn segment(&self) -> tcp::Tcp<'static> {
+        tcp::Tcp {
+            acknowledgment: true,
+            acknowledgment_number: self.receive_next,
+            window: self.receive_window() as u16,
+            ..header(self.local.port(), self.remote.port(), self.send_next)
+        }
+    }
+
+    fn push(&mut self, transmits: &mut Vec<Transmit>, header: tcp::Tcp<'static>, payload: Vec<u8>) {
+        self.ack_pending = false;
+        transmits.push(Transmit::Tcp {
+            source_address: self.local.ip(),
+            destination_address: self.remote.ip(),
+            header,
+            payload,
+        });
+    }
+}
+
+struct Datagrams {
+    port: u16,
+    received: VecDeque<(SocketAddr, Vec<u8>)>,
+    transmit: VecDeque<(SocketAddr, Vec<u8>)>,
+}
+
+enum Socket {
+    TcpListener { port: u16 },
+    Tcp(Connection),
+    Udp(Datagrams),
+}
+
+/// Sockets of an interface, the handles refer to them.
+#[derive(Default)]
+pub struct SocketSet {
+    sockets: Vec<Option<Socket>>,
+    // Initial sequence numbers hash the connection with a secret (RFC 6528)
+    sequence_secret: RandomState,
+    connections: u32,
+    // Sent by the next dispatch, for connections that are already freed
+    resets: Vec<Transmit>,
+}
+
+impl SocketSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(&mut self, socket: Socket) -> usize {
+        match self.sockets.iter().position(Option::is_none) {
+            Some(index) => {
+                self.sockets[index] = Some(socket);
+                index
+            }
+            None => {
+                self.sockets.push(Some(socket));
+                self.sockets.len() - 1
+            }
+        }
+    }
+
+    fn listener(&self, port: u16) -> Option<usize> {
+        self.sockets.iter().position(
+            |socket| matches!(socket, Some(Socket::TcpListener { port: bound }) if *bound == port),
+        )
+    }
+
+    fn udp_socket(&self, port: u16) -> Option<usize> {
+        self.sockets.iter().position(
+            |socket| matches!(socket, Some(Socket::Udp(datagrams)) if datagrams.port == port),
+        )
+    }
+
+    fn connection(&self, index: usize) -> &Connection {
+        match &self.sockets[index] {
+            Some(Socket::Tcp(connection)) => connection,
+            _ => unreachable!(),
+        }
+    }
+
+    fn connection_mut(&mut self, index: usize) -> &mut Connection {
+        match &mut self.sockets[index] {
+            Some(Socket::Tcp(connection)) => connection,
+            _ => unreachable!(),
+        }
+    }
+
+    fn datagrams(&self, index: usize) -> &Datagrams {
+        match &self.sockets[index] {
+            Some(Socket::Udp(datagrams)) => datagrams,
+            _ => unreachable!(),
+        }
+    }
+
+    fn datagrams_mut(&mut self, index: usize) -> &mut Datagrams {
+        match &mut self.sockets[index] {
+            Some(Socket::Udp(datagrams)) => datagrams,
+            _ => unreachable!(),
+        }
+    }
+
+    fn connections(&mut self) -> impl Iterator<Item = &mut Connection> {
+        self.sockets.iter_mut().filter_map(|socket| match socket {
+            Some(Socket::Tcp(connection)) => Some(connection),
+            _ => None,
+        })
+    }
+
+    fn initial_sequence_number(&mut self, local: &SocketAddr, remote: &SocketAddr) -> u32 {
+        let hash = self
+            .sequence_secret
+            .hash_one((local, remote, self.connections));
+        self.connections = self.connections.wrapping_add(1);
+        hash as u32
+    }
+
+    /// Process a segment sent to `local`, returns the reset answering it if it belongs to no
+    /// connection.
+    pub(crate) fn process_tcp(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        segment: &tcp::Tcp,
+        now: Instant,
+    ) -> Option<tcp::Tcp<'static>> {
+        let connection = self.connections().find(|connection| {
+            connection.local == local
+                && connection.remote == remote
+                && connection.state != State::Closed
+        });
+        if let Some(connection) = connection {
+            return connection.process(segment, now).then(|| reset_for(segment));
+        }
+
+        if segment.reset {
+            return None;
+        }
+        let listener = self.listener(local.port());
+        match listener {
+            Some(listener) if segment.synchronize && !segment.acknowledgment => {
+                let backlog = self
+                    .connections()
+                    .filter(|connection| connection.listener == Some(listener))
+                    .count();
+                // The peer retries the SYN later
+                if backlog == MAX_BACKLOG {
+                    return None;
+                }
+                let initial_sequence_number = self.initial_sequence_number(&local, &remote);
+                let connection =
+                    Connection::new(local, remote, listener, initial_sequence_number, segment);
+                self.add(Socket::Tcp(connection));
+                None
+            }
+            _ => Some(reset_for(segment)),
+        }
+    }
+
+    /// Queue a datagram sent to `port`, returns whether a socket is bound to it.
+    pub(crate) fn process_udp(&mut self, port: u16, source: SocketAddr, payload: &[u8]) -> bool {
+        let Some(index) = self.udp_socket(port) else {
+            return false;
+        };
+        let datagrams = self.datagrams_mut(index);
+        // Further datagrams are dropped until the application catches up
+        if datagrams.received.len() < MAX_DATAGRAMS {
+            datagrams.received.push_back((source, payload.to_vec()));
+        }
+        true
+    }
+
+    /// Packets the sockets have to send, connections that are closed and released are freed.
+    /// Time a connection timer expires, data queued by the sockets is sent on the next
+    /// `dispatch` anyway.
+    pub(crate) fn poll_at(&self) -> Option<Instant> {
+        self.sockets
+            .iter()
+            .filter_map(|slot| match slot {
+                Some(Socket::Tcp(connection)) => connection.poll_at(),
+                _ => None,
+            })
+            .min()
+    }
+
+    pub(crate) fn dispatch(&mut self, now: Instant) -> Vec<Transmit> {
+        let mut transmits = std::mem::take(&mut self.resets);
+        for slot in self.sockets.iter_mut() {
+            match slot {
+                Some(Socket::Tcp(connection)) => {
+                    connection.dispatch(now, &mut transmits);
+                    // Nobody holds a handle to connections closed before they were accepted
+                    let unused = connection.released || connection.listener.is_some();
+                    if connection.state == State::Closed && unused {
+                        *slot = None;
+                    }
+                }
+                Some(Socket::Udp(datagrams)) => {
+                    for (destination, payload) in datagrams.transmit.drain(..) {
+                        transmits.push(Transmit::Udp {
+                            source_port: datagrams.port,
+                            destination,
+                            payload,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        transmits
+    }
+
+    /// Reset a connection, for those a listener is closed with.
+    fn abort(&mut self, index: usize) -> Option<Transmit> {
+        let connection = self.connection(index);
+        let transmit = (connection.state != State::Closed).then(|| Transmit::Tcp {
+            source_address: connection.local.ip(),
+            destination_address: connection.remote.ip(),
+            header: tcp::Tcp {
+                reset: true,
+                ..header(
+                    connection.local.port(),
+                    connection.remote.port(),
+                    connection.send_next,
+                )
+            },
+            payload: vec![],
+        });
+        self.sockets[index] = None;
+        transmit
+    }
+}
+
+/// Listening TCP socket, bound to a port on every address of the interface.
+#[derive(Debug)]
+pub struct TcpListener(usize);
+
+impl TcpListener {
+    pub fn bind(sockets: &mut SocketSet, port: u16) -> Result<TcpListener, SocketError> {
+        if sockets.listener(port).is_some() {
+            return Err(SocketError::AddressInUse);
+        }
+        Ok(TcpListener(sockets.add(Socket::TcpListener { port })))
+    }
+
+    fn pending(&self, sockets: &SocketSet) -> Option<usize> {
+        sockets.sockets.iter().position(|socket| match socket {
+            Some(Socket::Tcp(connection)) => {
+                connection.listener == Some(self.0) && connection.state != State::SynReceived
+            }
+            _ => false,
+        })
+    }
+
+    /// Whether a connection completed the handshake and `accept` would return it.
+    pub fn can_accept(&self, sockets: &SocketSet) -> bool {
+        self.pending(sockets).is_some()
+    }
+
+    pub fn accept(&self, sockets: &mut SocketSet) -> Result<TcpStream, SocketError> {
+        let index = self.pending(sockets).ok_or(SocketError::WouldBlock)?;
+        sockets.connection_mut(index).listener = None;
+        Ok(TcpStream(index))
+    }
+
+    /// Stop listening, the connections that were not accepted yet are reset.
+    pub fn close(self, sockets: &mut SocketSet) {
+        let pending: Vec<usize> = (0..sockets.sockets.len())
+            .filter(|&index| match &sockets.sockets[index] {
+                Some(Socket::Tcp(connection)) => connection.listener == Some(self.0),
+                _ => false,
+            })
+            .collect();
+        for index in pending {
+            if let Some(reset) = sockets.abort(index) {
+                sockets.resets.push(reset);
+            }
+        }
+        sockets.sockets[self.0] = None;
+    }
+}
+
+/// TCP connection accepted from a listener.
+#[derive(Debug)]
+pub struct TcpStream(usize);
+
+impl TcpStream {
+    pub fn local_addr(&self, sockets: &SocketSet) -> SocketAddr {
+        sockets.connection(self.0).local
+    }
+
+    pub fn peer_addr(&self, sockets: &SocketSet) -> SocketAddr {
+        sockets.connection(self.0).remote
+    }
+
+    /// Whether `recv` would return data, the end of the stream or an error.
+    pub fn can_recv(&self, sockets: &SocketSet) -> bool {
+        let connection = sockets.connection(self.0);
+        !connection.receive_buffer.is_empty()
+            || connection.remote_closed
+            || connection.state == State::Closed
+    }
+
+    /// Whether `send` would accept data or return an error.
+    pub fn can_send(&self, sockets: &SocketSet) -> bool {
+        let connection = sockets.connection(self.0);
+        !matches!(connection.state, State::Established | State::CloseWait)
+            || connection.send_buffer.len() < TCP_BUFFER_SIZE
+    }
+
+    /// Read received data into `buffer`, 0 means the peer closed the stream.
+    pub fn recv(&self, sockets: &mut SocketSet, buffer: &mut [u8]) -> Result<usize, SocketError> {
+        let connection = sockets.connection_mut(self.0);
+        if connection.receive_buffer.is_empty() {
+            return match connection.error {
+                Some(error) => Err(error),
+                None if connection.remote_closed => Ok(0),
+                None => Err(SocketError::WouldBlock),
+            };
+        }
+
+        let window = connection.receive_window();
+        let length = buffer.len().min(connection.receive_buffer.len());
+        for (byte, received) in buffer
+            .iter_mut()
+            .zip(connection.receive_buffer.drain(..length))
+        {
+            *byte = received;
+        }
+        // Tell the peer it can send again
+        if window < connection.mss() && connection.receive_window() >= connection.mss() {
+            connection.ack_pending = true;
+        }
+        Ok(length)
+    }
+
+    /// Queue data to send, returns how much fitted in the send buffer.
+    pub fn send(&self, sockets: &mut SocketSet, data: &[u8]) -> Result<usize, SocketError> {
+        let connection = sockets.connection_mut(self.0);
+        if !matches!(connection.state, State::Established | State::CloseWait) {
+            return Err(connection.error.unwrap_or(SocketError::NotConnected));
+        }
+        let length = data
+            .len()
+            .min(TCP_BUFFER_SIZE - connection.send_buffer.len());
+        if length == 0 && !data.is_empty() {
+            return Err(SocketError::WouldBlock);
+        }
+        connection.send_buffer.extend(&data[..length]);
+        Ok(length)
+    }
+
+    /// Send the remaining data then a FIN, the connection is freed once the peer closed it
+    /// too.
+    pub fn close(self, sockets: &mut SocketSet) {
+        let connection = sockets.connection_mut(self.0);
+        connection.close();
+        if connection.state == State::Closed {
+            sockets.sockets[self.0] = None;
+        }
+    }
+}
+
+/// UDP socket bound to a port on every address of the interface.
+#[derive(Debug)]
+pub struct UdpSocket(usize);
+
+impl UdpSocket {
+    pub fn bind(sockets: &mut SocketSet, port: u16) -> Result<UdpSocket, SocketError> {
+        if sockets.udp_socket(port).is_some() {
+            return Err(SocketError::AddressInUse);
+        }
+        Ok(UdpSocket(sockets.add(Socket::Udp(Datagrams {
+            port,
+            received: VecDeque::new(),
+            transmit: VecDeque::new(),
+        }))))
+    }
+
+    pub fn can_recv(&self, sockets: &SocketSet) -> bool {
+        !sockets.datagrams(self.0).received.is_empty()
+    }
+
+    pub fn can_send(&self, sockets: &SocketSet) -> bool {
+        sockets.datagrams(self.0).transmit.len() < MAX_DATAGRAMS
+    }
+
+    /// Read a datagram into `buffer`, the part that does not fit is discarded.
+    pub fn recv_from(
+        &self,
+        sockets: &mut SocketSet,
+        buffer: &mut [u8],
+    ) -> Result<(usize, SocketAddr), SocketError> {
+        let (source, payload) = sockets
+            .datagrams_mut(self.0)
+            .received
+            .pop_front()
+            .ok_or(SocketError::WouldBlock)?;
+        let length = buffer.len().min(payload.len());
+        buffer[..length].copy_from_slice(&payload[..length]);
+        Ok((length, source))
+    }
+
+    pub fn send_to(
+        &self,
+        sockets: &mut SocketSet,
+        data: &[u8],
+        destination: SocketAddr,
+    ) -> Result<usize, SocketError> {
+        if data.len() > max_datagram_len(&destination) {
+            return Err(SocketError::MessageTooLong);
+        }
+        let datagrams = sockets.datagrams_mut(self.0);
+        if datagrams.transmit.len() == MAX_DATAGRAMS {
+            return Err(SocketError::WouldBlock);
+        }
+        datagrams.transmit.push_back((destination, data.to_vec()));
+        Ok(data.len())
+    }
+
+    pub fn close(self, sockets: &mut SocketSet) {
+        sockets.sockets[self.0] = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses() -> (SocketAddr, SocketAddr) {
+        (
+            "[fd42:4242:4242::1]:80".parse().unwrap(),
+            "[fd42:4242:4242::100]:40000".parse().unwrap(),
+        )
+    }
+
+    fn segment(sequence_number: u32, acknowledgment_number: Option<u32>) -> tcp::Tcp<'static> {
+        tcp::Tcp {
+            acknowledgment: acknowledgment_number.is_some(),
+            acknowledgment_number: acknowledgment_number.unwrap_or(0),
+            window: 8192,
+            ..header(40000, 80, sequence_number)
+        }
+    }
+
+    fn sent(transmits: Vec<Transmit>) -> Vec<(tcp::Tcp<'static>, Vec<u8>)> {
+        transmits
+            .into_iter()
+            .map(|transmit| match transmit {
+                Transmit::Tcp {
+                    header, payload, ..
+                } => (header, payload),
+                Transmit::Udp { .. } => panic!("UDP datagram sent"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tcp_connection() {
+        let now = Instant::now();
+        let (local, remote) = addresses();
+        let mut sockets = SocketSet::new();
+        let listener = TcpListener::bind(&mut sockets, 80).unwrap();
+        assert_eq!(
+            TcpListener::bind(&mut sockets, 80).unwrap_err(),
+            SocketError::AddressInUse
+        );
+
+        let syn = tcp::Tcp {
+            synchronize: true,
+            ..segment(1000, None)
+        };
+        assert!(sockets.process_tcp(local, remote, &syn, now).is_none());
+        assert!(!listener.can_accept(&sockets));
+        let syn_ack = &sent(sockets.dispatch(now))[0].0;
+        assert!(syn_ack.synchronize && syn_ack.acknowledgment);
+        assert_eq!(syn_ack.acknowledgment_number, 1001);
+        let send_start = syn_ack.sequence_number.wrapping_add(1);
+
+        sockets.process_tcp(local, remote, &segment(1001, Some(send_start)), now);
+        let stream = listener.accept(&mut sockets).unwrap();
+        assert_eq!(stream.peer_addr(&sockets), remote);
+
+        let request = tcp::Tcp {
+            payload: b"GET",
+            ..segment(1001, Some(send_start))
+        };
+        sockets.process_tcp(local, remote, &request, now);
+        let mut buffer = [0; 8];
+        assert_eq!(stream.recv(&mut sockets, &mut buffer), Ok(3));
+        assert_eq!(&buffer[..3], b"GET");
+        assert_eq!(
+            stream.recv(&mut sockets, &mut buffer),
+            Err(SocketError::WouldBlock)
+        );
+
+        assert_eq!(stream.send(&mut sockets, b"OK"), Ok(2));
+        stream.close(&mut sockets);
+        let response = sent(sockets.dispatch(now));
+        assert_eq!(response.len(), 1);
+        let (header, payload) = &response[0];
+        assert_eq!(header.sequence_number, send_start);
+        assert_eq!(header.acknowledgment_number, 1004);
+        assert!(header.fin);
+        assert_eq!(payload, b"OK");
+
+        // Without an acknowledgment the data and FIN are sent again
+        let retransmitted = sent(sockets.dispatch(now + Duration::from_secs(2)));
+        assert_eq!(retransmitted[0].0.sequence_number, send_start);
+        assert_eq!(retransmitted[0].1, b"OK");
+
+        let fin = tcp::Tcp {
+            fin: true,
+            ..segment(1004, Some(send_start.wrapping_add(3)))
+        };
+        sockets.process_tcp(local, remote, &fin, now);
+        let ack = &sent(sockets.dispatch(now))[0].0;
+        assert_eq!(ack.acknowledgment_number, 1005);
+
+        // The connection is freed after TIME-WAIT, then segments are answered with resets
+        assert!(sent(sockets.dispatch(now + TIME_WAIT_TIMEOUT)).is_empty());
+        let reset = sockets
+            .process_tcp(local, remote, &segment(1005, Some(send_start)), now)
+            .unwrap();
+        assert!(reset.reset);
+        assert_eq!(reset.sequence_number, send_start);
+    }
+
+    #[test]
+    fn test_udp_socket() {
+        let (local, remote) = addresses();
+        let mut sockets = SocketSet::new();
+        let socket = UdpSocket::bind(&mut sockets, local.port()).unwrap();
+        assert!(!sockets.process_udp(81, remote, b"ping"));
+        assert!(sockets.process_udp(80, remote, b"ping"));
+
+        let mut buffer = [0; 2];
+        assert!(socket.can_recv(&sockets));
+        assert_eq!(socket.recv_from(&mut sockets, &mut buffer), Ok((2, remote)));
+        assert_eq!(&buffer, b"pi");
+
+        socket.send_to(&mut sockets, b"pong", remote).unwrap();
+        match &sockets.dispatch(Instant::now())[..] {
+            [Transmit::Udp {
+                source_port: 80,
+                destination,
+                payload,
+            }] => {
+                assert_eq!(*destination, remote);
+                assert_eq!(payload, b"pong");
+            }
+            transmits => panic!("{:?}", transmits),
+        }
+
+        // IPv4 datagrams must fit in a frame
+        let ipv4_remote = "192.168.42.2:1234".parse().unwrap();
+        assert_eq!(
+            socket.send_to(&mut sockets, &[0; 1473], ipv4_remote),
+            Err(SocketError::MessageTooLong)
+        );
+        assert_eq!(socket.send_to(&mut sockets, &[0; 1473], remote), Ok(1473));
+    }
+}
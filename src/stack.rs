@@ -0,0 +1,458 @@
+use super::cdc_eem::CdcEemClass;
+use super::interface::Interface;
+use super::socket::{self, SocketError};
+use super::time::{Duration, Instant};
+use core::convert::Infallible;
+use core::future::poll_fn;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use usb_device::bus::UsbBus;
+use usb_device::device::{UsbDevice, UsbDeviceState};
+use usb_device::UsbError;
+
+// Without an interrupt waking the run task, the USB device is polled this often
+const USB_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Set when the interface must be polled again, waking the run task
+#[derive(Default)]
+struct Signal {
+    pending: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Signal {
+    fn wake(&self) {
+        self.pending.store(true, Ordering::Release);
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    // Whether the signal was set, `waker` is woken when it is otherwise
+    fn take(&self, waker: &Waker) -> bool {
+        *self.waker.lock().unwrap() = Some(waker.clone());
+        self.pending.swap(false, Ordering::AcqRel)
+    }
+}
+
+/// Wakes the task running the stack, e.g. from the USB interrupt handler.
+#[derive(Clone)]
+pub struct StackWaker(Arc<Signal>);
+
+impl StackWaker {
+    pub fn wake(&self) {
+        self.0.wake();
+    }
+}
+
+struct Shared {
+    interface: Interface,
+    /// Tasks waiting for a socket to become ready, woken after each poll.
+    wakers: Vec<Waker>,
+    signal: Arc<Signal>,
+    /// A `StackWaker` was handed out, the USB device needn't be polled on a timer.
+    interrupt_driven: bool,
+    /// Time of the last poll of the interface.
+    polled_at: Option<Instant>,
+}
+
+/// Interface shared by the task driving it with `run` and the tasks using its sockets.
+#[derive(Clone)]
+pub struct Stack(Rc<RefCell<Shared>>);
+
+impl Stack {
+    pub fn new(interface: Interface) -> Self {
+        Stack(Rc::new(RefCell::new(Shared {
+            interface,
+            wakers: vec![],
+            signal: Arc::default(),
+            interrupt_driven: false,
+            polled_at: None,
+        })))
+    }
+
+    /// Configure the interface, e.g. to add services.
+    pub fn with<R>(&self, f: impl FnOnce(&mut Interface) -> R) -> R {
+        let result = f(&mut self.0.borrow_mut().interface);
+        self.notify();
+        result
+    }
+
+    /// Waker to call when the USB device has something to process, typically from its
+    /// interrupt. Until one is requested `run` polls the device every millisecond.
+    pub fn waker(&self) -> StackWaker {
+        let mut shared = self.0.borrow_mut();
+        shared.interrupt_driven = true;
+        StackWaker(shared.signal.clone())
+    }
+
+    /// Time `run` must be polled by even if it wasn't woken, for the executor to set a timer.
+    pub fn poll_at(&self) -> Option<Instant> {
+        let shared = self.0.borrow();
+        let usb_poll_at = shared
+            .polled_at
+            .filter(|_| !shared.interrupt_driven)
+            .map(|polled_at| polled_at + USB_POLL_INTERVAL);
+        [shared.interface.poll_at(), usb_poll_at]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    // Have the run task poll the interface, e.g. to send data queued in a socket
+    fn notify(&self) {
+        self.0.borrow().signal.wake();
+    }
+
+    /// Poll the USB device and the interface, waking the socket tasks each time. Only returns
+    /// when the device fails.
+    ///
+    /// Between polls the task waits to be woken by a `StackWaker` or a socket operation, or
+    /// for `poll_at`, which the executor must wake it at.
+    pub async fn run<B: UsbBus>(
+        &self,
+        usb_device: &mut UsbDevice<'_, B>,
+        eem_class: &mut CdcEemClass<'_, B>,
+    ) -> Result<Infallible, UsbError> {
+        let mut configured = false;
+        loop {
+            usb_device.poll(&mut [eem_class]);
+            let wakers = {
+                let mut shared = self.0.borrow_mut();
+                // The link is up once the host selects the configuration
+                let was_configured = configured;
+                configured = usb_device.state() == UsbDeviceState::Configured;
+                if configured && !was_configured {
+                    shared.interface.link_up(Instant::now());
+                }
+                shared.interface.poll(Instant::now(), eem_class)?;
+                shared.polled_at = Some(Instant::now());
+                std::mem::take(&mut shared.wakers)
+            };
+            for waker in wakers {
+                waker.wake();
+            }
+            self.wait().await;
+        }
+    }
+
+    // Until woken or a timer expires
+    async fn wait(&self) {
+        poll_fn(|cx| {
+            let signal = self.0.borrow().signal.clone();
+            let expired = self.poll_at().is_some_and(|at| Instant::now() >= at);
+            if signal.take(cx.waker()) || expired {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    // Run a non-blocking socket operation, the task is woken after the next poll when it
+    // would block
+    fn poll_socket<T>(
+        &self,
+        cx: &mut Context<'_>,
+        f: impl FnOnce(&mut socket::SocketSet) -> Result<T, SocketError>,
+    ) -> Poll<Result<T, SocketError>> {
+        let mut shared = self.0.borrow_mut();
+        match f(shared.interface.sockets()) {
+            Err(SocketError::WouldBlock) => {
+                if !shared
+                    .wakers
+                    .iter()
+                    .any(|waker| waker.will_wake(cx.waker()))
+                {
+                    shared.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            result => {
+                shared.signal.wake();
+                Poll::Ready(result)
+            }
+        }
+    }
+}
+
+/// Listening TCP socket, `accept` waits for connections.
+pub struct TcpListener {
+    stack: Stack,
+    listener: Option<socket::TcpListener>,
+}
+
+impl TcpListener {
+    pub fn bind(stack: &Stack, port: u16) -> Result<TcpListener, SocketError> {
+        let listener = socket::TcpListener::bind(stack.0.borrow_mut().interface.sockets(), port)?;
+        Ok(TcpListener {
+            stack: stack.clone(),
+            listener: Some(listener),
+        })
+    }
+
+    pub async fn accept(&self) -> Result<TcpStream, SocketError> {
+        let listener = self.listener.as_ref().unwrap();
+        let stream = poll_fn(|cx| {
+            self.stack
+                .poll_socket(cx, |sockets| listener.accept(sockets))
+        })
+        .await?;
+        Ok(TcpStream {
+            stack: self.stack.clone(),
+            stream: Some(stream),
+        })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        if let Some(listener) = self.listener.take() {
+            listener.close(self.stack.0.borrow_mut().interface.sockets());
+            self.stack.notify();
+        }
+    }
+}
+
+/// TCP connection, closed when dropped.
+pub struct TcpStream {
+    stack: Stack,
+    stream: Option<socket::TcpStream>,
+}
+
+impl TcpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        let stream = self.stream.as_ref().unwrap();
+        stream.peer_addr(self.stack.0.borrow_mut().interface.sockets())
+    }
+
+    fn poll_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+        buffer: &mut [u8],
+    ) -> Poll<Result<usize, SocketError>> {
+        let stream = self.stream.as_ref().unwrap();
+        self.stack
+            .poll_socket(cx, |sockets| stream.recv(sockets, buffer))
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>, data: &[u8]) -> Poll<Result<usize, SocketError>> {
+        let stream = self.stream.as_ref().unwrap();
+        self.stack
+            .poll_socket(cx, |sockets| stream.send(sockets, data))
+    }
+
+    /// Wait for received data, 0 means the peer closed the stream.
+    pub async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, SocketError> {
+        poll_fn(|cx| self.poll_recv(cx, buffer)).await
+    }
+
+    /// Wait for room in the send buffer, returns how much of `data` was queued.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, SocketError> {
+        poll_fn(|cx| self.poll_send(cx, data)).await
+    }
+
+    pub async fn write_all(&mut self, mut data: &[u8]) -> Result<(), SocketError> {
+        while !data.is_empty() {
+            let written = self.write(data).await?;
+            data = &data[written..];
+        }
+        Ok(())
+    }
+
+    fn close_stream(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            stream.close(self.stack.0.borrow_mut().interface.sockets());
+            self.stack.notify();
+        }
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.close_stream();
+    }
+}
+
+impl futures_io::AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buffer: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_recv(cx, buffer)
+            .map_err(io::Error::from)
+    }
+}
+
+impl futures_io::AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_send(cx, data).map_err(io::Error::from)
+    }
+
+    // Queued data is sent by the next poll of the interface
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().close_stream();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// UDP socket, closed when dropped.
+pub struct UdpSocket {
+    stack: Stack,
+    socket: Option<socket::UdpSocket>,
+}
+
+impl UdpSocket {
+    pub fn bind(stack: &Stack, port: u16) -> Result<UdpSocket, SocketError> {
+        let socket = socket::UdpSocket::bind(stack.0.borrow_mut().interface.sockets(), port)?;
+        Ok(UdpSocket {
+            stack: stack.clone(),
+            socket: Some(socket),
+        })
+    }
+
+    pub async fn recv_from(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), SocketError> {
+        let socket = self.socket.as_ref().unwrap();
+        poll_fn(|cx| {
+            self.stack
+                .poll_socket(cx, |sockets| socket.recv_from(sockets, buffer))
+        })
+        .await
+    }
+
+    pub async fn send_to(
+        &self,
+        data: &[u8],
+        destination: SocketAddr,
+    ) -> Result<usize, SocketError> {
+        let socket = self.socket.as_ref().unwrap();
+        poll_fn(|cx| {
+            self.stack
+                .poll_socket(cx, |sockets| socket.send_to(sockets, data, destination))
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            socket.close(self.stack.0.borrow_mut().interface.sockets());
+            self.stack.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::Transmit;
+    use crate::tcp;
+    use core::future::Future;
+    use core::pin::pin;
+
+    fn segment(sequence_number: u32, acknowledgment_number: u32) -> tcp::Tcp<'static> {
+        tcp::Tcp {
+            source_port: 40000,
+            destination_port: 80,
+            sequence_number,
+            acknowledgment_number,
+            data_offset: 5,
+            urgent_pointer_is_significant: false,
+            acknowledgment: true,
+            push_function: false,
+            reset: false,
+            synchronize: false,
+            fin: false,
+            window: 8192,
+            checksum: 0,
+            urgent_pointer: 0,
+            payload: &[],
+        }
+    }
+
+    #[test]
+    fn test_accept_and_read() {
+        let now = Instant::now();
+        let local: SocketAddr = "[fe80::1]:80".parse().unwrap();
+        let remote: SocketAddr = "[fe80::2]:40000".parse().unwrap();
+        let stack = Stack::new(Interface::new([2, 0, 0, 0, 0, 1], [0x42; 16], now));
+        let listener = TcpListener::bind(&stack, 80).unwrap();
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let mut accept = pin!(listener.accept());
+        assert!(accept.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(stack.0.borrow().wakers.len(), 1);
+
+        let send_start = stack.with(|interface| {
+            let sockets = interface.sockets();
+            let syn = tcp::Tcp {
+                synchronize: true,
+                acknowledgment: false,
+                ..segment(1000, 0)
+            };
+            sockets.process_tcp(local, remote, &syn, now);
+            let Transmit::Tcp { header, .. } = sockets.dispatch(now).remove(0) else {
+                panic!("no SYN-ACK");
+            };
+            let send_start = header.sequence_number.wrapping_add(1);
+            sockets.process_tcp(local, remote, &segment(1001, send_start), now);
+            send_start
+        });
+        let Poll::Ready(Ok(mut stream)) = accept.as_mut().poll(&mut cx) else {
+            panic!("connection not accepted");
+        };
+        assert_eq!(stream.peer_addr(), remote);
+        // The run task polls the interface after socket operations
+        let signal = stack.0.borrow().signal.clone();
+        assert!(signal.take(cx.waker()));
+        assert!(!signal.take(cx.waker()));
+
+        let mut buffer = [0; 8];
+        let mut read = pin!(stream.read(&mut buffer));
+        assert!(read.as_mut().poll(&mut cx).is_pending());
+        stack.with(|interface| {
+            let data = tcp::Tcp {
+                payload: b"GET",
+                ..segment(1001, send_start)
+            };
+            interface.sockets().process_tcp(local, remote, &data, now);
+        });
+        assert_eq!(read.as_mut().poll(&mut cx), Poll::Ready(Ok(3)));
+    }
+
+    #[test]
+    fn test_waker() {
+        let stack = Stack::new(Interface::new(
+            [2, 0, 0, 0, 0, 1],
+            [0x42; 16],
+            Instant::now(),
+        ));
+        let signal = stack.0.borrow().signal.clone();
+        let waker = Waker::noop();
+        assert!(!signal.take(waker));
+
+        let stack_waker = stack.waker();
+        assert!(stack.0.borrow().interrupt_driven);
+        stack_waker.wake();
+        assert!(signal.take(waker));
+        assert!(!signal.take(waker));
+    }
+}
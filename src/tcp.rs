@@ -1,5 +1,11 @@
+use super::buffer::PacketBuffer;
+use super::error::{check_len, ParseError};
+use super::ip;
 use super::ipv6::checksum;
-use std::net::Ipv6Addr;
+use core::net::IpAddr;
+
+// Without options, which we never send
+pub const HEADER_LENGTH: usize = 20;
 
 #[derive(Debug)]
 pub struct Tcp<'a> {
@@ -21,7 +27,8 @@ pub struct Tcp<'a> {
 }
 
 impl Tcp<'_> {
-    pub fn parse(buffer: &[u8]) -> Tcp<'_> {
+    pub fn parse(buffer: &[u8]) -> Result<Tcp<'_>, ParseError> {
+        check_len(buffer, HEADER_LENGTH)?;
         let source_port = u16::from_be_bytes([buffer[0], buffer[1]]);
         let destination_port = u16::from_be_bytes([buffer[2], buffer[3]]);
         let sequence_number = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
@@ -37,8 +44,11 @@ impl Tcp<'_> {
         let window = u16::from_be_bytes([buffer[14], buffer[15]]);
         let checksum = u16::from_be_bytes([buffer[16], buffer[17]]);
         let urgent_pointer = u16::from_be_bytes([buffer[18], buffer[19]]);
+        if data_offset < 5 || data_offset as usize * 4 > buffer.len() {
+            return Err(ParseError::BadLength);
+        }
         let payload = &buffer[(data_offset as usize * 4)..];
-        Tcp {
+        Ok(Tcp {
             source_port,
             destination_port,
             sequence_number,
@@ -54,37 +64,44 @@ impl Tcp<'_> {
             checksum,
             urgent_pointer,
             payload,
-        }
+        })
     }
 
-    pub fn to_bytes(&self, src_addr: &Ipv6Addr, dst_addr: &Ipv6Addr) -> Vec<u8> {
-        let mut packet = vec![];
-        packet.extend_from_slice(&self.source_port.to_be_bytes());
-        packet.extend_from_slice(&self.destination_port.to_be_bytes());
-        packet.extend_from_slice(&self.sequence_number.to_be_bytes());
-        packet.extend_from_slice(&self.acknowledgment_number.to_be_bytes());
-        let data_offset: u8 = 5;
-        packet.extend_from_slice(&(data_offset << 4).to_be_bytes());
+    /// Prepend the header to the data of `buffer`, which is the payload in place of
+    /// `self.payload`.
+    pub fn emit(&self, buffer: &mut PacketBuffer, src_addr: &IpAddr, dst_addr: &IpAddr) {
+        let header = buffer.prepend(HEADER_LENGTH);
+        header[0..2].copy_from_slice(&self.source_port.to_be_bytes());
+        header[2..4].copy_from_slice(&self.destination_port.to_be_bytes());
+        header[4..8].copy_from_slice(&self.sequence_number.to_be_bytes());
+        header[8..12].copy_from_slice(&self.acknowledgment_number.to_be_bytes());
+        let data_offset = (HEADER_LENGTH / 4) as u8;
+        header[12] = data_offset << 4;
         let mut flags: u8 = 0;
         flags |= (self.urgent_pointer_is_significant as u8) << 5;
         flags |= (self.acknowledgment as u8) << 4;
         flags |= (self.push_function as u8) << 3;
         flags |= (self.reset as u8) << 2;
         flags |= (self.synchronize as u8) << 1;
-        flags |= (self.fin as u8) << 0;
-        packet.extend_from_slice(&flags.to_be_bytes());
-        packet.extend_from_slice(&self.window.to_be_bytes());
-        packet.extend_from_slice(&[0, 0]);
-        packet.extend_from_slice(&self.urgent_pointer.to_be_bytes());
-        packet.extend_from_slice(self.payload);
-        let mut checksum = !checksum::combine(&[
-            checksum::pseudo_header(src_addr, dst_addr, 6, packet.len() as u32),
-            checksum::data(&packet),
+        flags |= self.fin as u8;
+        header[13] = flags;
+        header[14..16].copy_from_slice(&self.window.to_be_bytes());
+        header[18..20].copy_from_slice(&self.urgent_pointer.to_be_bytes());
+
+        let segment = buffer.data_mut();
+        let checksum = !checksum::combine(&[
+            ip::pseudo_header(src_addr, dst_addr, 6, segment.len() as u32),
+            checksum::data(segment),
         ]);
-        if checksum == 0 {
-            checksum = 0xfff;
-        }
-        (&mut packet[16..18]).copy_from_slice(&checksum.to_be_bytes());
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self, src_addr: &IpAddr, dst_addr: &IpAddr) -> Vec<u8> {
+        let mut packet = vec![0; HEADER_LENGTH + self.payload.len()];
+        let mut buffer = PacketBuffer::new(&mut packet, HEADER_LENGTH);
+        buffer.append(self.payload);
+        self.emit(&mut buffer, src_addr, dst_addr);
         packet
     }
 }
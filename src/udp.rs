@@ -1,5 +1,10 @@
+use super::buffer::PacketBuffer;
+use super::error::{check_len, ParseError};
+use super::ip;
 use super::ipv6::checksum;
-use std::net::Ipv6Addr;
+use core::net::IpAddr;
+
+pub const HEADER_LENGTH: usize = 8;
 
 #[derive(Debug)]
 pub struct Udp<'a> {
@@ -9,35 +14,51 @@ pub struct Udp<'a> {
 }
 
 impl Udp<'_> {
-    pub fn parse(buffer: &[u8]) -> Udp<'_> {
+    pub fn parse(buffer: &[u8]) -> Result<Udp<'_>, ParseError> {
+        check_len(buffer, HEADER_LENGTH)?;
         let source_port = u16::from_be_bytes([buffer[0], buffer[1]]);
         let destination_port = u16::from_be_bytes([buffer[2], buffer[3]]);
-        let length = u16::from_be_bytes([buffer[4], buffer[5]]);
-        let checksum = u16::from_be_bytes([buffer[6], buffer[7]]);
-        let payload = &buffer[8..];
-        assert!(payload.len() + 8 == length as usize);
-        Udp {
+        let length = u16::from_be_bytes([buffer[4], buffer[5]]) as usize;
+        let _checksum = u16::from_be_bytes([buffer[6], buffer[7]]);
+        if length < HEADER_LENGTH {
+            return Err(ParseError::BadLength);
+        }
+        check_len(buffer, length)?;
+        let payload = &buffer[HEADER_LENGTH..length];
+        Ok(Udp {
             source_port,
             destination_port,
             payload,
-        }
+        })
     }
 
-    pub fn to_bytes(&self, src_addr: &Ipv6Addr, dst_addr: &Ipv6Addr) -> Vec<u8> {
-        let mut packet = vec![];
-        packet.extend_from_slice(&self.source_port.to_be_bytes());
-        packet.extend_from_slice(&self.destination_port.to_be_bytes());
-        packet.extend_from_slice(&((self.payload.len() + 8) as u16).to_be_bytes());
-        packet.extend_from_slice(&[0, 0]);
-        packet.extend_from_slice(self.payload);
+    /// Prepend the header to the data of `buffer`, which is the payload in place of
+    /// `self.payload`.
+    pub fn emit(&self, buffer: &mut PacketBuffer, src_addr: &IpAddr, dst_addr: &IpAddr) {
+        let length = (HEADER_LENGTH + buffer.len()) as u16;
+        let header = buffer.prepend(HEADER_LENGTH);
+        header[0..2].copy_from_slice(&self.source_port.to_be_bytes());
+        header[2..4].copy_from_slice(&self.destination_port.to_be_bytes());
+        header[4..6].copy_from_slice(&length.to_be_bytes());
+
+        let datagram = buffer.data_mut();
         let mut checksum = !checksum::combine(&[
-            checksum::pseudo_header(src_addr, dst_addr, 17, packet.len() as u32),
-            checksum::data(&packet),
+            ip::pseudo_header(src_addr, dst_addr, 17, datagram.len() as u32),
+            checksum::data(datagram),
         ]);
+        // Zero means no checksum, it is sent as all ones (RFC 768)
         if checksum == 0 {
-            checksum = 0xfff;
+            checksum = 0xffff;
         }
-        (&mut packet[6..8]).copy_from_slice(&checksum.to_be_bytes());
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self, src_addr: &IpAddr, dst_addr: &IpAddr) -> Vec<u8> {
+        let mut packet = vec![0; HEADER_LENGTH + self.payload.len()];
+        let mut buffer = PacketBuffer::new(&mut packet, HEADER_LENGTH);
+        buffer.append(self.payload);
+        self.emit(&mut buffer, src_addr, dst_addr);
         packet
     }
 }